@@ -0,0 +1,28 @@
+use super::db::LibraryStats;
+use super::scanner::ScanStatus;
+use super::AuthMode;
+
+/// Everything on the admin page, which is also what the admin page sends as JSON
+#[derive(Serialize)]
+pub struct AdminReport {
+    pub scans: Vec<ScanStatus>,
+    pub stats: LibraryStats,
+    /// Issues whose file is gone, only the first MAX_MISSING are listed
+    pub missing_count: usize,
+    pub missing_files: Vec<String>,
+    pub users: Vec<UserSummary>,
+    pub settings: Settings,
+}
+
+#[derive(Serialize)]
+pub struct UserSummary {
+    pub username: String,
+    pub role: &'static str,
+}
+
+/// The settings that can be changed from the admin page
+#[derive(Serialize)]
+pub struct Settings {
+    pub auth_mode: AuthMode,
+    pub session_minutes: u64,
+}
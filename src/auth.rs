@@ -0,0 +1,170 @@
+use super::db::User;
+use std::borrow::Borrow;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+pub static SESSION_COOKIE: &str = "rust_opds_session";
+
+struct Entry {
+    user: User,
+    expires: Instant,
+}
+
+/// Remembers who has already been through Argon2, so a reader flipping pages
+/// doesn't pay for it on every request.  Browsers (and the readers that keep cookies)
+/// get a session cookie, everyone else gets their Authorization header cached.
+/// Both expire ttl after the login however much they're used.  API tokens are cheap
+/// to check and can be revoked at any time, so they're looked up every time instead.
+pub struct Sessions {
+    ttl: Mutex<Duration>,
+    sessions: Mutex<HashMap<String, Entry>>,
+    credentials: Mutex<HashMap<u64, Entry>>,
+    // Randomly keyed per process, so we never hold on to a password and
+    // nobody can work out a header that collides with someone else's
+    hasher: RandomState,
+}
+
+impl Sessions {
+    pub fn new(ttl: Duration) -> Sessions {
+        Sessions {
+            ttl: Mutex::new(ttl),
+            sessions: Mutex::new(HashMap::new()),
+            credentials: Mutex::new(HashMap::new()),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn ttl(&self) -> Duration {
+        *self.ttl.lock().unwrap()
+    }
+
+    /// Only applies to logins from now on
+    pub fn set_ttl(&self, ttl: Duration) {
+        *self.ttl.lock().unwrap() = ttl;
+    }
+
+    /// returns the token to hand out as the session cookie
+    pub fn start_session(&self, user: &User) -> String {
+        let token = Uuid::new_v4().simple().to_string();
+        insert(&self.sessions, token.clone(), user, self.ttl());
+        token
+    }
+
+    pub fn get_session(&self, token: &str) -> Option<User> {
+        lookup(&self.sessions, token)
+    }
+
+    pub fn cache_credentials(&self, authorization: &str, user: &User) {
+        let key = self.digest(authorization);
+        insert(&self.credentials, key, user, self.ttl());
+    }
+
+    pub fn get_cached_credentials(&self, authorization: &str) -> Option<User> {
+        lookup(&self.credentials, &self.digest(authorization))
+    }
+
+    /// Logs a user out everywhere, say when they've been deleted
+    pub fn forget_user(&self, user_id: i64) {
+        self.sessions.lock().unwrap().retain(|_, e| e.user.id != user_id);
+        self.credentials.lock().unwrap().retain(|_, e| e.user.id != user_id);
+    }
+
+    fn digest(&self, authorization: &str) -> u64 {
+        let mut hasher = self.hasher.build_hasher();
+        authorization.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn insert<K: Hash + Eq>(map: &Mutex<HashMap<K, Entry>>, key: K, user: &User, ttl: Duration) {
+    let now = Instant::now();
+    let mut map = map.lock().unwrap();
+    // Cheap enough at the number of users we expect
+    map.retain(|_, e| e.expires > now);
+    map.insert(
+        key,
+        Entry {
+            user: user.clone(),
+            expires: now + ttl,
+        },
+    );
+}
+
+fn lookup<K, Q: ?Sized>(map: &Mutex<HashMap<K, Entry>>, key: &Q) -> Option<User>
+where
+    K: Borrow<Q> + Hash + Eq,
+    Q: Hash + Eq,
+{
+    let map = map.lock().unwrap();
+    match map.get(key) {
+        Some(entry) if entry.expires > Instant::now() => Some(entry.user.clone()),
+        _ => None,
+    }
+}
+
+/// Pulls our session token out of a Cookie header
+pub fn parse_session_cookie(cookies: &str) -> Option<&str> {
+    cookies
+        .split(';')
+        .filter_map(|c| {
+            let mut parts = c.trim().splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) if name == SESSION_COOKIE => Some(value),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::tests::user;
+
+    #[test]
+    fn sessions_expire_after_the_ttl() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.start_session(&user(1));
+        assert_eq!(sessions.get_session(&token).map(|u| u.id), Some(1));
+        assert!(sessions.get_session("made up").is_none());
+
+        // A shorter ttl only applies to logins from then on
+        sessions.set_ttl(Duration::from_secs(0));
+        assert!(sessions.get_session(&token).is_some());
+        let expired = sessions.start_session(&user(2));
+        assert!(sessions.get_session(&expired).is_none());
+        sessions.cache_credentials("Basic dXNlcjI6c2VjcmV0", &user(2));
+        assert!(sessions.get_cached_credentials("Basic dXNlcjI6c2VjcmV0").is_none());
+    }
+
+    #[test]
+    fn forgetting_a_user_ends_all_their_logins() {
+        let sessions = Sessions::new(Duration::from_secs(60));
+        let token = sessions.start_session(&user(1));
+        let other = sessions.start_session(&user(2));
+        sessions.cache_credentials("Basic dXNlcjE6c2VjcmV0", &user(1));
+        assert!(sessions.get_cached_credentials("Basic dXNlcjE6c2VjcmV0").is_some());
+        assert!(sessions.get_cached_credentials("Basic dXNlcjE6b3RoZXI=").is_none());
+
+        sessions.forget_user(1);
+        assert!(sessions.get_session(&token).is_none());
+        assert!(sessions.get_cached_credentials("Basic dXNlcjE6c2VjcmV0").is_none());
+        assert_eq!(sessions.get_session(&other).map(|u| u.id), Some(2));
+    }
+
+    #[test]
+    fn session_cookie() {
+        assert_eq!(parse_session_cookie("rust_opds_session=abc"), Some("abc"));
+        assert_eq!(
+            parse_session_cookie("theme=dark; rust_opds_session=abc=; other=1"),
+            Some("abc=")
+        );
+        assert_eq!(parse_session_cookie("not_rust_opds_session=abc"), None);
+        assert_eq!(parse_session_cookie("rust_opds_session"), None);
+        assert_eq!(parse_session_cookie(""), None);
+    }
+}
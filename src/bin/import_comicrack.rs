@@ -15,7 +15,7 @@ use serde_xml_rs::deserialize;
 
 use failure::Error;
 use regex::Regex;
-use rust_opds::{db, ComicInfo, Config};
+use rust_opds::{db, ComicInfo, Config, LibraryConfig};
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
@@ -57,6 +57,17 @@ fn main() -> Result<(), Error> {
         _ => None,
     };
 
+    let libraries = config.libraries();
+    let library_name = config
+        .import_comicrack
+        .as_ref()
+        .and_then(|i| i.library.as_ref());
+    let library = match library_name {
+        Some(name) => libraries.iter().find(|l| &l.name == name),
+        None => libraries.first(),
+    }.ok_or_else(|| failure::err_msg("No library to import into"))?;
+    let library_id = db.store_library(library)?;
+
     println!("config: {:#?}", config);
 
     // I could parse the whole thing as an XML, but since
@@ -73,7 +84,7 @@ fn main() -> Result<(), Error> {
         if in_book && BOOK_END_RE.is_match(&book_buf) {
             in_book = false;
             let book: Book = deserialize(book_buf.as_bytes())?;
-            process_book(&config, &db, book, &book_buf, user_id)?;
+            process_book(&config, &db, library, library_id, book, &book_buf, user_id)?;
             book_buf.clear();
         } else if !in_book && BOOK_START_RE.is_match(&book_buf) {
             in_book = true;
@@ -82,6 +93,7 @@ fn main() -> Result<(), Error> {
         }
     }
 
+    db.refresh_series()?;
     db.analyze().ok(); // Ok to ignore error
     Ok(())
 }
@@ -89,6 +101,8 @@ fn main() -> Result<(), Error> {
 fn process_book(
     config: &Config,
     db: &db::DB,
+    library: &LibraryConfig,
+    library_id: i64,
     book: Book,
     comic_info: &str,
     user_id: Option<i64>,
@@ -102,7 +116,7 @@ fn process_book(
                 file = file.trim_left_matches(strip).to_string();
             }
         }
-        let mut path = config.comics_path.clone();
+        let mut path = library.path.clone();
         path.push(file.replace("\\", "/"));
         path
     } else {
@@ -124,10 +138,9 @@ fn process_book(
         false
     };
 
-    let issue_id = db.store_comic(
-        &ComicInfo::new(&path, Some(comic_info.to_string()))?,
-        &Vec::new(),
-    )?;
+    let mut info = ComicInfo::new(&path, Some(comic_info.to_string()))?;
+    info.library_id = Some(library_id);
+    let issue_id = db.store_comic(&info, &Vec::new())?;
     if read && user_id.is_some() {
         println!("Marking {:#?} as read", path);
         db.mark_read(issue_id, user_id.unwrap())?;
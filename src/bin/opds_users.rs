@@ -0,0 +1,183 @@
+extern crate env_logger;
+extern crate failure;
+extern crate rust_opds;
+extern crate toml;
+
+use failure::Error;
+use rust_opds::db::{RestrictionKind, TokenScope};
+use rust_opds::{db, Config};
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+
+static USAGE: &str = "Usage:
+    opds_users list
+    opds_users add <username> [password]
+    opds_users delete <username>
+    opds_users passwd <username> [password]
+    opds_users show <username>
+    opds_users role <username> <admin|user|guest>
+    opds_users allow <username> <library|path|publisher> <value>
+    opds_users disallow <username> <library|path|publisher> <value>
+    opds_users max-age <username> <age|none>
+    opds_users token add <username> <name> [read|progress]
+    opds_users token list <username>
+    opds_users token revoke <username> <name>
+
+If the password is left off it is read from stdin.  Tokens can be sent as
+a Bearer token, used as the password, or put at the start of the path as
+/t/<token>/.  Read tokens can't change read status, progress (the default) can.
+
+Once a user is allowed any library they only see issues in one of their
+libraries, likewise for paths (whole folders, absolute or relative to the first library) and
+publishers.  With a max-age
+they only see issues with an AgeRating up to that age, so unrated issues are
+hidden too.  Guests can browse and download but nothing is recorded for them.
+Changes apply to logged in users on their next request.";
+
+/// Utility to manage users, mostly for when the auth mode in config.toml
+/// is closed or anonymous and users can't provision themselves.
+fn main() -> Result<(), Error> {
+    env_logger::init();
+
+    let args: Vec<String> = env::args().collect();
+
+    let mut file = File::open("config.toml")?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let config: Config = toml::from_str(&contents)?;
+    let db = db::DB::new(config.database_path.as_path())?;
+
+    match (args.get(1).map(|s| s.as_str()), args.get(2)) {
+        (Some("list"), _) => for (username, role) in db.list_users()? {
+            println!("{}\t{}", username, role.as_str());
+        },
+        (Some("add"), Some(username)) => {
+            let password = get_password(args.get(3))?;
+            db.create_user(username, &password)?;
+            println!("Added {}", username);
+        }
+        (Some("delete"), Some(username)) => {
+            db.delete_user(username)?;
+            println!("Deleted {}", username);
+        }
+        (Some("passwd"), Some(username)) => {
+            let password = get_password(args.get(3))?;
+            db.set_password(username, &password)?;
+            println!("Changed the password for {}", username);
+        }
+        (Some("show"), Some(username)) => {
+            let user = db.get_user_details(username)?;
+            let restrictions = &user.restrictions;
+            println!("role\t{}", user.role.as_str());
+            for library in &restrictions.libraries {
+                println!("library\t{}", library);
+            }
+            for path in &restrictions.paths {
+                println!("path\t{}", path);
+            }
+            for publisher in &restrictions.publishers {
+                println!("publisher\t{}", publisher);
+            }
+            if let Some(age) = restrictions.max_age_rating {
+                println!("max-age\t{}", age);
+            }
+        }
+        (Some("role"), Some(username)) => {
+            let role = match args.get(3) {
+                Some(role) => role.parse()?,
+                None => usage(),
+            };
+            db.set_role(username, role)?;
+            println!("{} is now {}", username, role.as_str());
+        }
+        (Some(command @ "allow"), Some(username))
+        | (Some(command @ "disallow"), Some(username)) => {
+            let (kind, value): (RestrictionKind, _) = match (args.get(3), args.get(4)) {
+                (Some(kind), Some(value)) => (kind.parse()?, value),
+                _ => usage(),
+            };
+            let value = match (kind, config.libraries().first()) {
+                (RestrictionKind::Path, Some(library)) => {
+                    library.path.join(value).to_string_lossy().into()
+                }
+                _ => value.clone(),
+            };
+            if command == "allow" {
+                db.add_restriction(username, kind, &value)?;
+                println!("{} can see {} {}", username, kind.as_str(), value);
+            } else {
+                db.remove_restriction(username, kind, &value)?;
+                println!("Removed {} {} for {}", kind.as_str(), value, username);
+            }
+        }
+        (Some("max-age"), Some(username)) => {
+            let age = match args.get(3).map(|s| s.as_str()) {
+                Some("none") => None,
+                Some(age) => Some(age.parse()?),
+                None => usage(),
+            };
+            db.set_max_age_rating(username, age)?;
+            println!("Set the max age for {}", username);
+        }
+        (Some("token"), Some(command)) => token(&db, command, &args[3..])?,
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    std::process::exit(1);
+}
+
+fn token(db: &db::DB, command: &str, args: &[String]) -> Result<(), Error> {
+    let user_id = match args.get(0) {
+        Some(username) => db.get_user(username)?,
+        None => usage(),
+    };
+    match (command, args.get(1)) {
+        ("add", Some(name)) => {
+            let scope = match args.get(2) {
+                Some(scope) => scope.parse()?,
+                None => TokenScope::Progress,
+            };
+            let token = db.create_token(user_id, name, scope)?;
+            println!("{}", token);
+            eprintln!("This is the only time the token will be shown");
+        }
+        ("list", _) => for token in db.list_tokens(user_id)? {
+            println!(
+                "{}\t{}\tcreated {}\tlast used {}",
+                token.name,
+                token.scope.as_str(),
+                token.created_at.to_rfc3339(),
+                token
+                    .last_used_at
+                    .map(|t| t.to_rfc3339())
+                    .unwrap_or_else(|| "never".to_owned())
+            );
+        },
+        ("revoke", Some(name)) => {
+            db.revoke_token(user_id, name)?;
+            println!("Revoked {}", name);
+        }
+        _ => usage(),
+    }
+    Ok(())
+}
+
+fn get_password(arg: Option<&String>) -> Result<String, Error> {
+    if let Some(password) = arg {
+        return Ok(password.clone());
+    }
+    eprint!("Password: ");
+    let mut password = String::new();
+    std::io::stdin().read_line(&mut password)?;
+    let password = password.trim_right_matches(&['\r', '\n'][..]).to_owned();
+    if password.is_empty() {
+        Err(failure::err_msg("Password can't be empty"))
+    } else {
+        Ok(password)
+    }
+}
@@ -0,0 +1,60 @@
+use failure::Error;
+use xml::reader::{EventReader, XmlEvent};
+
+/// A ComicRack reading list (.cbl file) before its books are matched to issues
+#[derive(Debug, Clone, Default)]
+pub struct CblList {
+    pub name: String,
+    pub books: Vec<CblBook>,
+}
+
+/// An entry of a reading list, described the way ComicRack sees it
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct CblBook {
+    pub series: String,
+    /// Not always a plain number, think 1.MU or 0.5
+    pub number: String,
+    /// Usually the year the volume started, going by ComicVine
+    pub volume: Option<i32>,
+    pub year: Option<i32>,
+}
+
+/// Parses the XML of a .cbl file, books come back in reading order
+pub fn parse(xml: &str) -> Result<CblList, Error> {
+    let mut list = CblList::default();
+    let mut current_string = String::new();
+    for e in EventReader::from_str(xml) {
+        match e? {
+            XmlEvent::StartElement {
+                name, attributes, ..
+            } => {
+                current_string.clear();
+                if name.local_name == "Book" {
+                    let mut book = CblBook::default();
+                    for attribute in attributes {
+                        let value = attribute.value.trim();
+                        match attribute.name.local_name.as_ref() {
+                            "Series" => book.series = value.to_owned(),
+                            "Number" => book.number = value.to_owned(),
+                            "Volume" => book.volume = value.parse().ok(),
+                            "Year" => book.year = value.parse().ok(),
+                            _ => (),
+                        }
+                    }
+                    list.books.push(book);
+                }
+            }
+            XmlEvent::Characters(s) => current_string = s,
+            XmlEvent::EndElement { ref name }
+                if name.local_name == "Name" && list.name.is_empty() =>
+            {
+                list.name = current_string.trim().to_owned();
+            }
+            _ => (),
+        }
+    }
+    if list.name.is_empty() {
+        return Err(format_err!("The reading list doesn't have a Name"));
+    }
+    Ok(list)
+}
@@ -1,10 +1,17 @@
-use super::ComicInfo;
+use super::cbl::{CblBook, CblList};
+use super::error::{Invalid, NotFound};
+use super::{ComicInfo, Direction, LibraryConfig};
 use chrono::prelude::*;
+use chrono::Duration;
 use failure::Error;
 use r2d2::Pool;
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Row;
-use std::path::Path;
+use rusqlite::types::ToSql;
+use rusqlite::{Connection, ErrorCode, Row};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::str::FromStr;
+use url::form_urlencoded;
 use walkdir::DirEntry;
 
 #[derive(Clone)]
@@ -15,8 +22,12 @@ pub struct DB {
 impl DB {
     pub fn new(db: &Path) -> Result<DB, Error> {
         let manager = SqliteConnectionManager::file(db);
-        let pool = ::r2d2::Pool::new(manager)?;
-        let conn = pool.get()?;
+        DB::with_pool(::r2d2::Pool::new(manager)?)
+    }
+
+    /// Creates or migrates the tables in whatever the pool connects to
+    fn with_pool(pool: Pool<SqliteConnectionManager>) -> Result<DB, Error> {
+        let mut conn = pool.get()?;
         conn.execute(
             "
           CREATE TABLE IF NOT EXISTS issue (
@@ -114,14 +125,78 @@ impl DB {
             &[],
         )?;
 
+        conn.execute(
+            "
+          CREATE TABLE IF NOT EXISTS api_token (
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL,
+            token_hash BLOB NOT NULL,
+            scope TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            last_used_at TEXT
+          )",
+            &[],
+        )?;
+
+        conn.execute(
+            "
+          CREATE UNIQUE INDEX IF NOT EXISTS api_token_hash on api_token(token_hash)
+          ",
+            &[],
+        )?;
+
+        conn.execute(
+            "
+          CREATE UNIQUE INDEX IF NOT EXISTS api_token_user_name on api_token(user_id, name)
+          ",
+            &[],
+        )?;
+
+        let reparse = migrate(&mut conn)?;
+
         conn.execute(
             "
           ANALYZE
           ",
             &[],
         )?;
+        // Let it go back to the pool, which may only have the one
+        drop(conn);
+
+        let db = DB { pool };
+        if reparse {
+            db.reparse_comic_info()?;
+        }
+        Ok(db)
+    }
 
-        Ok(DB { pool })
+    /// Fills in columns added by migrations from the ComicInfo.xml we kept in
+    /// issue_fts, rather than making everyone rescan (which would also throw away
+    /// anything brought in by import_comicrack)
+    fn reparse_comic_info(&self) -> Result<(), Error> {
+        info!("Filling in new metadata from stored ComicInfo.xml");
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "select i.rowid, i.filepath, f.comicinfo from issue i, issue_fts f where i.rowid = f.issue_id",
+        )?;
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let issue_id: i64 = row.get(0);
+            let filepath: String = row.get(1);
+            match ComicInfo::new(Path::new(&filepath), Some(row.get(2))) {
+                Ok(info) => {
+                    let direction = info.direction.map(|d| d.as_str());
+                    conn.execute(
+                        "update issue set age_rating = ?, direction = ? where rowid = ?",
+                        &[&info.age_rating, &direction, &issue_id],
+                    )?;
+                    store_categories(&conn, issue_id, &info)?;
+                }
+                Err(e) => error!("Can't reparse ComicInfo.xml for {}: {}", filepath, e),
+            }
+        }
+        Ok(())
     }
 
     pub fn analyze(&self) -> Result<(), Error> {
@@ -134,15 +209,19 @@ impl DB {
         let conn = self.pool.get()?;
         let mut stmt = conn.prepare_cached("insert into issue(filepath, modified_at, size, comicvine_id,
             comicvine_url, series, issue_number, volume, title, summary, released_at, writer, penciller,
-            inker, colorist, cover_artist, publisher, page_count) values (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16,?17,?18)
+            inker, colorist, cover_artist, publisher, page_count, age_rating, library_id, added_at, direction) values (?1,?2,?3,?4,?5,?6,?7,?8,?9,?10,?11,?12,?13,?14,?15,?16,?17,?18,?19,?20,?21,?22)
             ON CONFLICT(filepath) DO UPDATE SET
             modified_at = excluded.modified_at, size = excluded.size, comicvine_id = excluded.comicvine_id,
             comicvine_url = excluded.comicvine_url, series = excluded.series, issue_number = excluded.issue_number,
             volume = excluded.volume, title = excluded.title, summary = excluded.summary, released_at = excluded.released_at,
             writer = excluded.writer, penciller = excluded.penciller, inker = excluded.inker, colorist = excluded.colorist,
-            cover_artist = excluded.cover_artist, publisher = excluded.publisher, page_count = excluded.page_count
+            cover_artist = excluded.cover_artist, publisher = excluded.publisher, page_count = excluded.page_count,
+            age_rating = excluded.age_rating, library_id = excluded.library_id, direction = excluded.direction
                                            ")?;
 
+        // added_at is left alone on updates, so it stays when the issue first showed up
+
+        let direction = info.direction.map(|d| d.as_str());
         stmt.insert(&[
             &info.filepath,
             &info.modified_at,
@@ -162,6 +241,10 @@ impl DB {
             &info.cover_artist,
             &info.publisher,
             &info.page_count,
+            &info.age_rating,
+            &info.library_id,
+            &Utc::now(),
+            &direction,
         ])?;
 
         // On upserts, the last rowid thing doesn't work
@@ -176,6 +259,7 @@ impl DB {
                 conn.prepare_cached("replace into issue_fts(issue_id, comicinfo) values (?1, ?2)")?;
             stmt.insert(&[&issue_id, comic_info])?;
         }
+        store_categories(&conn, issue_id, info)?;
 
         if !entries.is_empty() {
             conn.execute("delete from page where issue_id = ?", &[&issue_id])?;
@@ -187,6 +271,112 @@ impl DB {
         Ok(issue_id)
     }
 
+    /// Records the library, returning its id.  Issues already under its path that
+    /// predate libraries are moved into it.
+    pub fn store_library(&self, library: &LibraryConfig) -> Result<i64, Error> {
+        let path: String = library.path.to_string_lossy().into();
+        let conn = self.pool.get()?;
+        conn.execute(
+            "insert into library(name, path, direction) values (?, ?, ?)
+            ON CONFLICT(name) DO UPDATE SET path = excluded.path, direction = excluded.direction",
+            &[&library.name, &path, &library.direction.map(|d| d.as_str())],
+        )?;
+        let library_id: i64 = conn.query_row(
+            "select rowid from library where name = ?",
+            &[&library.name],
+            |row| row.get(0),
+        )?;
+        conn.execute(
+            "update issue set library_id = ? where library_id is null and instr(filepath, ?) = 1",
+            &[&library_id, &path],
+        )?;
+        Ok(library_id)
+    }
+
+    /// Stores every library from the config and forgets the rest, returning their ids.
+    /// Issues of a renamed library move to the one with their path now, anything
+    /// else outside the libraries goes along with everything about it, so run
+    /// refresh_series afterwards.
+    pub fn sync_libraries(&self, libraries: &[LibraryConfig]) -> Result<Vec<i64>, Error> {
+        let mut ids = Vec::new();
+        for library in libraries {
+            ids.push(self.store_library(library)?);
+        }
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let binds = ids.iter().map(|_| "?").collect::<Vec<_>>().join(", ");
+        let params: Vec<&ToSql> = ids.iter().map(|id| id as &ToSql).collect();
+        tx.execute(
+            &format!("delete from library where rowid not in ({})", binds),
+            &params,
+        )?;
+        // Deepest first, so libraries inside other libraries keep their issues
+        let mut folders: Vec<(i64, String)> = ids
+            .iter()
+            .zip(libraries)
+            .map(|(&id, library)| (id, restriction_path(&library.path.to_string_lossy())))
+            .collect();
+        folders.sort_by_key(|(_, folder)| ::std::cmp::Reverse(folder.len()));
+        let reassign = format!(
+            "update issue set library_id = ? where (library_id is null or library_id not in ({})) and instr(filepath, ?) = 1",
+            binds
+        );
+        for (library_id, folder) in &folders {
+            let mut reassign_params: Vec<&ToSql> = vec![library_id];
+            reassign_params.extend(params.iter().cloned());
+            reassign_params.push(folder);
+            tx.execute(&reassign, &reassign_params)?;
+        }
+        tx.execute(
+            &format!("update issue set library_id = null where library_id not in ({})", binds),
+            &params,
+        )?;
+        let gone = "select rowid from issue where library_id is null";
+        for table in &[
+            "page",
+            "read",
+            "progress",
+            "issue_category",
+            "issue_fts",
+            "reading_list_issue",
+            "shelf_issue",
+        ] {
+            tx.execute(
+                &format!("delete from {} where issue_id in ({})", table, gone),
+                &[],
+            )?;
+        }
+        tx.execute("delete from issue where library_id is null", &[])?;
+        tx.commit()?;
+        Ok(ids)
+    }
+
+    /// Libraries the user can see something in
+    pub fn get_libraries(&self, user: &User) -> Result<Vec<Library>, Error> {
+        let (restriction, params) = user.restrictions.to_sql();
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!(
+            "select l.rowid, l.name, l.direction, max(i.modified_at) from library l, issue i where l.rowid = i.library_id and {} group by 1 order by l.name",
+            restriction
+        ))?;
+        let mut rows = stmt.query(&params)?;
+        let mut libraries = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let direction: Option<String> = row.get(2);
+            libraries.push(Library {
+                id: row.get(0),
+                name: row.get(1),
+                direction: match direction {
+                    Some(direction) => Some(direction.parse()?),
+                    None => None,
+                },
+                updated_at: row.get(3),
+            });
+        }
+        Ok(libraries)
+    }
+
     // Basically the only time we shouldn't update is if we know
     // that path hasn't be modified since the last mod_time
     pub fn should_update(&self, entry: &DirEntry) -> bool {
@@ -207,22 +397,112 @@ impl DB {
         }
     }
 
-    pub fn get_all(&self) -> Result<Vec<ComicInfo>, Error> {
+    pub fn get_all(&self, user: &User, facets: &Facets) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(&IssueQuery::new(user).facets(facets))
+    }
+
+    pub fn get_for_library(
+        &self,
+        user: &User,
+        library_id: i64,
+        facets: &Facets,
+    ) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(
+            &IssueQuery::new(user)
+                .library(library_id)
+                .sort(Sort::Series)
+                .facets(facets),
+        )
+    }
+
+    /// The folders directly inside a folder of a library, with when anything under
+    /// them last changed.  Worked out from the stored paths, not the disk.
+    pub fn get_subfolders(
+        &self,
+        user: &User,
+        library_id: i64,
+        folder: &str,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
+        let (restriction, restriction_params) = user.restrictions.to_sql();
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare_cached(SELECT_CLAUSE)?;
-        let iter = stmt.query_map(&[], row_to_entry)?;
-        let mut retval = Vec::new();
-        for comic in iter {
-            retval.push(comic?)
+        let prefix = folder_prefix(&conn, library_id, folder)?;
+        let sep = MAIN_SEPARATOR.to_string();
+        let mut params: Vec<&ToSql> = vec![&sep, &prefix, &library_id, &prefix];
+        params.extend(restriction_params);
+        params.push(&sep);
+        let mut stmt = conn.prepare_cached(&format!(
+            "select substr(rest, 1, instr(rest, ?) - 1), max(modified_at) from (select substr(i.filepath, length(?) + 1) rest, i.modified_at from issue i where i.library_id = ? and instr(i.filepath, ?) = 1 and {}) where instr(rest, ?) > 0 group by 1 order by 1",
+            restriction
+        ))?;
+        let mut rows = stmt.query(&params)?;
+
+        let mut folders = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            folders.push((row.get(0), row.get(1)));
         }
-        Ok(retval)
+        Ok(folders)
+    }
+
+    /// The issues directly inside a folder of a library, see get_subfolders
+    pub fn get_for_folder(
+        &self,
+        user: &User,
+        library_id: i64,
+        folder: &str,
+    ) -> Result<Vec<ComicInfo>, Error> {
+        let prefix = folder_prefix(&*self.pool.get()?, library_id, folder)?;
+        self.query_issues(
+            &IssueQuery::new(user)
+                .library(library_id)
+                .filter(Filter::Folder(prefix))
+                .order(Order::Path),
+        )
+    }
+
+    /// Newest to the library first, however old the issues are
+    pub fn get_added(&self, user: &User, facets: &Facets) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(&IssueQuery::new(user).sort(Sort::Added).facets(facets))
+    }
+
+    /// Issues the user is in the middle of, the one they read last first
+    pub fn get_continue_reading(
+        &self,
+        user: &User,
+        facets: &Facets,
+    ) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(
+            &IssueQuery::new(user)
+                .filter(Filter::Started)
+                .read(false)
+                .order(Order::LastRead)
+                .facets(facets),
+        )
+    }
+
+    /// The next issue of each series the user has been reading lately
+    pub fn get_on_deck(&self, user: &User, facets: &Facets) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(
+            &IssueQuery::new(user)
+                .filter(Filter::OnDeck {
+                    user_id: user.id,
+                    since: Utc::now() - Duration::days(ON_DECK_DAYS),
+                    restrictions: user.restrictions.clone(),
+                })
+                .sort(Sort::Series)
+                .facets(facets),
+        )
+    }
+
+    pub fn get_recent(&self, user: &User, facets: &Facets) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(&IssueQuery::new(user).sort(Sort::Released).facets(facets))
     }
 
-    pub fn get_recent(&self) -> Result<Vec<ComicInfo>, Error> {
+    pub fn query_issues(&self, query: &IssueQuery) -> Result<Vec<ComicInfo>, Error> {
+        let (sql, params) = query.to_sql();
         let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare_cached(&format!("{} order by released_at desc", SELECT_CLAUSE))?;
-        let iter = stmt.query_map(&[], row_to_entry)?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, row_to_entry)?;
         let mut retval = Vec::new();
         for comic in iter {
             retval.push(comic?)
@@ -230,250 +510,2607 @@ impl DB {
         Ok(retval)
     }
 
-    pub fn get_unread(&self, user_id: i64) -> Result<Vec<ComicInfo>, Error> {
+    /// How many issues the query picks, ignoring any page
+    pub fn count_issues(&self, query: &IssueQuery) -> Result<i64, Error> {
+        let (sql, params) = query.count_sql();
         let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare_cached(
-                &format!("{} left join (select issue_id from read where user_id = ?) r on i.rowid = r.issue_id where r.issue_id is null order by released_at", SELECT_CLAUSE)
-                )?;
-        let iter = stmt.query_map(&[&user_id], row_to_entry)?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        Ok(stmt.query_row(&params, |row| row.get(0))?)
+    }
+
+    /// Rebuilds the series and publisher tables from the issues, keeping the ids
+    /// of those that are still around, and drops categories nothing has anymore
+    /// along with shelf and collection entries for series that are gone, so a
+    /// later series that gets the same rowid doesn't turn up in them.
+    /// A series reads the way its tagged issues agree on, if they do.
+    /// Run after anything that stores comics.
+    pub fn refresh_series(&self) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute_batch(
+            "INSERT OR IGNORE INTO publisher(name) SELECT DISTINCT publisher FROM issue;
+            DELETE FROM publisher WHERE NOT EXISTS (SELECT 1 FROM issue i WHERE i.publisher IS publisher.name);
+            INSERT OR IGNORE INTO series(name, volume, publisher) SELECT DISTINCT series, volume, publisher FROM issue;
+            UPDATE issue SET series_id = (SELECT s.rowid FROM series s WHERE ifnull(s.name, '') = ifnull(issue.series, '') AND ifnull(s.volume, -1) = ifnull(issue.volume, -1) AND ifnull(s.publisher, '') = ifnull(issue.publisher, ''));
+            UPDATE series SET
+              issue_count = (SELECT count(*) FROM issue i WHERE i.series_id = series.rowid),
+              start_year = (SELECT min(cast(strftime('%Y', i.released_at) AS INTEGER)) FROM issue i WHERE i.series_id = series.rowid),
+              updated_at = (SELECT max(i.modified_at) FROM issue i WHERE i.series_id = series.rowid),
+              direction = (SELECT CASE WHEN count(DISTINCT i.direction) = 1 THEN max(i.direction) END FROM issue i WHERE i.series_id = series.rowid);
+            DELETE FROM series WHERE issue_count = 0;
+            DELETE FROM shelf_series WHERE series_id NOT IN (SELECT rowid FROM series);
+            DELETE FROM collection_series WHERE series_id NOT IN (SELECT rowid FROM series);
+            DELETE FROM category WHERE NOT EXISTS (SELECT 1 FROM issue_category ic WHERE ic.category_id = category.rowid);",
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Series with issue and unread counts for the issues the user can see
+    fn select_series(&self, user: &User, filter: &SeriesFilter) -> Result<Vec<Series>, Error> {
+        let (sql, params) = filter.to_sql(user);
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, row_to_series)?;
         let mut retval = Vec::new();
-        for comic in iter {
-            retval.push(comic?)
+        for series in iter {
+            retval.push(series?)
         }
         Ok(retval)
     }
 
-    pub fn get_unread_series(&self, user_id: i64) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
-        let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare_cached("select series, max(modified_at) from issue i left join (select issue_id from read where user_id = ?) r on i.rowid = r.issue_id where r.issue_id is null group by 1 order by series")?;
-        let mut rows = stmt.query(&[&user_id])?;
-        let mut series = Vec::new();
+    /// Every series the user can see
+    pub fn get_series(&self, user: &User) -> Result<Vec<Series>, Error> {
+        self.select_series(user, &SeriesFilter::All)
+    }
 
-        while let Some(row) = rows.next() {
-            let row = row?;
-            let s: Option<String> = row.get(0);
-            series.push((s.unwrap_or_else(|| "None".to_owned()), row.get(1)));
-        }
-        Ok(series)
+    pub fn get_unread(&self, user: &User, facets: &Facets) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(
+            &IssueQuery::new(user)
+                .read(false)
+                .order(Order::OldestFirst)
+                .facets(facets),
+        )
     }
 
-    pub fn get_recent_unread_series(&self, user_id: i64) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
-        let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare_cached("select series, max(modified_at) from issue i left join (select issue_id from read where user_id = ?) r on i.rowid = r.issue_id where r.issue_id is null and date('now','-6 months') < i.released_at and i.series is not null group by 1 order by series")?;
-        let mut rows = stmt.query(&[&user_id])?;
-        let mut series = Vec::new();
+    pub fn get_unread_series(&self, user: &User) -> Result<Vec<Series>, Error> {
+        self.select_series(user, &SeriesFilter::Unread)
+    }
 
-        while let Some(row) = rows.next() {
-            let row = row?;
-            series.push((row.get(0), row.get(1)));
-        }
-        Ok(series)
+    /// Series with an unread issue released in the last six months
+    pub fn get_recent_unread_series(&self, user: &User) -> Result<Vec<Series>, Error> {
+        self.select_series(user, &SeriesFilter::RecentUnread)
     }
 
     pub fn get_unread_for_series(
         &self,
-        user_id: i64,
-        series: &str,
+        user: &User,
+        key: &SeriesKey,
+        facets: &Facets,
     ) -> Result<Vec<ComicInfo>, Error> {
-        let mut query = format!("{} left join (select issue_id from read where user_id = ?) r on i.rowid = r.issue_id where r.issue_id is null ", SELECT_CLAUSE);
+        self.query_issues(
+            &IssueQuery::new(user)
+                .series(key)
+                .read(false)
+                .order(Order::OldestFirst)
+                .facets(facets),
+        )
+    }
 
+    /// Publishers the user can see something from, with when that last changed
+    fn select_publishers(
+        &self,
+        user: &User,
+        filter: &PublisherFilter,
+    ) -> Result<Vec<Publisher>, Error> {
+        let (sql, params) = filter.to_sql(user);
         let conn = self.pool.get()?;
-        // The ? in the None case will pick up both the unlikely event that there really
-        // is a series named None as well as making the binds happy
-        match series {
-            "None" => query.push_str("and (series is null or series = ?) "),
-            _ => query.push_str("and series = ? "),
-        };
-
-        query.push_str(" order by released_at");
-        let mut stmt = conn.prepare_cached(&query)?;
-        let iter = stmt.query_map(&[&user_id, &series], row_to_entry)?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, |row| Publisher {
+            id: row.get(0),
+            name: row.get(1),
+            updated_at: row.get(2),
+        })?;
         let mut retval = Vec::new();
-        for comic in iter {
-            retval.push(comic?)
+        for publisher in iter {
+            retval.push(publisher?)
         }
         Ok(retval)
     }
 
-    pub fn get_publishers(&self) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
-        let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare_cached("select publisher, max(modified_at) from issue group by 1")?;
-        let mut rows = stmt.query(&[])?;
+    pub fn get_publishers(&self, user: &User) -> Result<Vec<Publisher>, Error> {
+        self.select_publishers(user, &PublisherFilter::All)
+    }
 
-        let mut pubs = Vec::new();
+    pub fn get_publisher(&self, user: &User, id: i64) -> Result<Publisher, Error> {
+        self.select_publishers(user, &PublisherFilter::Id(id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("publisher").into())
+    }
 
-        while let Some(row) = rows.next() {
-            let row = row?;
-            let publisher: Option<String> = row.get(0);
-            pubs.push((publisher.unwrap_or_else(|| "None".to_owned()), row.get(1)));
-        }
-        Ok(pubs)
+    /// None finds the publisher for issues that don't have one
+    pub fn find_publisher(&self, user: &User, name: &Option<String>) -> Result<Publisher, Error> {
+        self.select_publishers(user, &PublisherFilter::Name(name))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("publisher").into())
     }
 
     pub fn get_series_for_publisher(
         &self,
-        publisher: &str,
-    ) -> Result<Vec<(String, DateTime<Utc>)>, Error> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare_cached(
-            "select series, max(modified_at) from issue where publisher = ? group by 1",
-        )?;
-        let mut rows = stmt.query(&[&publisher])?;
+        user: &User,
+        publisher: &Option<String>,
+    ) -> Result<Vec<Series>, Error> {
+        self.select_series(user, &SeriesFilter::Publisher(publisher))
+    }
 
-        let mut series = Vec::new();
+    pub fn get_series_by_id(&self, user: &User, id: i64) -> Result<Series, Error> {
+        self.select_series(user, &SeriesFilter::Id(id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("series").into())
+    }
 
-        while let Some(row) = rows.next() {
-            let row = row?;
-            let publisher: Option<String> = row.get(0);
-            series.push((publisher.unwrap_or_else(|| "None".to_owned()), row.get(1)));
+    /// The series the user can see with that key, along with its counts
+    pub fn find_series(&self, user: &User, key: &SeriesKey) -> Result<Series, Error> {
+        self.select_series(user, &SeriesFilter::Key(key))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("series").into())
+    }
+
+    /// For the first URLs of all, which only had the series name and sometimes the
+    /// publisher.  When there's more than one volume the oldest wins.
+    pub fn find_series_by_name(
+        &self,
+        user: &User,
+        publisher: Option<Option<String>>,
+        name: Option<String>,
+    ) -> Result<Series, Error> {
+        let mut query = IssueQuery::new(user)
+            .series_name(name)
+            .order(Order::OldestFirst)
+            .page(0, 1);
+        if let Some(publisher) = publisher {
+            query = query.publisher(publisher);
         }
-        Ok(series)
+        let issue = self.query_issues(&query)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("series"))?;
+        let key = SeriesKey {
+            name: issue.series,
+            volume: issue.volume,
+            publisher: issue.publisher,
+        };
+        self.find_series(user, &key)
     }
 
-    pub fn get_for_publisher_series(
+    pub fn get_for_series(
         &self,
-        publisher: &str,
-        series: &str,
+        user: &User,
+        key: &SeriesKey,
+        facets: &Facets,
     ) -> Result<Vec<ComicInfo>, Error> {
-        let conn = self.pool.get()?;
-        // The ? in the None case will pick up both the unlikely event that there really
-        // is a publisher named None as well as making the binds happy
-        let mut where_clause = match publisher {
-            "None" => "where publisher is null or publisher = ?".to_owned(),
-            _ => "where publisher = ?".to_owned(),
-        };
+        self.query_issues(
+            &IssueQuery::new(user)
+                .series(key)
+                .order(Order::IssueNumber)
+                .facets(facets),
+        )
+    }
 
-        match series {
-            "None" => where_clause.push_str(" and series is null or series = ?"),
-            _ => where_clause.push_str("and series = ?"),
-        }
-        let mut stmt = conn.prepare_cached(&format!("{} {}", SELECT_CLAUSE, where_clause))?;
-        let iter = stmt.query_map(&[&publisher, &series], row_to_entry)?;
+    /// Issues the user isn't allowed to see come back as not found
+    pub fn get(&self, user: &User, id: i64) -> Result<ComicInfo, Error> {
+        self.query_issues(&IssueQuery::new(user).id(id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("issue").into())
+    }
+
+    /// Reading lists with how many of their issues the user can see and hasn't read
+    fn select_lists(&self, user: &User, filter: &ListFilter) -> Result<Vec<ReadingList>, Error> {
+        let (sql, params) = filter.to_sql(user);
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, |row| ReadingList {
+            id: row.get(0),
+            name: row.get(1),
+            owner_id: row.get(2),
+            owner: row.get(3),
+            issue_count: row.get(4),
+            unread_count: row.get(5),
+            updated_at: row.get(6),
+        })?;
         let mut retval = Vec::new();
-        for comic in iter {
-            retval.push(comic?)
+        for list in iter {
+            retval.push(list?)
         }
         Ok(retval)
     }
 
-    pub fn get(&self, id: i64) -> Result<ComicInfo, Error> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare_cached(&format!("{} where rowid = ?", SELECT_CLAUSE))?;
-        Ok(stmt.query_row(&[&id], row_to_entry)?)
+    /// Everyone's reading lists, they're shared with the whole server
+    pub fn get_lists(&self, user: &User) -> Result<Vec<ReadingList>, Error> {
+        self.select_lists(user, &ListFilter::All)
     }
 
-    pub fn get_page(
+    pub fn get_list(&self, user: &User, list_id: i64) -> Result<ReadingList, Error> {
+        self.select_lists(user, &ListFilter::Id(list_id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("reading list").into())
+    }
+
+    pub fn find_list(&self, user: &User, name: &str) -> Result<Option<ReadingList>, Error> {
+        Ok(self.select_lists(user, &ListFilter::Name(name))?
+            .into_iter()
+            .next())
+    }
+
+    /// The issues on a reading list in its order, unless the facets sort them
+    pub fn get_for_list(
         &self,
-        issue_id: i64,
-        page_id: i32,
-        user_id: i64,
-    ) -> Result<(String, Vec<u8>), Error> {
-        #[derive(Default)]
-        struct Entry {
-            issue: String,
-            entry: String,
-        };
+        user: &User,
+        list_id: i64,
+        facets: &Facets,
+    ) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(&IssueQuery::new(user).list(list_id).facets(facets))
+    }
 
-        let page_id: usize = page_id as usize;
+    /// Names are unique, check with find_list first.  The list and what's on it
+    /// go in together, so a failure doesn't leave an empty list behind.
+    pub fn create_list(&self, user_id: i64, name: &str, issue_ids: &[i64]) -> Result<i64, Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let list_id = insert_list(&tx, user_id, name)?;
+        replace_list_issues(&tx, list_id, issue_ids)?;
+        tx.commit()?;
+        Ok(list_id)
+    }
+
+    /// Renames the list and replaces what's on it with issue_ids, in that order,
+    /// either of which can be left alone
+    pub fn update_list(
+        &self,
+        list_id: i64,
+        name: Option<&str>,
+        issue_ids: Option<&[i64]>,
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        if let Some(name) = name {
+            tx.execute(
+                "update reading_list set name = ?, updated_at = ? where rowid = ?",
+                &[&name, &Utc::now(), &list_id],
+            ).map_err(|e| list_name_taken(e, name))?;
+        }
+        if let Some(issue_ids) = issue_ids {
+            replace_list_issues(&tx, list_id, issue_ids)?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
 
+    pub fn delete_list(&self, list_id: i64) -> Result<(), Error> {
         let conn = self.pool.get()?;
-        let mut stmt = conn.prepare_cached("select i.filepath, p.entry from issue i, page p where i.rowid = p.issue_id and i.rowid = ? order by p.entry")?;
-        let iter = stmt.query_map(&[&issue_id], |r| Entry {
-            issue: r.get(0),
-            entry: r.get(1),
-        })?;
+        conn.execute("delete from reading_list_issue where list_id = ?", &[&list_id])?;
+        conn.execute("delete from reading_list where rowid = ?", &[&list_id])?;
+        Ok(())
+    }
 
-        let entries: Vec<Entry> = iter.map(|e| e.unwrap_or_default())
-            .filter(|e| {
-                e.entry.ends_with("jpg") || e.entry.ends_with("gif") || e.entry.ends_with("png")
-            })
-            .collect();
+    /// The issue a reading list entry is talking about, if we have it.  Series
+    /// are compared ignoring case, a volume has to match either the issue's or the
+    /// year its series started, and the year only breaks ties.
+    pub fn match_book(&self, book: &CblBook) -> Result<Option<i64>, Error> {
+        let number: i32 = match book.number.parse() {
+            Ok(number) => number,
+            Err(_) => return Ok(None),
+        };
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "select i.rowid from issue i left join series s on s.rowid = i.series_id where lower(i.series) = lower(?1) and i.issue_number = ?2 and (?3 is null or i.volume is null or i.volume = ?3 or s.start_year = ?3) order by cast(strftime('%Y', i.released_at) AS INTEGER) is ?4 desc, i.rowid limit 1",
+        )?;
+        let mut rows = stmt.query(&[&book.series, &number, &book.volume, &book.year])?;
+        match rows.next() {
+            Some(row) => Ok(Some(row?.get(0))),
+            None => Ok(None),
+        }
+    }
 
-        if page_id < entries.len() {
-            if page_id + 3 > entries.len() {
-                self.mark_read(issue_id, user_id).ok(); // Ignore the error
+    /// Stores a .cbl file as a list owned by user_id, or replaces what's on the list
+    /// with that name.  Returns the list's id and the books we don't have.
+    pub fn import_list(&self, user_id: i64, list: &CblList) -> Result<(i64, Vec<CblBook>), Error> {
+        let mut issue_ids = Vec::new();
+        let mut unmatched = Vec::new();
+        for book in &list.books {
+            match self.match_book(book)? {
+                Some(issue_id) => issue_ids.push(issue_id),
+                None => unmatched.push(book.clone()),
             }
-            Ok((
-                entries[page_id].entry.clone(),
-                super::get_bytes_for_entry(&entries[page_id].issue, &entries[page_id].entry)?,
-            ))
-        } else {
-            Err(::failure::err_msg("No such page"))
         }
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        let existing: Option<i64> = {
+            let mut stmt = tx.prepare_cached("select rowid from reading_list where name = ?")?;
+            let mut rows = stmt.query(&[&list.name])?;
+            match rows.next() {
+                Some(row) => Some(row?.get(0)),
+                None => None,
+            }
+        };
+        let list_id = match existing {
+            Some(list_id) => list_id,
+            None => insert_list(&tx, user_id, &list.name)?,
+        };
+        replace_list_issues(&tx, list_id, &issue_ids)?;
+        tx.commit()?;
+        Ok((list_id, unmatched))
     }
 
-    pub fn mark_read(&self, issue_id: i64, user_id: i64) -> Result<usize, Error> {
+    /// The user's shelves, favorites and want to read first, with counts for the
+    /// issues on them the user can see, counting those of any shelved series
+    fn select_shelves(&self, user: &User, filter: &ShelfFilter) -> Result<Vec<Shelf>, Error> {
+        let (sql, params) = filter.to_sql(user);
         let conn = self.pool.get()?;
-        let mut stmt =
-            conn.prepare_cached("replace into read(user_id, issue_id, read_at) values(?,?,?)")?;
-        Ok(stmt.execute(&[&user_id, &issue_id, &Local::now()])?)
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let mut rows = stmt.query(&params)?;
+        let mut retval = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let kind: String = row.get(1);
+            retval.push(Shelf {
+                id: row.get(0),
+                kind: kind.parse()?,
+                name: row.get(2),
+                issue_count: row.get(3),
+                unread_count: row.get(4),
+                updated_at: row.get(5),
+            });
+        }
+        Ok(retval)
     }
 
-    /// grabs as user_id given a name.  If you want to check the password use
-    /// check_or_provision_user, this version will raise an error if not found
-    pub fn get_user(&self, username: &str) -> Result<i64, Error> {
-        let conn = self.pool.get()?;
-        let mut stmt = conn.prepare_cached("select rowid from user where username = ?")?;
-        Ok(stmt.query_row(&[&username], |row| row.get(0))?)
+    pub fn get_shelves(&self, user: &User) -> Result<Vec<Shelf>, Error> {
+        self.select_shelves(user, &ShelfFilter::All)
     }
 
-    /// either check if the password is correct or make a user with the password
-    /// if auth fails, return 0, otherwise the user_id
-    pub fn check_or_provision_user(&self, username: &str, password: &str) -> Result<i64, Error> {
-        use argon2rs::Argon2;
-        let a2 = Argon2::default(::argon2rs::Variant::Argon2i);
-        let conn = self.pool.get()?;
+    /// Other users' shelves come back as not found
+    pub fn get_shelf(&self, user: &User, shelf_id: i64) -> Result<Shelf, Error> {
+        self.select_shelves(user, &ShelfFilter::Id(shelf_id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("shelf").into())
+    }
+
+    pub fn find_shelf(&self, user: &User, name: &str) -> Result<Option<Shelf>, Error> {
+        Ok(self.select_shelves(user, &ShelfFilter::Name(name))?
+            .into_iter()
+            .next())
+    }
+
+    /// Issues put on the shelf themselves, see get_series_for_shelf for the rest
+    pub fn get_for_shelf(&self, user: &User, shelf_id: i64) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(&IssueQuery::new(user).shelf(shelf_id).sort(Sort::Series))
+    }
+
+    pub fn get_series_for_shelf(&self, user: &User, shelf_id: i64) -> Result<Vec<Series>, Error> {
+        self.select_series(user, &SeriesFilter::Shelf(shelf_id))
+    }
 
+    /// A custom shelf, names are unique for each user so check with find_shelf first
+    pub fn create_shelf(&self, user_id: i64, name: &str) -> Result<i64, Error> {
+        let conn = self.pool.get()?;
         let mut stmt = conn.prepare_cached(
-            "select rowid, username, salt, ciphertext from user where username = ?",
+            "insert into shelf(user_id, kind, name, updated_at) values (?, ?, ?, ?)",
         )?;
-        let mut rows = stmt.query(&[&username])?;
-        if let Some(Ok(row)) = rows.next() {
-            let mut ciphertext = [0; 32];
-            let salt: Vec<u8> = row.get(2);
-            let db_cipher: Vec<u8> = row.get(3);
-            a2.hash(
-                &mut ciphertext,
-                password.as_bytes(),
-                salt.as_slice(),
-                &[],
-                &[],
-            );
-            if ciphertext.to_vec() == db_cipher {
-                Ok(row.get(0))
-            } else {
-                Ok(0)
-            }
-        } else {
-            use rand::os::OsRng;
-            use rand::RngCore;
-            let mut osrng = OsRng::new().unwrap(); // supposed to not really fail
-            let mut salt = [0; 32];
-            osrng.fill_bytes(&mut salt[..]);
-            let mut ciphertext = [0; 32];
-            a2.hash(&mut ciphertext, password.as_bytes(), &salt, &[], &[]);
+        Ok(stmt.insert(&[&user_id, &ShelfKind::Custom.as_str(), &name, &Utc::now()])?)
+    }
 
-            let mut stmt = conn.prepare_cached(
-                "insert into user(username, salt, ciphertext) values (?, ?, ?)",
+    pub fn delete_shelf(&self, shelf_id: i64) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        conn.execute("delete from shelf_issue where shelf_id = ?", &[&shelf_id])?;
+        conn.execute("delete from shelf_series where shelf_id = ?", &[&shelf_id])?;
+        conn.execute("delete from shelf where rowid = ?", &[&shelf_id])?;
+        Ok(())
+    }
+
+    /// Puts an issue on a shelf, or takes it off
+    pub fn shelve_issue(&self, shelf_id: i64, issue_id: i64, shelved: bool) -> Result<(), Error> {
+        self.shelve("shelf_issue", "issue_id", shelf_id, issue_id, shelved)
+    }
+
+    /// Puts a whole series on a shelf, or takes it off
+    pub fn shelve_series(&self, shelf_id: i64, series_id: i64, shelved: bool) -> Result<(), Error> {
+        self.shelve("shelf_series", "series_id", shelf_id, series_id, shelved)
+    }
+
+    fn shelve(
+        &self,
+        table: &str,
+        column: &str,
+        shelf_id: i64,
+        id: i64,
+        shelved: bool,
+    ) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        let sql = if shelved {
+            format!("insert or ignore into {}(shelf_id, {}) values (?, ?)", table, column)
+        } else {
+            format!("delete from {} where shelf_id = ? and {} = ?", table, column)
+        };
+        if conn.execute(&sql, &[&shelf_id, &id])? > 0 {
+            conn.execute(
+                "update shelf set updated_at = ? where rowid = ?",
+                &[&Utc::now(), &shelf_id],
             )?;
-            let user_id = stmt.insert(&[&username, &salt.to_vec(), &ciphertext.to_vec()])?;
-            Ok(user_id)
         }
+        Ok(())
     }
-}
 
-const SELECT_CLAUSE: &str = "select i.rowid, i.filepath, i.modified_at, i.size, i.comicvine_id, i.comicvine_url, i.series, i.issue_number, i.volume, i.title, i.summary, i.released_at, i.writer, i.penciller, i.inker, i.colorist, i.cover_artist, i.publisher, i.page_count from issue i";
-
-fn row_to_entry(row: &Row) -> ComicInfo {
-    ComicInfo {
-        comic_info: None,
-        id: row.get(0),
-        filepath: row.get(1),
-        modified_at: row.get(2),
+    /// Categories of a kind with an issue the user can see, with when that last changed
+    fn select_categories(
+        &self,
+        user: &User,
+        kind: CategoryKind,
+        filter: &CategoryFilter,
+    ) -> Result<Vec<Category>, Error> {
+        let kind_str = kind.as_str();
+        let (sql, params) = filter.to_sql(user, &kind_str);
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, |row| Category {
+            id: row.get(0),
+            kind,
+            name: row.get(1),
+            issue_count: row.get(2),
+            updated_at: row.get(3),
+        })?;
+        let mut retval = Vec::new();
+        for category in iter {
+            retval.push(category?)
+        }
+        Ok(retval)
+    }
+
+    pub fn get_categories(&self, user: &User, kind: CategoryKind) -> Result<Vec<Category>, Error> {
+        self.select_categories(user, kind, &CategoryFilter::All)
+    }
+
+    pub fn get_category(
+        &self,
+        user: &User,
+        kind: CategoryKind,
+        category_id: i64,
+    ) -> Result<Category, Error> {
+        self.select_categories(user, kind, &CategoryFilter::Id(category_id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound(kind.as_str()).into())
+    }
+
+    pub fn get_for_category(
+        &self,
+        user: &User,
+        category_id: i64,
+        facets: &Facets,
+    ) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(
+            &IssueQuery::new(user)
+                .category(category_id)
+                .sort(Sort::Series)
+                .facets(facets),
+        )
+    }
+
+    /// Collections are the same for everyone, it's the series in them that
+    /// depend on what the user can see
+    fn select_collections(&self, filter: &CollectionFilter) -> Result<Vec<Collection>, Error> {
+        let (sql, params) = filter.to_sql();
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, |row| Collection {
+            id: row.get(0),
+            name: row.get(1),
+            updated_at: row.get(2),
+        })?;
+        let mut retval = Vec::new();
+        for collection in iter {
+            retval.push(collection?)
+        }
+        Ok(retval)
+    }
+
+    pub fn get_collections(&self) -> Result<Vec<Collection>, Error> {
+        self.select_collections(&CollectionFilter::All)
+    }
+
+    pub fn get_collection(&self, collection_id: i64) -> Result<Collection, Error> {
+        self.select_collections(&CollectionFilter::Id(collection_id))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NotFound("collection").into())
+    }
+
+    pub fn find_collection(&self, name: &str) -> Result<Option<Collection>, Error> {
+        Ok(self.select_collections(&CollectionFilter::Name(name))?
+            .into_iter()
+            .next())
+    }
+
+    pub fn get_series_for_collection(
+        &self,
+        user: &User,
+        collection_id: i64,
+    ) -> Result<Vec<Series>, Error> {
+        self.select_series(user, &SeriesFilter::Collection(collection_id))
+    }
+
+    /// Names are unique, check with find_collection first
+    pub fn create_collection(&self, name: &str) -> Result<i64, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare_cached("insert into collection(name, updated_at) values (?, ?)")?;
+        Ok(stmt.insert(&[&name, &Utc::now()])?)
+    }
+
+    pub fn rename_collection(&self, collection_id: i64, name: &str) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "update collection set name = ?, updated_at = ? where rowid = ?",
+            &[&name, &Utc::now(), &collection_id],
+        )?;
+        Ok(())
+    }
+
+    /// Replaces the series in the collection with series_ids
+    pub fn set_collection_series(
+        &self,
+        collection_id: i64,
+        series_ids: &[i64],
+    ) -> Result<(), Error> {
+        let mut conn = self.pool.get()?;
+        let tx = conn.transaction()?;
+        tx.execute(
+            "delete from collection_series where collection_id = ?",
+            &[&collection_id],
+        )?;
+        {
+            let mut stmt = tx.prepare_cached(
+                "insert or ignore into collection_series(collection_id, series_id) values (?, ?)",
+            )?;
+            for series_id in series_ids {
+                stmt.execute(&[&collection_id, series_id])?;
+            }
+        }
+        tx.execute(
+            "update collection set updated_at = ? where rowid = ?",
+            &[&Utc::now(), &collection_id],
+        )?;
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn delete_collection(&self, collection_id: i64) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "delete from collection_series where collection_id = ?",
+            &[&collection_id],
+        )?;
+        conn.execute("delete from collection where rowid = ?", &[&collection_id])?;
+        Ok(())
+    }
+
+    /// The years the user can see anything from, oldest first
+    pub fn get_years(&self, user: &User) -> Result<Vec<ReleaseYear>, Error> {
+        let query = IssueQuery::new(user);
+        let (sql, params) = query.years_sql();
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&sql)?;
+        let iter = stmt.query_map(&params, |row| ReleaseYear {
+            year: row.get(0),
+            issue_count: row.get(1),
+            updated_at: row.get(2),
+        })?;
+        let mut retval = Vec::new();
+        for year in iter {
+            retval.push(year?)
+        }
+        Ok(retval)
+    }
+
+    /// Everything released in year, a month at a time unless the facets sort it
+    pub fn get_for_year(
+        &self,
+        user: &User,
+        year: i32,
+        facets: &Facets,
+    ) -> Result<Vec<ComicInfo>, Error> {
+        self.query_issues(
+            &IssueQuery::new(user)
+                .year(year)
+                .order(Order::Month)
+                .facets(facets),
+        )
+    }
+
+    /// Pages are numbered in reading order from the cover whichever way the issue
+    /// reads, turning them the other way for rtl is up to the reader, so progress
+    /// and PSE page numbers mean the same thing either way
+    pub fn get_page(
+        &self,
+        issue_id: i64,
+        page_id: i32,
+        user: &User,
+    ) -> Result<(String, Vec<u8>), Error> {
+        #[derive(Default)]
+        struct Entry {
+            issue: String,
+            entry: String,
+        };
+
+        let page_id: usize = page_id as usize;
+
+        let (restriction, restriction_params) = user.restrictions.to_sql();
+        let mut params: Vec<&ToSql> = vec![&issue_id];
+        params.extend(restriction_params);
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(&format!("select i.filepath, p.entry from issue i, page p where i.rowid = p.issue_id and i.rowid = ? and {} order by p.entry", restriction))?;
+        let iter = stmt.query_map(&params, |r| Entry {
+            issue: r.get(0),
+            entry: r.get(1),
+        })?;
+
+        let entries: Vec<Entry> = iter.map(|e| e.unwrap_or_default())
+            .filter(|e| {
+                e.entry.ends_with("jpg") || e.entry.ends_with("gif") || e.entry.ends_with("png")
+            })
+            .collect();
+
+        if page_id < entries.len() {
+            if page_id + 3 > entries.len() && !user.read_only {
+                self.mark_read(issue_id, user.id).ok(); // Ignore the error
+            }
+            Ok((
+                entries[page_id].entry.clone(),
+                super::get_bytes_for_entry(&entries[page_id].issue, &entries[page_id].entry)?,
+            ))
+        } else {
+            Err(NotFound("page").into())
+        }
+    }
+
+    /// read_at is in UTC, like everything it gets compared to
+    pub fn mark_read(&self, issue_id: i64, user_id: i64) -> Result<usize, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare_cached("replace into read(user_id, issue_id, read_at) values(?,?,?)")?;
+        Ok(stmt.execute(&[&user_id, &issue_id, &Utc::now()])?)
+    }
+
+    /// Settings changed from the admin page, which win over config.toml.
+    /// See Config::apply_setting.
+    pub fn get_settings(&self) -> Result<Vec<(String, String)>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select name, value from setting order by name")?;
+        let iter = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?;
+        let mut retval = Vec::new();
+        for setting in iter {
+            retval.push(setting?)
+        }
+        Ok(retval)
+    }
+
+    pub fn set_setting(&self, name: &str, value: &str) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "replace into setting(name, value) values (?, ?)",
+            &[&name, &value],
+        )?;
+        Ok(())
+    }
+
+    /// Counts for the admin page
+    pub fn get_stats(&self) -> Result<LibraryStats, Error> {
+        let conn = self.pool.get()?;
+        let (issues, untagged) = conn.query_row(
+            "select count(*), count(*) - count(series) from issue",
+            &[],
+            |row| (row.get(0), row.get(1)),
+        )?;
+        let mut stmt = conn.prepare_cached(
+            "select coalesce(publisher, 'None'), count(*) from issue group by 1 order by 2 desc",
+        )?;
+        let iter = stmt.query_map(&[], |row| (row.get(0), row.get(1)))?;
+        let mut publishers = Vec::new();
+        for publisher in iter {
+            publishers.push(publisher?)
+        }
+        Ok(LibraryStats {
+            issues,
+            untagged,
+            publishers,
+        })
+    }
+
+    /// Every issue's path, to check for files that have gone missing
+    pub fn get_filepaths(&self) -> Result<Vec<String>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select filepath from issue order by filepath")?;
+        let iter = stmt.query_map(&[], |row| row.get(0))?;
+        let mut retval = Vec::new();
+        for filepath in iter {
+            retval.push(filepath?)
+        }
+        Ok(retval)
+    }
+
+    /// Remembers the page the user is on, reaching the last few pages marks it read
+    pub fn set_progress(&self, user_id: i64, issue_id: i64, page: i32) -> Result<(), Error> {
+        let count = self.count_pages(issue_id)?;
+        if page < 0 || page >= count {
+            let why = format!("Page {} is not in an issue of {} pages", page, count);
+            return Err(Invalid(why).into());
+        }
+        // mark_read needs a connection of its own
+        {
+            let conn = self.pool.get()?;
+            let mut stmt = conn.prepare_cached(
+                "replace into progress(user_id, issue_id, page, updated_at) values (?, ?, ?, ?)",
+            )?;
+            stmt.execute(&[&user_id, &issue_id, &page, &Utc::now()])?;
+        }
+        if page >= count - 3 {
+            self.mark_read(issue_id, user_id)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_progress(&self, user_id: i64, issue_id: i64) -> Result<Option<i32>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare_cached("select page from progress where user_id = ? and issue_id = ?")?;
+        let mut rows = stmt.query(&[&user_id, &issue_id])?;
+        match rows.next() {
+            Some(row) => Ok(Some(row?.get(0))),
+            None => Ok(None),
+        }
+    }
+
+    /// How many pages get_page will serve for an issue
+    pub fn count_pages(&self, issue_id: i64) -> Result<i32, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "select count(*) from page where issue_id = ? and substr(entry, -3) in ('jpg', 'gif', 'png')",
+        )?;
+        Ok(stmt.query_row(&[&issue_id], |row| row.get(0))?)
+    }
+
+    pub fn mark_unread(&self, issue_id: i64, user_id: i64) -> Result<usize, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("delete from read where user_id = ? and issue_id = ?")?;
+        Ok(stmt.execute(&[&user_id, &issue_id])?)
+    }
+
+    /// Everything the user has read
+    pub fn get_read_ids(&self, user_id: i64) -> Result<HashSet<i64>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select issue_id from read where user_id = ?")?;
+        let iter = stmt.query_map(&[&user_id], |row| row.get(0))?;
+        let mut retval = HashSet::new();
+        for issue_id in iter {
+            retval.insert(issue_id?);
+        }
+        Ok(retval)
+    }
+
+    /// The page the user is on for everything they've started
+    pub fn get_all_progress(&self, user_id: i64) -> Result<HashMap<i64, i32>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare_cached("select issue_id, page from progress where user_id = ?")?;
+        let iter = stmt.query_map(&[&user_id], |row| (row.get(0), row.get(1)))?;
+        let mut retval = HashMap::new();
+        for progress in iter {
+            let (issue_id, page) = progress?;
+            retval.insert(issue_id, page);
+        }
+        Ok(retval)
+    }
+
+    /// when the user last marked anything read, feeds filtered on read status
+    /// can only change when this (or the issues themselves) change
+    pub fn get_last_read_at(&self, user_id: i64) -> Result<Option<DateTime<Utc>>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select max(read_at) from read where user_id = ?")?;
+        Ok(stmt.query_row(&[&user_id], |row| row.get(0))?)
+    }
+
+    /// Like get_last_read_at, for feeds that depend on how far along the user is
+    pub fn get_last_progress_at(&self, user_id: i64) -> Result<Option<DateTime<Utc>>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare_cached("select max(updated_at) from progress where user_id = ?")?;
+        Ok(stmt.query_row(&[&user_id], |row| row.get(0))?)
+    }
+
+    /// grabs as user_id given a name.  If you want to check the password use
+    /// check_user, this version will raise an error if not found
+    pub fn get_user(&self, username: &str) -> Result<i64, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select rowid from user where username = ?")?;
+        Ok(stmt.query_row(&[&username], |row| row.get(0))?)
+    }
+
+    pub fn list_users(&self) -> Result<Vec<(String, Role)>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select username, role from user order by username")?;
+        let mut rows = stmt.query(&[])?;
+        let mut retval = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let role: String = row.get(1);
+            retval.push((row.get(0), role.parse()?));
+        }
+        Ok(retval)
+    }
+
+    /// check if the password is correct, returns None if it isn't or there is no such user
+    pub fn check_user(&self, username: &str, password: &str) -> Result<Option<User>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "select rowid, salt, ciphertext from user where username = ?",
+        )?;
+        let mut rows = stmt.query(&[&username])?;
+        if let Some(row) = rows.next() {
+            let row = row?;
+            let salt: Vec<u8> = row.get(1);
+            let db_cipher: Vec<u8> = row.get(2);
+            if hash_password(password, &salt) == db_cipher {
+                return Ok(Some(self.load_user(&conn, row.get(0))?));
+            }
+        }
+        Ok(None)
+    }
+
+    /// either check if the password is correct or make a user with the password
+    pub fn check_or_provision_user(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> Result<Option<User>, Error> {
+        if self.get_user(username).is_ok() {
+            self.check_user(username, password)
+        } else {
+            let id = self.create_user(username, password)?;
+            let conn = self.pool.get()?;
+            Ok(Some(self.load_user(&conn, id)?))
+        }
+    }
+
+    /// Everything about a user we need to answer their requests
+    fn load_user(&self, conn: &Connection, user_id: i64) -> Result<User, Error> {
+        let (username, role, max_age_rating, generation) = conn.query_row(
+            "select username, role, max_age_rating, generation from user where rowid = ?",
+            &[&user_id],
+            |row| {
+                let role: String = row.get(1);
+                (row.get(0), role, row.get(2), row.get(3))
+            },
+        )?;
+        let role: Role = role.parse()?;
+
+        let mut restrictions = Restrictions {
+            max_age_rating,
+            ..Restrictions::default()
+        };
+        let mut stmt =
+            conn.prepare_cached("select kind, value from user_restriction where user_id = ?")?;
+        let mut rows = stmt.query(&[&user_id])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let value: String = row.get(1);
+            match row.get::<_, String>(0).parse()? {
+                RestrictionKind::Library => restrictions.libraries.push(value),
+                RestrictionKind::Path => restrictions.paths.push(restriction_path(&value)),
+                RestrictionKind::Publisher => restrictions.publishers.push(value),
+            }
+        }
+
+        Ok(User {
+            id: user_id,
+            username,
+            read_only: role == Role::Guest,
+            role,
+            restrictions,
+            generation,
+        })
+    }
+
+    /// None once the user is gone, see User::generation
+    pub fn get_user_generation(&self, user_id: i64) -> Result<Option<i64>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached("select generation from user where rowid = ?")?;
+        let mut rows = stmt.query(&[&user_id])?;
+        match rows.next() {
+            Some(row) => Ok(Some(row?.get(0))),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_role(&self, username: &str, role: Role) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        match conn.execute(
+            "update user set role = ?, generation = generation + 1 where username = ?",
+            &[&role.as_str(), &username],
+        )? {
+            0 => Err(NotFound("user").into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// None lets them see everything, including issues without an AgeRating
+    pub fn set_max_age_rating(&self, username: &str, age: Option<i32>) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        match conn.execute(
+            "update user set max_age_rating = ?, generation = generation + 1 where username = ?",
+            &[&age, &username],
+        )? {
+            0 => Err(NotFound("user").into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Once a user has any restriction of a kind, they only see issues matching one of them
+    pub fn add_restriction(
+        &self,
+        username: &str,
+        kind: RestrictionKind,
+        value: &str,
+    ) -> Result<(), Error> {
+        let user_id = self.get_user(username)?;
+        let value = match kind {
+            RestrictionKind::Path => restriction_path(value),
+            _ => value.to_owned(),
+        };
+        let conn = self.pool.get()?;
+        conn.execute(
+            "replace into user_restriction(user_id, kind, value) values (?, ?, ?)",
+            &[&user_id, &kind.as_str(), &value],
+        )?;
+        bump_generation(&conn, user_id)
+    }
+
+    pub fn remove_restriction(
+        &self,
+        username: &str,
+        kind: RestrictionKind,
+        value: &str,
+    ) -> Result<(), Error> {
+        let user_id = self.get_user(username)?;
+        // Paths stored before they got their trailing separator go too
+        let prefix = match kind {
+            RestrictionKind::Path => restriction_path(value),
+            _ => value.to_owned(),
+        };
+        let conn = self.pool.get()?;
+        match conn.execute(
+            "delete from user_restriction where user_id = ? and kind = ? and value in (?, ?)",
+            &[&user_id, &kind.as_str(), &value, &prefix],
+        )? {
+            0 => Err(NotFound("restriction").into()),
+            _ => bump_generation(&conn, user_id),
+        }
+    }
+
+    pub fn get_user_details(&self, username: &str) -> Result<User, Error> {
+        let user_id = self.get_user(username)?;
+        let conn = self.pool.get()?;
+        self.load_user(&conn, user_id)
+    }
+
+    /// raises an error if the username is taken
+    pub fn create_user(&self, username: &str, password: &str) -> Result<i64, Error> {
+        let mut conn = self.pool.get()?;
+        let salt = make_salt();
+        let ciphertext = hash_password(password, &salt);
+        // A user without their built-in shelves would have nowhere to put favorites
+        let tx = conn.transaction()?;
+        let user_id = tx
+            .prepare_cached("insert into user(username, salt, ciphertext) values (?, ?, ?)")?
+            .insert(&[&username, &salt, &ciphertext])?;
+        let builtin = [
+            (ShelfKind::Favorites, "Favorites"),
+            (ShelfKind::WantToRead, "Want to read"),
+        ];
+        for &(kind, name) in &builtin {
+            tx.execute(
+                "insert into shelf(user_id, kind, name, updated_at) values (?, ?, ?, ?)",
+                &[&user_id, &kind.as_str(), &name, &Utc::now()],
+            )?;
+        }
+        tx.commit()?;
+        Ok(user_id)
+    }
+
+    pub fn set_password(&self, username: &str, password: &str) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        let salt = make_salt();
+        let ciphertext = hash_password(password, &salt);
+        let mut stmt =
+            conn.prepare_cached(
+            "update user set salt = ?, ciphertext = ?, generation = generation + 1 where username = ?",
+        )?;
+        match stmt.execute(&[&salt, &ciphertext, &username])? {
+            0 => Err(NotFound("user").into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// also throws away everything they've read and their shelves
+    pub fn delete_user(&self, username: &str) -> Result<(), Error> {
+        let user_id = self.get_user(username)?;
+        let conn = self.pool.get()?;
+        conn.execute("delete from read where user_id = ?", &[&user_id])?;
+        conn.execute("delete from api_token where user_id = ?", &[&user_id])?;
+        conn.execute("delete from user_restriction where user_id = ?", &[&user_id])?;
+        conn.execute("delete from progress where user_id = ?", &[&user_id])?;
+        conn.execute(
+            "delete from reading_list_issue where list_id in (select rowid from reading_list where user_id = ?)",
+            &[&user_id],
+        )?;
+        conn.execute("delete from reading_list where user_id = ?", &[&user_id])?;
+        conn.execute(
+            "delete from shelf_issue where shelf_id in (select rowid from shelf where user_id = ?)",
+            &[&user_id],
+        )?;
+        conn.execute(
+            "delete from shelf_series where shelf_id in (select rowid from shelf where user_id = ?)",
+            &[&user_id],
+        )?;
+        conn.execute("delete from shelf where user_id = ?", &[&user_id])?;
+        conn.execute("delete from user where rowid = ?", &[&user_id])?;
+        Ok(())
+    }
+
+    /// Makes a new API token, this is the only time the token itself is available
+    pub fn create_token(
+        &self,
+        user_id: i64,
+        name: &str,
+        scope: TokenScope,
+    ) -> Result<String, Error> {
+        // 32 random bytes are as good for a token as for a salt
+        let token = ::base64::encode_config(&make_salt(), ::base64::URL_SAFE_NO_PAD);
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "insert into api_token(user_id, name, token_hash, scope, created_at) values (?, ?, ?, ?, ?)",
+        )?;
+        stmt.insert(&[
+            &user_id,
+            &name,
+            &hash_token(&token),
+            &scope.as_str(),
+            &Utc::now(),
+        ])?;
+        Ok(token)
+    }
+
+    /// Looks up who owns a token, recording that it was used
+    pub fn check_token(&self, token: &str) -> Result<Option<User>, Error> {
+        let hash = hash_token(token);
+        let conn = self.pool.get()?;
+        let mut stmt =
+            conn.prepare_cached("select user_id, scope from api_token where token_hash = ?")?;
+        let mut rows = stmt.query(&[&hash])?;
+        let mut user = match rows.next() {
+            Some(row) => {
+                let row = row?;
+                let scope: String = row.get(1);
+                let mut user = self.load_user(&conn, row.get(0))?;
+                if scope.parse::<TokenScope>()? == TokenScope::Read {
+                    user.read_only = true;
+                }
+                user
+            }
+            None => return Ok(None),
+        };
+        conn.execute(
+            "update api_token set last_used_at = ? where token_hash = ?",
+            &[&Utc::now(), &hash],
+        )?;
+        Ok(Some(user))
+    }
+
+    pub fn list_tokens(&self, user_id: i64) -> Result<Vec<ApiToken>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare_cached(
+            "select name, scope, created_at, last_used_at from api_token where user_id = ? order by name",
+        )?;
+        let mut rows = stmt.query(&[&user_id])?;
+        let mut tokens = Vec::new();
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let scope: String = row.get(1);
+            tokens.push(ApiToken {
+                name: row.get(0),
+                scope: scope.parse()?,
+                created_at: row.get(2),
+                last_used_at: row.get(3),
+            });
+        }
+        Ok(tokens)
+    }
+
+    pub fn revoke_token(&self, user_id: i64, name: &str) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        match conn.execute(
+            "delete from api_token where user_id = ? and name = ?",
+            &[&user_id, &name],
+        )? {
+            0 => Err(NotFound("token").into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What an API token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TokenScope {
+    /// Browse and download, without touching read status
+    Read,
+    /// Also records read status and progress, like logging in with a password
+    Progress,
+}
+
+impl TokenScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TokenScope::Read => "read",
+            TokenScope::Progress => "progress",
+        }
+    }
+}
+
+impl FromStr for TokenScope {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TokenScope, Error> {
+        match s {
+            "read" => Ok(TokenScope::Read),
+            "progress" => Ok(TokenScope::Progress),
+            _ => Err(format_err!("Unknown token scope {}", s)),
+        }
+    }
+}
+
+pub struct ApiToken {
+    pub name: String,
+    pub scope: TokenScope,
+    pub created_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+}
+
+/// What a user is allowed to do, on top of any restrictions on what they can see
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    /// Can manage the server and other users
+    Admin,
+    User,
+    /// Can browse and download, but nothing is recorded for them
+    Guest,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::User => "user",
+            Role::Guest => "guest",
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Role, Error> {
+        match s {
+            "admin" => Ok(Role::Admin),
+            "user" => Ok(Role::User),
+            "guest" => Ok(Role::Guest),
+            _ => Err(format_err!("Unknown role {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestrictionKind {
+    /// Only issues in one of these libraries, by name
+    Library,
+    /// Only issues under one of these paths
+    Path,
+    /// Only issues from one of these publishers
+    Publisher,
+}
+
+impl RestrictionKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RestrictionKind::Library => "library",
+            RestrictionKind::Path => "path",
+            RestrictionKind::Publisher => "publisher",
+        }
+    }
+}
+
+impl FromStr for RestrictionKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RestrictionKind, Error> {
+        match s {
+            "library" => Ok(RestrictionKind::Library),
+            "path" => Ok(RestrictionKind::Path),
+            "publisher" => Ok(RestrictionKind::Publisher),
+            _ => Err(format_err!("Unknown restriction {}", s)),
+        }
+    }
+}
+
+/// Limits on which issues a user can see.  Empty lists don't restrict anything.
+#[derive(Debug, Clone, Default)]
+pub struct Restrictions {
+    pub libraries: Vec<String>,
+    pub paths: Vec<String>,
+    pub publishers: Vec<String>,
+    /// Issues rated above this, or without a rating, are hidden
+    pub max_age_rating: Option<i32>,
+}
+
+impl Restrictions {
+    /// A condition on issue i to add to a where clause, along with its binds
+    pub fn to_sql(&self) -> (String, Vec<&ToSql>) {
+        self.to_sql_for("i")
+    }
+
+    /// The same condition on an issue with another alias, for subqueries
+    fn to_sql_for(&self, alias: &str) -> (String, Vec<&ToSql>) {
+        let mut clauses = Vec::new();
+        let mut params: Vec<&ToSql> = Vec::new();
+        if !self.libraries.is_empty() {
+            let binds: Vec<&str> = self.libraries.iter().map(|_| "?").collect();
+            clauses.push(format!(
+                "{}.library_id in (select rowid from library where name in ({}))",
+                alias,
+                binds.join(", ")
+            ));
+            params.extend(self.libraries.iter().map(|l| l as &ToSql));
+        }
+        if !self.paths.is_empty() {
+            let paths: Vec<String> = self.paths
+                .iter()
+                .map(|_| format!("instr({}.filepath, ?) = 1", alias))
+                .collect();
+            clauses.push(format!("({})", paths.join(" or ")));
+            params.extend(self.paths.iter().map(|p| p as &ToSql));
+        }
+        if !self.publishers.is_empty() {
+            let binds: Vec<&str> = self.publishers.iter().map(|_| "?").collect();
+            clauses.push(format!("{}.publisher in ({})", alias, binds.join(", ")));
+            params.extend(self.publishers.iter().map(|p| p as &ToSql));
+        }
+        if let Some(ref age) = self.max_age_rating {
+            clauses.push(format!("{}.age_rating <= ?", alias));
+            params.push(age);
+        }
+        if clauses.is_empty() {
+            ("1".to_owned(), params)
+        } else {
+            (clauses.join(" and "), params)
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LibraryStats {
+    pub issues: i64,
+    /// Issues without a series, which usually means no ComicInfo.xml
+    pub untagged: i64,
+    /// Issue count for each publisher, biggest first
+    pub publishers: Vec<(String, i64)>,
+}
+
+/// Orders for acquisition feeds, picked with facets
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sort {
+    /// Newest releases first
+    Released,
+    /// Newest to the library first
+    Added,
+    Series,
+    Title,
+}
+
+impl Sort {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Released => "released",
+            Sort::Added => "added",
+            Sort::Series => "series",
+            Sort::Title => "title",
+        }
+    }
+
+    fn order_by(&self) -> &'static str {
+        match self {
+            Sort::Released => "i.released_at desc",
+            Sort::Added => "i.added_at desc, i.rowid desc",
+            Sort::Series => "i.series, i.volume, i.issue_number",
+            Sort::Title => "i.title, i.series, i.volume, i.issue_number",
+        }
+    }
+}
+
+impl FromStr for Sort {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Sort, Error> {
+        match s {
+            "released" => Ok(Sort::Released),
+            "added" => Ok(Sort::Added),
+            "series" => Ok(Sort::Series),
+            "title" => Ok(Sort::Title),
+            _ => Err(format_err!("Unknown sort {}", s)),
+        }
+    }
+}
+
+/// The kinds of archive we serve
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Cbz,
+    Cbr,
+}
+
+impl Format {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Format::Cbz => "cbz",
+            Format::Cbr => "cbr",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format, Error> {
+        match s {
+            "cbz" => Ok(Format::Cbz),
+            "cbr" => Ok(Format::Cbr),
+            _ => Err(format_err!("Unknown format {}", s)),
+        }
+    }
+}
+
+/// Sorting and filtering picked from the facet links of an acquisition feed,
+/// anything left out keeps the feed's own order and contents
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Facets {
+    pub sort: Option<Sort>,
+    pub read: Option<bool>,
+    pub publisher: Option<String>,
+    /// Year of release
+    pub year: Option<i32>,
+    pub format: Option<Format>,
+}
+
+impl Facets {
+    /// From the query string of a feed URL, see to_query
+    pub fn from_query(params: &HashMap<String, String>) -> Result<Facets, Error> {
+        let mut facets = Facets::default();
+        for (name, value) in params {
+            match name.as_str() {
+                "sort" => facets.sort = Some(value.parse()?),
+                "read" => {
+                    facets.read = Some(value.parse().map_err(|_| {
+                        format_err!("read should be true or false, not {}", value)
+                    })?)
+                }
+                "publisher" => facets.publisher = Some(value.clone()),
+                "year" => {
+                    facets.year = Some(value
+                        .parse()
+                        .map_err(|_| format_err!("{} is not a year", value))?)
+                }
+                "format" => facets.format = Some(value.parse()?),
+                _ => (),
+            }
+        }
+        Ok(facets)
+    }
+
+    pub fn to_query(&self) -> String {
+        let mut query = form_urlencoded::Serializer::new(String::new());
+        if let Some(sort) = self.sort {
+            query.append_pair("sort", sort.as_str());
+        }
+        if let Some(read) = self.read {
+            query.append_pair("read", &read.to_string());
+        }
+        if let Some(ref publisher) = self.publisher {
+            query.append_pair("publisher", publisher);
+        }
+        if let Some(year) = self.year {
+            query.append_pair("year", &year.to_string());
+        }
+        if let Some(format) = self.format {
+            query.append_pair("format", format.as_str());
+        }
+        query.finish()
+    }
+}
+
+/// A condition on the issues an IssueQuery picks.  Each owns what it binds, so
+/// the SQL and its params can't get out of step.
+#[derive(Debug, Clone)]
+enum Filter {
+    Id(i64),
+    Library(i64),
+    /// Directly inside the folder whose paths all start with this, see folder_prefix
+    Folder(String),
+    Series(SeriesKey),
+    /// Just the series name, whatever the volume or publisher
+    SeriesName(Option<String>),
+    /// None for issues without one
+    Publisher(Option<String>),
+    /// Year of release
+    Year(i32),
+    Format(Format),
+    Read(bool),
+    /// The user has a page saved for it
+    Started,
+    /// The first unread issue of a series whose previous issue the user read
+    /// since the given time, and which they haven't started.  Issues hidden by
+    /// the restrictions don't count as first or previous.
+    OnDeck {
+        user_id: i64,
+        since: DateTime<Utc>,
+        restrictions: Restrictions,
+    },
+    /// Every word is in the ComicInfo.xml or the path, fts is the words quoted for FTS
+    Search { fts: String, words: Vec<String> },
+    /// On a reading list
+    List(i64),
+    /// Put on a shelf, not counting those in a shelved series
+    Shelf(i64),
+    /// With a genre, tag, character, team or location
+    Category(i64),
+}
+
+impl Filter {
+    /// A condition on i or r, pushing its binds onto params
+    fn to_sql<'a>(&'a self, params: &mut Vec<&'a ToSql>) -> String {
+        match *self {
+            Filter::Id(ref id) => {
+                params.push(id);
+                "i.rowid = ?".to_owned()
+            }
+            Filter::Library(ref id) => {
+                params.push(id);
+                "i.library_id = ?".to_owned()
+            }
+            Filter::Folder(ref prefix) => {
+                params.push(prefix);
+                params.push(prefix);
+                // The separator is a / or \, either is fine to put in the SQL
+                format!(
+                    "instr(i.filepath, ?) = 1 and instr(substr(i.filepath, length(?) + 1), '{}') = 0",
+                    MAIN_SEPARATOR
+                )
+            }
+            Filter::Series(ref key) => {
+                params.push(&key.name);
+                params.push(&key.volume);
+                params.push(&key.publisher);
+                // IS rather than = so missing values match each other
+                "i.series is ? and i.volume is ? and i.publisher is ?".to_owned()
+            }
+            Filter::SeriesName(ref name) => {
+                params.push(name);
+                "i.series is ?".to_owned()
+            }
+            Filter::Publisher(ref publisher) => {
+                params.push(publisher);
+                "i.publisher is ?".to_owned()
+            }
+            Filter::Year(ref year) => {
+                // A range rather than strftime, so it can use issue_released_at
+                params.push(year);
+                params.push(year);
+                "i.released_at >= printf('%04d-01-01', ?) and i.released_at < printf('%04d-01-01', ? + 1)"
+                    .to_owned()
+            }
+            // as_str is one of ours, so it's safe to put in the SQL
+            Filter::Format(format) => format!("lower(i.filepath) like '%.{}'", format.as_str()),
+            Filter::Read(true) => "r.issue_id is not null".to_owned(),
+            Filter::Read(false) => "r.issue_id is null".to_owned(),
+            Filter::Started => "p.issue_id is not null".to_owned(),
+            Filter::OnDeck {
+                ref user_id,
+                ref since,
+                ref restrictions,
+            } => {
+                let (next_restriction, next_params) = restrictions.to_sql_for("n");
+                let (prev_restriction, prev_params) = restrictions.to_sql_for("prev");
+                params.push(user_id);
+                params.extend(next_params);
+                params.push(user_id);
+                params.extend(prev_params);
+                params.push(since);
+                format!("r.issue_id is null and p.issue_id is null and i.series_id is not null
+                and i.rowid = (select n.rowid from issue n left join read nr on nr.issue_id = n.rowid and nr.user_id = ? where n.series_id = i.series_id and nr.issue_id is null and {} order by n.issue_number, n.released_at limit 1)
+                and (select pr.read_at from issue prev left join read pr on pr.issue_id = prev.rowid and pr.user_id = ? where prev.series_id = i.series_id and prev.issue_number < i.issue_number and {} order by prev.issue_number desc, prev.released_at desc limit 1) > ?", next_restriction, prev_restriction)
+            }
+            Filter::Search { ref fts, ref words } => {
+                if words.is_empty() {
+                    return "0".to_owned();
+                }
+                params.push(fts);
+                params.extend(words.iter().map(|w| w as &ToSql));
+                let path_clause = words
+                    .iter()
+                    .map(|_| "instr(lower(i.filepath), ?) > 0")
+                    .collect::<Vec<_>>()
+                    .join(" and ");
+                format!(
+                    "(i.rowid in (select issue_id from issue_fts where comicinfo match ?) or ({}))",
+                    path_clause
+                )
+            }
+            Filter::List(ref id) => {
+                params.push(id);
+                "i.rowid in (select issue_id from reading_list_issue where list_id = ?)".to_owned()
+            }
+            Filter::Shelf(ref id) => {
+                params.push(id);
+                "i.rowid in (select issue_id from shelf_issue where shelf_id = ?)".to_owned()
+            }
+            Filter::Category(ref id) => {
+                params.push(id);
+                "i.rowid in (select issue_id from issue_category where category_id = ?)".to_owned()
+            }
+        }
+    }
+}
+
+/// How an IssueQuery comes back, besides the Sorts picked with facets
+#[derive(Debug, Clone, PartialEq)]
+enum Order {
+    /// Whatever order sqlite finds them in
+    Unordered,
+    Sorted(Sort),
+    /// Oldest releases first, for catching up
+    OldestFirst,
+    /// Reading order within a series
+    IssueNumber,
+    Path,
+    /// Most recently read from first, for issues they've started
+    LastRead,
+    /// Where they are on a reading list
+    List(i64),
+    /// By the month they came out, then by series
+    Month,
+}
+
+impl Order {
+    /// What goes after order by, pushing any binds onto params
+    fn to_sql<'a>(&'a self, params: &mut Vec<&'a ToSql>) -> &'static str {
+        match *self {
+            Order::Unordered => "",
+            Order::Sorted(sort) => sort.order_by(),
+            Order::OldestFirst => "i.released_at",
+            Order::IssueNumber => "i.issue_number, i.released_at",
+            Order::Path => "i.filepath",
+            Order::LastRead => "p.updated_at desc",
+            Order::Month => "strftime('%m', i.released_at), i.series, i.volume, i.issue_number",
+            Order::List(ref id) => {
+                params.push(id);
+                "(select min(position) from reading_list_issue where list_id = ? and issue_id = i.rowid)"
+            }
+        }
+    }
+}
+
+/// Picks out a list of issues for a feed, a page or the API.  The user's
+/// restrictions always apply, and their read status and progress are joined
+/// in as r and p.
+/// Run it with DB::query_issues.
+#[derive(Debug, Clone)]
+pub struct IssueQuery<'a> {
+    user: &'a User,
+    filters: Vec<Filter>,
+    order: Order,
+    /// offset and limit
+    page: Option<(i64, i64)>,
+}
+
+impl<'a> IssueQuery<'a> {
+    /// Every issue the user can see
+    pub fn new(user: &'a User) -> IssueQuery<'a> {
+        IssueQuery {
+            user,
+            filters: Vec::new(),
+            order: Order::Unordered,
+            page: None,
+        }
+    }
+
+    fn filter(mut self, filter: Filter) -> IssueQuery<'a> {
+        self.filters.push(filter);
+        self
+    }
+
+    fn order(mut self, order: Order) -> IssueQuery<'a> {
+        self.order = order;
+        self
+    }
+
+    pub fn id(self, id: i64) -> IssueQuery<'a> {
+        self.filter(Filter::Id(id))
+    }
+
+    pub fn library(self, library_id: i64) -> IssueQuery<'a> {
+        self.filter(Filter::Library(library_id))
+    }
+
+    pub fn series(self, key: &SeriesKey) -> IssueQuery<'a> {
+        self.filter(Filter::Series(key.clone()))
+    }
+
+    pub fn series_name(self, name: Option<String>) -> IssueQuery<'a> {
+        self.filter(Filter::SeriesName(name))
+    }
+
+    pub fn publisher(self, publisher: Option<String>) -> IssueQuery<'a> {
+        self.filter(Filter::Publisher(publisher))
+    }
+
+    pub fn read(self, read: bool) -> IssueQuery<'a> {
+        self.filter(Filter::Read(read))
+    }
+
+    /// Issues on a reading list, in its order
+    pub fn list(self, list_id: i64) -> IssueQuery<'a> {
+        self.filter(Filter::List(list_id)).order(Order::List(list_id))
+    }
+
+    pub fn shelf(self, shelf_id: i64) -> IssueQuery<'a> {
+        self.filter(Filter::Shelf(shelf_id))
+    }
+
+    /// A genre, tag, character, team or location, by id
+    pub fn category(self, category_id: i64) -> IssueQuery<'a> {
+        self.filter(Filter::Category(category_id))
+    }
+
+    pub fn year(self, year: i32) -> IssueQuery<'a> {
+        self.filter(Filter::Year(year))
+    }
+
+    /// Issues with every word of query in their ComicInfo.xml or path,
+    /// a query without any words finds nothing
+    pub fn search(self, query: &str) -> IssueQuery<'a> {
+        let words: Vec<String> = query.split_whitespace().map(|w| w.to_lowercase()).collect();
+        // Quote every word so FTS doesn't try to make sense of the punctuation
+        let fts = words
+            .iter()
+            .map(|w| format!("\"{}\"", w.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.filter(Filter::Search { fts, words })
+    }
+
+    pub fn sort(self, sort: Sort) -> IssueQuery<'a> {
+        self.order(Order::Sorted(sort))
+    }
+
+    /// Narrows and sorts by whatever facets were picked
+    pub fn facets(mut self, facets: &Facets) -> IssueQuery<'a> {
+        if let Some(sort) = facets.sort {
+            self = self.sort(sort);
+        }
+        if let Some(read) = facets.read {
+            self = self.read(read);
+        }
+        if let Some(ref publisher) = facets.publisher {
+            self = self.publisher(Some(publisher.clone()));
+        }
+        if let Some(year) = facets.year {
+            self = self.filter(Filter::Year(year));
+        }
+        if let Some(format) = facets.format {
+            self = self.filter(Filter::Format(format));
+        }
+        self
+    }
+
+    /// Only limit issues, skipping the first offset
+    pub fn page(mut self, offset: i64, limit: i64) -> IssueQuery<'a> {
+        self.page = Some((offset, limit));
+        self
+    }
+
+    /// The from and where clauses shared by the select and the count
+    fn where_clause(&self) -> (String, Vec<&ToSql>) {
+        let (restriction, restriction_params) = self.user.restrictions.to_sql();
+        let mut params: Vec<&ToSql> = vec![&self.user.id, &self.user.id];
+        params.extend(restriction_params);
+        let mut conditions = vec![restriction];
+        for filter in &self.filters {
+            conditions.push(filter.to_sql(&mut params));
+        }
+        let sql = format!(
+            "from issue i left join read r on r.issue_id = i.rowid and r.user_id = ? left join progress p on p.issue_id = i.rowid and p.user_id = ? where {}",
+            conditions.join(" and ")
+        );
+        (sql, params)
+    }
+
+    fn to_sql(&self) -> (String, Vec<&ToSql>) {
+        let (where_clause, mut params) = self.where_clause();
+        let mut sql = format!("{} {}", ISSUE_COLUMNS, where_clause);
+        if self.order != Order::Unordered {
+            sql.push_str(" order by ");
+            sql.push_str(self.order.to_sql(&mut params));
+        }
+        if let Some((ref offset, ref limit)) = self.page {
+            sql.push_str(" limit ? offset ?");
+            params.push(limit);
+            params.push(offset);
+        }
+        (sql, params)
+    }
+
+    /// Counts every issue the query picks, whatever page it's on
+    fn count_sql(&self) -> (String, Vec<&ToSql>) {
+        let (where_clause, params) = self.where_clause();
+        (format!("select count(*) {}", where_clause), params)
+    }
+
+    /// The years the query picks anything from with how many and when they last
+    /// changed, oldest first
+    fn years_sql(&self) -> (String, Vec<&ToSql>) {
+        let (where_clause, params) = self.where_clause();
+        (
+            format!(
+                "select cast(strftime('%Y', i.released_at) AS INTEGER) as year, count(i.rowid), max(i.modified_at) {} and i.released_at is not null group by year order by year",
+                where_clause
+            ),
+            params,
+        )
+    }
+}
+
+/// Which series select_series picks, out of those the user can see
+enum SeriesFilter<'a> {
+    All,
+    Id(i64),
+    Key(&'a SeriesKey),
+    /// None for series without one
+    Publisher(&'a Option<String>),
+    /// With an issue the user hasn't read
+    Unread,
+    /// Named, with an unread issue released in the last six months
+    RecentUnread,
+    /// Put on this shelf
+    Shelf(i64),
+    /// In this collection
+    Collection(i64),
+}
+
+impl<'a> SeriesFilter<'a> {
+    /// The select for select_series, with its binds
+    fn to_sql<'b>(&'b self, user: &'b User) -> (String, Vec<&'b ToSql>) {
+        let (restriction, restriction_params) = user.restrictions.to_sql();
+        // The cover and description come from the first issue the user can see,
+        // so each of those picks binds the restrictions again
+        let mut params: Vec<&ToSql> = restriction_params.clone();
+        params.extend(restriction_params.iter().cloned());
+        params.push(&user.id);
+        params.extend(restriction_params);
+        let (filter, having) = match *self {
+            SeriesFilter::All => ("", ""),
+            SeriesFilter::Id(ref id) => {
+                params.push(id);
+                ("and s.rowid = ?", "")
+            }
+            SeriesFilter::Key(key) => {
+                params.extend(vec![&key.name as &ToSql, &key.volume, &key.publisher]);
+                ("and s.name is ? and s.volume is ? and s.publisher is ?", "")
+            }
+            SeriesFilter::Publisher(publisher) => {
+                params.push(publisher);
+                ("and s.publisher is ?", "")
+            }
+            SeriesFilter::Unread => ("", "having count(r.issue_id) < count(i.rowid)"),
+            SeriesFilter::RecentUnread => (
+                "and s.name is not null",
+                "having max(r.issue_id is null and date('now','-6 months') < i.released_at) = 1",
+            ),
+            SeriesFilter::Shelf(ref shelf_id) => {
+                params.push(shelf_id);
+                (
+                    "and s.rowid in (select series_id from shelf_series where shelf_id = ?)",
+                    "",
+                )
+            }
+            SeriesFilter::Collection(ref collection_id) => {
+                params.push(collection_id);
+                (
+                    "and s.rowid in (select series_id from collection_series where collection_id = ?)",
+                    "",
+                )
+            }
+        };
+        let first_issue = format!(
+            "from issue i where i.series_id = s.rowid and {} order by i.issue_number, i.released_at limit 1",
+            restriction
+        );
+        let sql = format!(
+            "select s.rowid, s.name, s.volume, s.publisher, s.start_year, (select i.summary {first}), (select i.rowid {first}), count(i.rowid), count(i.rowid) - count(r.issue_id), max(i.modified_at), s.direction from series s join issue i on i.series_id = s.rowid left join read r on r.issue_id = i.rowid and r.user_id = ? where {} {} group by s.rowid {} order by s.name, s.volume, s.publisher",
+            restriction,
+            filter,
+            having,
+            first = first_issue
+        );
+        (sql, params)
+    }
+}
+
+/// Which publishers select_publishers picks, out of those the user can see
+enum PublisherFilter<'a> {
+    All,
+    Id(i64),
+    /// None for the publisher of issues without one
+    Name(&'a Option<String>),
+}
+
+impl<'a> PublisherFilter<'a> {
+    /// The select for select_publishers, with its binds
+    fn to_sql<'b>(&'b self, user: &'b User) -> (String, Vec<&'b ToSql>) {
+        let (restriction, mut params) = user.restrictions.to_sql();
+        let filter = match *self {
+            PublisherFilter::All => "",
+            PublisherFilter::Id(ref id) => {
+                params.push(id);
+                "and p.rowid = ?"
+            }
+            PublisherFilter::Name(name) => {
+                params.push(name);
+                "and p.name is ?"
+            }
+        };
+        let sql = format!(
+            "select p.rowid, p.name, max(i.modified_at) from publisher p join issue i on i.publisher is p.name where {} {} group by p.rowid order by p.name",
+            restriction, filter
+        );
+        (sql, params)
+    }
+}
+
+/// Which reading lists select_lists picks, they're shared so it's out of everyone's
+enum ListFilter<'a> {
+    All,
+    Id(i64),
+    Name(&'a str),
+}
+
+impl<'a> ListFilter<'a> {
+    /// The select for select_lists, with its binds
+    fn to_sql<'b>(&'b self, user: &'b User) -> (String, Vec<&'b ToSql>) {
+        let (restriction, mut params) = user.restrictions.to_sql();
+        params.push(&user.id);
+        let filter = match *self {
+            ListFilter::All => "",
+            ListFilter::Id(ref id) => {
+                params.push(id);
+                "and l.rowid = ?"
+            }
+            ListFilter::Name(ref name) => {
+                params.push(name);
+                "and l.name = ?"
+            }
+        };
+        let sql = format!(
+            "select l.rowid, l.name, l.user_id, u.username, count(i.rowid), count(i.rowid) - count(r.issue_id), l.updated_at from reading_list l join user u on u.rowid = l.user_id left join reading_list_issue li on li.list_id = l.rowid left join issue i on i.rowid = li.issue_id and {} left join read r on r.issue_id = i.rowid and r.user_id = ? where 1 {} group by l.rowid order by l.name",
+            restriction, filter
+        );
+        (sql, params)
+    }
+}
+
+/// Which of the user's shelves select_shelves picks
+enum ShelfFilter<'a> {
+    All,
+    Id(i64),
+    Name(&'a str),
+}
+
+impl<'a> ShelfFilter<'a> {
+    /// The select for select_shelves, with its binds
+    fn to_sql<'b>(&'b self, user: &'b User) -> (String, Vec<&'b ToSql>) {
+        let (restriction, mut params) = user.restrictions.to_sql();
+        params.push(&user.id);
+        let filter = match *self {
+            ShelfFilter::All => "",
+            ShelfFilter::Id(ref id) => {
+                params.push(id);
+                "and sh.rowid = ?"
+            }
+            ShelfFilter::Name(ref name) => {
+                params.push(name);
+                "and sh.name = ?"
+            }
+        };
+        let sql = format!(
+            "select sh.rowid, sh.kind, sh.name, count(i.rowid), count(i.rowid) - count(r.issue_id), sh.updated_at from shelf sh left join issue i on (i.rowid in (select issue_id from shelf_issue where shelf_id = sh.rowid) or i.series_id in (select series_id from shelf_series where shelf_id = sh.rowid)) and {} left join read r on r.issue_id = i.rowid and r.user_id = sh.user_id where sh.user_id = ? {} group by sh.rowid order by sh.kind = 'custom', sh.kind, sh.name",
+            restriction, filter
+        );
+        (sql, params)
+    }
+}
+
+/// Which categories of a kind select_categories picks, out of those the user can see
+enum CategoryFilter {
+    All,
+    Id(i64),
+}
+
+impl CategoryFilter {
+    /// The select for select_categories, with its binds
+    fn to_sql<'b>(&'b self, user: &'b User, kind: &'b &'static str) -> (String, Vec<&'b ToSql>) {
+        let (restriction, restriction_params) = user.restrictions.to_sql();
+        let mut params: Vec<&ToSql> = vec![kind];
+        params.extend(restriction_params);
+        let filter = match *self {
+            CategoryFilter::All => "",
+            CategoryFilter::Id(ref id) => {
+                params.push(id);
+                "and c.rowid = ?"
+            }
+        };
+        let sql = format!(
+            "select c.rowid, c.name, count(i.rowid), max(i.modified_at) from category c join issue_category ic on ic.category_id = c.rowid join issue i on i.rowid = ic.issue_id where c.kind = ? and {} {} group by c.rowid order by c.name",
+            restriction, filter
+        );
+        (sql, params)
+    }
+}
+
+/// Which collections select_collections picks
+enum CollectionFilter<'a> {
+    All,
+    Id(i64),
+    Name(&'a str),
+}
+
+impl<'a> CollectionFilter<'a> {
+    /// The select for select_collections, with its binds
+    fn to_sql(&self) -> (String, Vec<&ToSql>) {
+        let (filter, params) = match *self {
+            CollectionFilter::All => ("", Vec::new()),
+            CollectionFilter::Id(ref id) => ("and rowid = ?", vec![id as &ToSql]),
+            CollectionFilter::Name(ref name) => ("and name = ?", vec![name as &ToSql]),
+        };
+        let sql = format!(
+            "select rowid, name, updated_at from collection where 1 {} order by name",
+            filter
+        );
+        (sql, params)
+    }
+}
+
+/// A library as stored, see LibraryConfig for the rest of its settings
+#[derive(Debug, Clone)]
+pub struct Library {
+    pub id: i64,
+    pub name: String,
+    /// Overrides how its issues are read
+    pub direction: Option<Direction>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A list of issues in reading order, made through the API or imported from a .cbl
+#[derive(Debug, Clone, Serialize)]
+pub struct ReadingList {
+    pub id: i64,
+    pub name: String,
+    /// Only they (or an admin) can change it
+    pub owner_id: i64,
+    pub owner: String,
+    /// Of the issues the requesting user can see
+    pub issue_count: i64,
+    pub unread_count: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Somewhere a user keeps issues and series they want to find again, only they see it
+#[derive(Debug, Clone, Serialize)]
+pub struct Shelf {
+    pub id: i64,
+    pub kind: ShelfKind,
+    pub name: String,
+    /// Of the issues the user can see, whether shelved themselves or by their series
+    pub issue_count: i64,
+    pub unread_count: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Everyone has a favorites and want to read shelf, and can make more
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShelfKind {
+    Favorites,
+    WantToRead,
+    /// Made by the user, the only ones they can delete
+    Custom,
+}
+
+impl ShelfKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ShelfKind::Favorites => "favorites",
+            ShelfKind::WantToRead => "want_to_read",
+            ShelfKind::Custom => "custom",
+        }
+    }
+}
+
+impl FromStr for ShelfKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ShelfKind, Error> {
+        match s {
+            "favorites" => Ok(ShelfKind::Favorites),
+            "want_to_read" => Ok(ShelfKind::WantToRead),
+            "custom" => Ok(ShelfKind::Custom),
+            _ => Err(format_err!("Unknown shelf kind {}", s)),
+        }
+    }
+}
+
+/// A genre, tag, character, team or location from ComicInfo.xml, with counts for whoever asked
+#[derive(Debug, Clone, Serialize)]
+pub struct Category {
+    pub id: i64,
+    pub kind: CategoryKind,
+    pub name: String,
+    pub issue_count: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CategoryKind {
+    Genre,
+    Tag,
+    Character,
+    Team,
+    Location,
+}
+
+impl CategoryKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CategoryKind::Genre => "genre",
+            CategoryKind::Tag => "tag",
+            CategoryKind::Character => "character",
+            CategoryKind::Team => "team",
+            CategoryKind::Location => "location",
+        }
+    }
+}
+
+impl FromStr for CategoryKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<CategoryKind, Error> {
+        match s {
+            "genre" => Ok(CategoryKind::Genre),
+            "tag" => Ok(CategoryKind::Tag),
+            "character" => Ok(CategoryKind::Character),
+            "team" => Ok(CategoryKind::Team),
+            "location" => Ok(CategoryKind::Location),
+            _ => Err(format_err!("Unknown category {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ReleaseYear {
+    pub year: i32,
+    pub issue_count: i64,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Series an admin put together, like everything in a crossover
+#[derive(Debug, Clone, Serialize)]
+pub struct Collection {
+    pub id: i64,
+    pub name: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A publisher as stored by refresh_series
+#[derive(Debug, Clone, Serialize)]
+pub struct Publisher {
+    pub id: i64,
+    pub name: Option<String>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Publisher {
+    /// Issues without a publisher are all lumped in with one called None
+    pub fn get_name(&self) -> &str {
+        self.name.as_ref().map_or("None", |n| &**n)
+    }
+}
+
+/// A series as stored by refresh_series, with counts for whoever asked
+#[derive(Debug, Clone, Serialize)]
+pub struct Series {
+    pub id: i64,
+    pub name: Option<String>,
+    pub volume: Option<i32>,
+    pub publisher: Option<String>,
+    pub start_year: Option<i32>,
+    /// The summary of its first issue
+    pub description: Option<String>,
+    pub cover_issue_id: Option<i64>,
+    pub issue_count: i64,
+    pub unread_count: i64,
+    pub updated_at: DateTime<Utc>,
+    /// Only set when its issues say, otherwise it's up to the library
+    pub direction: Option<Direction>,
+}
+
+impl Series {
+    /// Issues without a series are all lumped in with one called None
+    pub fn get_name(&self) -> &str {
+        self.name.as_ref().map_or("None", |n| &**n)
+    }
+
+    /// The name with enough added to tell apart series that share it, like Batman (2016)
+    pub fn get_title(&self) -> String {
+        match (self.start_year, self.volume) {
+            (Some(year), _) => format!("{} ({})", self.get_name(), year),
+            (None, Some(volume)) => format!("{} v{}", self.get_name(), volume),
+            (None, None) => self.get_name().to_owned(),
+        }
+    }
+
+    pub fn key(&self) -> SeriesKey {
+        SeriesKey {
+            name: self.name.clone(),
+            volume: self.volume,
+            publisher: self.publisher.clone(),
+        }
+    }
+}
+
+/// What tells one series from another, two Batmans can differ in volume
+/// and the same name can be used by different publishers
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeriesKey {
+    pub name: Option<String>,
+    pub volume: Option<i32>,
+    pub publisher: Option<String>,
+}
+
+impl SeriesKey {
+    /// From the segments of old URLs, where None stands in for anything missing
+    pub fn from_parts(publisher: &str, name: &str, volume: &str) -> Result<SeriesKey, Error> {
+        let part = |p: &str| if p == "None" { None } else { Some(p.to_owned()) };
+        let volume = match volume {
+            "None" => None,
+            v => Some(v.parse::<i32>().map_err(|_| NotFound("volume"))?),
+        };
+        Ok(SeriesKey {
+            name: part(name),
+            volume,
+            publisher: part(publisher),
+        })
+    }
+}
+
+/// Whoever is making a request
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    /// We don't record anything (like read status) for read only users
+    pub read_only: bool,
+    pub restrictions: Restrictions,
+    /// Goes up whenever their password, role or restrictions change, so a cached
+    /// login can tell it's out of date even when the change came from opds_users
+    pub generation: i64,
+}
+
+impl User {
+    /// Stand in for requests without credentials when the auth mode allows them.
+    /// rowids start at 1, so it will never own anything in the DB
+    pub fn anonymous() -> User {
+        User {
+            id: 0,
+            username: "anonymous".to_owned(),
+            role: Role::Guest,
+            read_only: true,
+            restrictions: Restrictions::default(),
+            generation: 0,
+        }
+    }
+}
+
+/// Path restrictions end in a separator, so allowing Kids doesn't let KidsMature through
+fn restriction_path(path: &str) -> String {
+    let mut path = path.to_owned();
+    if !path.ends_with(MAIN_SEPARATOR) {
+        path.push(MAIN_SEPARATOR);
+    }
+    path
+}
+
+fn insert_list(conn: &Connection, user_id: i64, name: &str) -> Result<i64, Error> {
+    let mut stmt = conn.prepare_cached(
+        "insert into reading_list(name, user_id, updated_at) values (?, ?, ?)",
+    )?;
+    stmt.insert(&[&name, &user_id, &Utc::now()])
+        .map_err(|e| list_name_taken(e, name))
+}
+
+/// The unique index on the name has the last word when two requests race past find_list
+fn list_name_taken(e: ::rusqlite::Error, name: &str) -> Error {
+    match e {
+        ::rusqlite::Error::SqliteFailure(ref failure, _)
+            if failure.code == ErrorCode::ConstraintViolation =>
+        {
+            Invalid(format!("There's already a list called {}", name)).into()
+        }
+        e => e.into(),
+    }
+}
+
+fn replace_list_issues(conn: &Connection, list_id: i64, issue_ids: &[i64]) -> Result<(), Error> {
+    conn.execute("delete from reading_list_issue where list_id = ?", &[&list_id])?;
+    {
+        let mut stmt = conn.prepare_cached(
+            "insert into reading_list_issue(list_id, position, issue_id) values (?, ?, ?)",
+        )?;
+        for (position, issue_id) in issue_ids.iter().enumerate() {
+            stmt.execute(&[&list_id, &(position as i64), issue_id])?;
+        }
+    }
+    conn.execute(
+        "update reading_list set updated_at = ? where rowid = ?",
+        &[&Utc::now(), &list_id],
+    )?;
+    Ok(())
+}
+
+fn bump_generation(conn: &Connection, user_id: i64) -> Result<(), Error> {
+    conn.execute(
+        "update user set generation = generation + 1 where rowid = ?",
+        &[&user_id],
+    )?;
+    Ok(())
+}
+
+/// Replaces the genres, tags, characters, teams and locations of an issue with the ones in info
+fn store_categories(conn: &Connection, issue_id: i64, info: &ComicInfo) -> Result<(), Error> {
+    conn.execute("delete from issue_category where issue_id = ?", &[&issue_id])?;
+    let categories = [
+        (CategoryKind::Genre, &info.genres),
+        (CategoryKind::Tag, &info.tags),
+        (CategoryKind::Character, &info.characters),
+        (CategoryKind::Team, &info.teams),
+        (CategoryKind::Location, &info.locations),
+    ];
+    for &(kind, names) in &categories {
+        for name in names.iter() {
+            conn.execute(
+                "insert or ignore into category(kind, name) values (?, ?)",
+                &[&kind.as_str(), name],
+            )?;
+            conn.execute(
+                "insert or ignore into issue_category(issue_id, category_id) select ?, rowid from category where kind = ? and name = ?",
+                &[&issue_id, &kind.as_str(), name],
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// What every path in a folder of a library starts with.  folder is relative to
+/// the library, with / between its parts whatever the platform.
+fn folder_prefix(conn: &Connection, library_id: i64, folder: &str) -> Result<String, Error> {
+    let mut prefix: String = conn.query_row(
+        "select path from library where rowid = ?",
+        &[&library_id],
+        |row| row.get(0),
+    )?;
+    let prefix_len = prefix.trim_right_matches(MAIN_SEPARATOR).len();
+    prefix.truncate(prefix_len);
+    prefix.push(MAIN_SEPARATOR);
+    for part in folder.split('/').filter(|p| !p.is_empty()) {
+        prefix.push_str(part);
+        prefix.push(MAIN_SEPARATOR);
+    }
+    Ok(prefix)
+}
+
+/// Schema changes after the CREATE TABLE IF NOT EXISTS above, applied in order
+/// and tracked with PRAGMA user_version.  Never edit one that has shipped, add another.
+/// The flag is set when the migration adds columns filled from ComicInfo.xml.
+const MIGRATIONS: &[(&str, bool)] = &[
+    (
+        "
+          ALTER TABLE user ADD COLUMN role TEXT NOT NULL DEFAULT 'user';
+          ALTER TABLE user ADD COLUMN max_age_rating INTEGER;
+          ALTER TABLE issue ADD COLUMN age_rating INTEGER;
+          CREATE TABLE user_restriction (
+            user_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            value TEXT NOT NULL
+          );
+          CREATE UNIQUE INDEX user_restriction_user on user_restriction(user_id, kind, value);
+          ",
+        true,
+    ),
+    (
+        "
+          CREATE TABLE library (
+            name TEXT PRIMARY KEY,
+            path TEXT NOT NULL,
+            direction TEXT NOT NULL DEFAULT 'ltr'
+          );
+          ALTER TABLE issue ADD COLUMN library_id INTEGER;
+          CREATE INDEX issue_library on issue(library_id);
+          ",
+        false,
+    ),
+    (
+        "
+          CREATE TABLE progress (
+            user_id INTEGER NOT NULL,
+            issue_id INTEGER NOT NULL,
+            page INTEGER NOT NULL,
+            updated_at TEXT NOT NULL
+          );
+          CREATE UNIQUE INDEX progress_user_issue on progress(user_id, issue_id);
+          ",
+        false,
+    ),
+    (
+        "
+          CREATE TABLE setting (
+            name TEXT PRIMARY KEY,
+            value TEXT NOT NULL
+          );
+          ",
+        false,
+    ),
+    (
+        "
+          CREATE TABLE series (
+            name TEXT,
+            volume INTEGER,
+            publisher TEXT,
+            start_year INTEGER,
+            description TEXT,
+            issue_count INTEGER NOT NULL DEFAULT 0,
+            cover_issue_id INTEGER,
+            updated_at TEXT
+          );
+          CREATE UNIQUE INDEX series_key on series(ifnull(name, ''), ifnull(volume, -1), ifnull(publisher, ''));
+          ALTER TABLE issue ADD COLUMN series_id INTEGER;
+          CREATE INDEX issue_series on issue(series_id);
+          ",
+        false,
+    ),
+    (
+        "
+          CREATE TABLE publisher (
+            name TEXT
+          );
+          CREATE UNIQUE INDEX publisher_name on publisher(ifnull(name, ''));
+          ",
+        false,
+    ),
+    (
+        // The file times are the best guess we have for issues already stored
+        "
+          ALTER TABLE issue ADD COLUMN added_at TEXT;
+          UPDATE issue SET added_at = modified_at;
+          CREATE INDEX issue_added_at on issue(added_at);
+          ",
+        false,
+    ),
+    (
+        "
+          CREATE TABLE reading_list (
+            name TEXT NOT NULL,
+            user_id INTEGER NOT NULL,
+            updated_at TEXT NOT NULL
+          );
+          CREATE UNIQUE INDEX reading_list_name on reading_list(name);
+          CREATE TABLE reading_list_issue (
+            list_id INTEGER NOT NULL,
+            position INTEGER NOT NULL,
+            issue_id INTEGER NOT NULL
+          );
+          CREATE UNIQUE INDEX reading_list_issue_position on reading_list_issue(list_id, position);
+          ",
+        false,
+    ),
+    (
+        // Everyone gets the built in shelves, new users get them in create_user
+        "
+          CREATE TABLE shelf (
+            user_id INTEGER NOT NULL,
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+          );
+          CREATE UNIQUE INDEX shelf_user_name on shelf(user_id, name);
+          CREATE TABLE shelf_issue (
+            shelf_id INTEGER NOT NULL,
+            issue_id INTEGER NOT NULL
+          );
+          CREATE UNIQUE INDEX shelf_issue_shelf on shelf_issue(shelf_id, issue_id);
+          CREATE TABLE shelf_series (
+            shelf_id INTEGER NOT NULL,
+            series_id INTEGER NOT NULL
+          );
+          CREATE UNIQUE INDEX shelf_series_shelf on shelf_series(shelf_id, series_id);
+          INSERT INTO shelf(user_id, kind, name, updated_at)
+            SELECT rowid, 'favorites', 'Favorites', strftime('%Y-%m-%dT%H:%M:%SZ', 'now') FROM user;
+          INSERT INTO shelf(user_id, kind, name, updated_at)
+            SELECT rowid, 'want_to_read', 'Want to read', strftime('%Y-%m-%dT%H:%M:%SZ', 'now') FROM user;
+          ",
+        false,
+    ),
+    (
+        // Names compare ignoring case, whichever way it was first written is kept
+        "
+          CREATE TABLE category (
+            kind TEXT NOT NULL,
+            name TEXT NOT NULL COLLATE NOCASE
+          );
+          CREATE UNIQUE INDEX category_kind_name on category(kind, name);
+          CREATE TABLE issue_category (
+            issue_id INTEGER NOT NULL,
+            category_id INTEGER NOT NULL
+          );
+          CREATE UNIQUE INDEX issue_category_issue on issue_category(issue_id, category_id);
+          CREATE INDEX issue_category_category on issue_category(category_id);
+          CREATE TABLE collection (
+            name TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+          );
+          CREATE UNIQUE INDEX collection_name on collection(name);
+          CREATE TABLE collection_series (
+            collection_id INTEGER NOT NULL,
+            series_id INTEGER NOT NULL
+          );
+          CREATE UNIQUE INDEX collection_series_collection on collection_series(collection_id, series_id);
+          ",
+        true,
+    ),
+    (
+        // Characters, teams and locations are categories too, they only need a reparse
+        "",
+        true,
+    ),
+    (
+        "
+          ALTER TABLE issue ADD COLUMN direction TEXT;
+          ALTER TABLE series ADD COLUMN direction TEXT;
+          ",
+        true,
+    ),
+    (
+        "
+          ALTER TABLE user ADD COLUMN generation INTEGER NOT NULL DEFAULT 0;
+          ",
+        false,
+    ),
+    (
+        // A library's direction is only there when config.toml sets one, which
+        // sync_libraries puts back at startup
+        "
+          CREATE TABLE library_new (
+            name TEXT PRIMARY KEY,
+            path TEXT NOT NULL,
+            direction TEXT
+          );
+          INSERT INTO library_new(rowid, name, path) SELECT rowid, name, path FROM library;
+          DROP TABLE library;
+          ALTER TABLE library_new RENAME TO library;
+          ",
+        false,
+    ),
+];
+
+/// returns whether the stored ComicInfo.xml needs another look
+fn migrate(conn: &mut Connection) -> Result<bool, Error> {
+    let version: i32 = conn.query_row("PRAGMA user_version", &[], |row| row.get(0))?;
+    let mut reparse = false;
+    for (i, &(sql, fills_columns)) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("Migrating database to version {}", i + 1);
+        let tx = conn.transaction()?;
+        tx.execute_batch(&format!("{} PRAGMA user_version = {};", sql, i + 1))?;
+        tx.commit()?;
+        reparse |= fills_columns;
+    }
+    Ok(reparse)
+}
+
+fn make_salt() -> Vec<u8> {
+    use rand::os::OsRng;
+    use rand::RngCore;
+    let mut osrng = OsRng::new().unwrap(); // supposed to not really fail
+    let mut salt = vec![0; 32];
+    osrng.fill_bytes(&mut salt[..]);
+    salt
+}
+
+// Tokens are random and long, so unlike passwords they don't need a slow hash
+// or a salt to hold up against guessing.  The cheapest argon2 we can ask for will do.
+fn hash_token(token: &str) -> Vec<u8> {
+    use argon2rs::Argon2;
+    let a2 = Argon2::new(1, 1, 8, ::argon2rs::Variant::Argon2i).unwrap();
+    let mut hash = [0; 32];
+    a2.hash(&mut hash, token.as_bytes(), b"rust_opds api token", &[], &[]);
+    hash.to_vec()
+}
+
+fn hash_password(password: &str, salt: &[u8]) -> Vec<u8> {
+    use argon2rs::Argon2;
+    let a2 = Argon2::default(::argon2rs::Variant::Argon2i);
+    let mut ciphertext = [0; 32];
+    a2.hash(&mut ciphertext, password.as_bytes(), salt, &[], &[]);
+    ciphertext.to_vec()
+}
+
+const ISSUE_COLUMNS: &str = "select i.rowid, i.filepath, i.modified_at, i.size, i.comicvine_id, i.comicvine_url, i.series, i.issue_number, i.volume, i.title, i.summary, i.released_at, i.writer, i.penciller, i.inker, i.colorist, i.cover_artist, i.publisher, i.page_count, i.age_rating, i.library_id, i.added_at, (select group_concat(c.kind || ':' || c.name) from issue_category ic join category c on c.rowid = ic.category_id where ic.issue_id = i.rowid), coalesce((select l.direction from library l where l.rowid = i.library_id), i.direction, (select s.direction from series s where s.rowid = i.series_id))";
+
+/// How recently the previous issue has to have been read for the next to be on deck
+const ON_DECK_DAYS: i64 = 30;
+
+fn row_to_series(row: &Row) -> Series {
+    Series {
+        id: row.get(0),
+        name: row.get(1),
+        volume: row.get(2),
+        publisher: row.get(3),
+        start_year: row.get(4),
+        description: row.get(5),
+        cover_issue_id: row.get(6),
+        issue_count: row.get(7),
+        unread_count: row.get(8),
+        updated_at: row.get(9),
+        direction: row.get::<_, Option<String>>(10).and_then(|d| d.parse().ok()),
+    }
+}
+
+fn row_to_entry(row: &Row) -> ComicInfo {
+    let mut info = ComicInfo {
+        comic_info: None,
+        id: row.get(0),
+        filepath: row.get(1),
+        modified_at: row.get(2),
         size: row.get(3),
         comicvine_id: row.get(4),
         comicvine_url: row.get(5),
@@ -490,5 +3127,769 @@ fn row_to_entry(row: &Row) -> ComicInfo {
         cover_artist: row.get(16),
         publisher: row.get(17),
         page_count: row.get(18),
+        age_rating: row.get(19),
+        library_id: row.get(20),
+        added_at: row.get(21),
+        genres: Vec::new(),
+        tags: Vec::new(),
+        characters: Vec::new(),
+        teams: Vec::new(),
+        locations: Vec::new(),
+        direction: row.get::<_, Option<String>>(23).and_then(|d| d.parse().ok()),
+    };
+    // kind:name, names can't have commas in them since split_list splits on them
+    let categories: Option<String> = row.get(22);
+    for category in categories.iter().flat_map(|c| c.split(',')) {
+        let mut parts = category.splitn(2, ':');
+        let names = match parts.next().unwrap_or("").parse() {
+            Ok(CategoryKind::Genre) => &mut info.genres,
+            Ok(CategoryKind::Tag) => &mut info.tags,
+            Ok(CategoryKind::Character) => &mut info.characters,
+            Ok(CategoryKind::Team) => &mut info.teams,
+            Ok(CategoryKind::Location) => &mut info.locations,
+            Err(_) => continue,
+        };
+        names.extend(parts.next().map(|name| name.to_owned()));
+    }
+    // group_concat doesn't promise any order
+    for names in &mut [
+        &mut info.genres,
+        &mut info.tags,
+        &mut info.characters,
+        &mut info.teams,
+        &mut info.locations,
+    ] {
+        names.sort();
+    }
+    info
+}
+
+/// Also used by the tests of the feeds and web handlers
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+
+    pub fn test_db() -> DB {
+        // Every connection to :memory: gets its own database, so keep to one
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(SqliteConnectionManager::memory())
+            .unwrap();
+        let db = DB::with_pool(pool).unwrap();
+        db.store_library(&LibraryConfig {
+            name: "Comics".to_owned(),
+            path: path(&["comics"]).into(),
+            scan_minutes: None,
+            direction: None,
+        }).unwrap();
+        db
+    }
+
+    pub fn path(parts: &[&str]) -> String {
+        let mut path = String::new();
+        for part in parts {
+            path.push(MAIN_SEPARATOR);
+            path.push_str(part);
+        }
+        path
+    }
+
+    pub fn user(id: i64) -> User {
+        User {
+            id,
+            username: format!("user{}", id),
+            role: Role::User,
+            read_only: false,
+            restrictions: Restrictions::default(),
+            generation: 0,
+        }
+    }
+
+    pub fn comic(
+        file: &[&str],
+        series: &str,
+        number: i32,
+        publisher: Option<&str>,
+        released_at: &str,
+    ) -> ComicInfo {
+        let mut parts = vec!["comics"];
+        parts.extend(file);
+        ComicInfo {
+            id: None,
+            library_id: Some(1),
+            comic_info: Some(format!("<ComicInfo><Series>{}</Series></ComicInfo>", series)),
+            filepath: path(&parts),
+            size: 1000,
+            modified_at: Local::now(),
+            added_at: None,
+            comicvine_id: None,
+            comicvine_url: None,
+            series: Some(series.to_owned()),
+            issue_number: Some(number),
+            volume: Some(1),
+            title: Some(format!("{} #{}", series, number)),
+            summary: None,
+            released_at: Some(released_at.parse().unwrap()),
+            writer: None,
+            penciller: None,
+            inker: None,
+            colorist: None,
+            cover_artist: None,
+            publisher: publisher.map(|p| p.to_owned()),
+            page_count: Some(20),
+            age_rating: None,
+            genres: Vec::new(),
+            tags: Vec::new(),
+            characters: Vec::new(),
+            teams: Vec::new(),
+            locations: Vec::new(),
+            direction: None,
+        }
+    }
+
+    pub fn store(
+        db: &DB,
+        file: &[&str],
+        series: &str,
+        number: i32,
+        publisher: Option<&str>,
+        released_at: &str,
+    ) -> i64 {
+        let info = comic(file, series, number, publisher, released_at);
+        let pages: Vec<String> = (1..21).map(|p| format!("{:02}.jpg", p)).collect();
+        db.store_comic(&info, &pages).unwrap()
+    }
+
+    /// Three issues of Saga, one of Ms. Marvel and an untagged one in a subfolder
+    pub fn test_library() -> DB {
+        let db = test_db();
+        store(&db, &["Image", "Saga 2.cbz"], "Saga", 2, Some("Image"), "2012-04-01");
+        store(&db, &["Image", "Saga 1.cbz"], "Saga", 1, Some("Image"), "2012-03-01");
+        store(&db, &["Image", "Saga 3.cbr"], "Saga", 3, Some("Image"), "2012-05-01");
+        store(&db, &["Marvel", "Ms. Marvel 1.cbz"], "Ms. Marvel", 1, Some("Marvel"), "2014-02-01");
+        store(&db, &["Misc", "Old", "Zine.cbz"], "Zine", 1, None, "1999-01-01");
+        db.refresh_series().unwrap();
+        db
+    }
+
+    pub fn titles(entries: &[ComicInfo]) -> Vec<String> {
+        entries.iter().map(|e| e.title.clone().unwrap()).collect()
+    }
+
+    #[test]
+    fn sorts_and_filters_with_facets() {
+        let db = test_library();
+        let user = user(1);
+        let mut facets = Facets {
+            sort: Some(Sort::Released),
+            ..Facets::default()
+        };
+        assert_eq!(
+            titles(&db.get_all(&user, &facets).unwrap()),
+            vec!["Ms. Marvel #1", "Saga #3", "Saga #2", "Saga #1", "Zine #1"]
+        );
+
+        facets.sort = Some(Sort::Added);
+        assert_eq!(db.get_all(&user, &facets).unwrap()[0].title, Some("Zine #1".to_owned()));
+
+        facets.publisher = Some("Image".to_owned());
+        facets.format = Some(Format::Cbz);
+        assert_eq!(titles(&db.get_all(&user, &facets).unwrap()), vec!["Saga #1", "Saga #2"]);
+
+        let facets = Facets {
+            year: Some(2014),
+            ..Facets::default()
+        };
+        assert_eq!(titles(&db.get_recent(&user, &facets).unwrap()), vec!["Ms. Marvel #1"]);
+    }
+
+    #[test]
+    fn added_is_when_first_stored() {
+        let db = test_library();
+        let user = user(1);
+        let first = db.get_added(&user, &Facets::default()).unwrap();
+        store(&db, &["Image", "Saga 1.cbz"], "Saga", 1, Some("Image"), "2012-03-01");
+        let id = store(&db, &["Back", "Watchmen 1.cbz"], "Watchmen", 1, None, "1986-09-01");
+
+        let added = db.get_added(&user, &Facets::default()).unwrap();
+        assert_eq!(added[0].id, Some(id));
+        let saga_1 = |entries: &[ComicInfo]| {
+            entries.iter().find(|e| e.title == Some("Saga #1".to_owned())).unwrap().added_at
+        };
+        assert_eq!(saga_1(&first), saga_1(&added));
+        let recent = db.get_recent(&user, &Facets::default()).unwrap();
+        assert_eq!(recent.last().unwrap().id, Some(id));
+
+        // Issues added at the same time, like by an import, still come out the same way
+        let now = Utc::now();
+        db.pool.get().unwrap().execute("update issue set added_at = ?", &[&now]).unwrap();
+        let ids: Vec<i64> = db
+            .get_added(&user, &Facets::default())
+            .unwrap()
+            .iter()
+            .map(|e| e.id.unwrap())
+            .collect();
+        let mut newest_first = ids.clone();
+        newest_first.sort_by(|a, b| b.cmp(a));
+        assert_eq!(ids, newest_first);
+    }
+
+    #[test]
+    fn read_status_is_per_user() {
+        let db = test_library();
+        let (reader, other) = (user(1), user(2));
+        let saga_1 = db.get_all(&reader, &Facets::default())
+            .unwrap()
+            .into_iter()
+            .find(|e| e.title == Some("Saga #1".to_owned()))
+            .unwrap();
+        db.mark_read(saga_1.id.unwrap(), reader.id).unwrap();
+
+        let read = Facets {
+            read: Some(true),
+            ..Facets::default()
+        };
+        assert_eq!(titles(&db.get_all(&reader, &read).unwrap()), vec!["Saga #1"]);
+        assert!(db.get_all(&other, &read).unwrap().is_empty());
+        assert_eq!(db.get_unread(&reader, &Facets::default()).unwrap().len(), 4);
+        assert_eq!(db.get_unread(&other, &Facets::default()).unwrap().len(), 5);
+
+        let saga = db.find_series(&reader, &saga_1_key()).unwrap();
+        assert_eq!((saga.issue_count, saga.unread_count), (3, 2));
+        assert_eq!(
+            titles(&db.get_unread_for_series(&reader, &saga.key(), &Facets::default()).unwrap()),
+            vec!["Saga #2", "Saga #3"]
+        );
+    }
+
+    fn saga_1_key() -> SeriesKey {
+        SeriesKey {
+            name: Some("Saga".to_owned()),
+            volume: Some(1),
+            publisher: Some("Image".to_owned()),
+        }
+    }
+
+    #[test]
+    fn continue_reading_and_on_deck() {
+        let db = test_library();
+        let (reader, other) = (user(1), user(2));
+        let ids: HashMap<String, i64> = db.get_all(&reader, &Facets::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.title.unwrap(), e.id.unwrap()))
+            .collect();
+        assert!(db.get_on_deck(&reader, &Facets::default()).unwrap().is_empty());
+
+        db.set_progress(reader.id, ids["Zine #1"], 4).unwrap();
+        db.set_progress(reader.id, ids["Ms. Marvel #1"], 19).unwrap();
+        db.set_progress(reader.id, ids["Saga #1"], 19).unwrap();
+        db.set_progress(reader.id, ids["Saga #2"], 10).unwrap();
+        let continuing = db.get_continue_reading(&reader, &Facets::default()).unwrap();
+        assert_eq!(titles(&continuing), vec!["Saga #2", "Zine #1"]);
+        assert!(db.get_continue_reading(&other, &Facets::default()).unwrap().is_empty());
+
+        // Saga #2 is already being read, and Ms. Marvel has nothing after it
+        assert!(db.get_on_deck(&reader, &Facets::default()).unwrap().is_empty());
+        db.set_progress(reader.id, ids["Saga #2"], 19).unwrap();
+        let on_deck = db.get_on_deck(&reader, &Facets::default()).unwrap();
+        assert_eq!(titles(&on_deck), vec!["Saga #3"]);
+        assert!(db.get_on_deck(&other, &Facets::default()).unwrap().is_empty());
+
+        // Saga #3 is in a library the restricted reader can't see, so #4 is next for them
+        db.store_library(&LibraryConfig {
+            name: "Hidden".to_owned(),
+            path: path(&["hidden"]).into(),
+            scan_minutes: None,
+            direction: None,
+        }).unwrap();
+        db.pool.get().unwrap().execute(
+            "update issue set library_id = (select rowid from library where name = 'Hidden') where rowid = ?",
+            &[&ids["Saga #3"]],
+        ).unwrap();
+        store(&db, &["Image", "Saga 4.cbz"], "Saga", 4, Some("Image"), "2012-06-01");
+        db.refresh_series().unwrap();
+        let mut restricted = user(1);
+        restricted.restrictions.libraries = vec!["Comics".to_owned()];
+        let on_deck = db.get_on_deck(&restricted, &Facets::default()).unwrap();
+        assert_eq!(titles(&on_deck), vec!["Saga #4"]);
+        let on_deck = db.get_on_deck(&reader, &Facets::default()).unwrap();
+        assert_eq!(titles(&on_deck), vec!["Saga #3"]);
+    }
+
+    #[test]
+    fn progress_stays_inside_the_issue() {
+        let db = test_library();
+        let reader = user(1);
+        let ids: HashMap<String, i64> = db.get_all(&reader, &Facets::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.title.unwrap(), e.id.unwrap()))
+            .collect();
+        let saga = ids["Saga #1"];
+        for &page in &[-1, 20, i32::max_value()] {
+            assert!(db.set_progress(reader.id, saga, page).is_err(), "page {}", page);
+        }
+        assert_eq!(db.get_progress(reader.id, saga).unwrap(), None);
+
+        // 20 pages, so 16 is still reading and 17 is within the last three
+        db.set_progress(reader.id, saga, 16).unwrap();
+        assert_eq!(db.get_progress(reader.id, saga).unwrap(), Some(16));
+        let continuing = db.get_continue_reading(&reader, &Facets::default()).unwrap();
+        assert_eq!(titles(&continuing), vec!["Saga #1"]);
+        db.set_progress(reader.id, saga, 17).unwrap();
+        assert!(db.get_continue_reading(&reader, &Facets::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn reading_lists() {
+        let db = test_library();
+        let owner = user(db.create_user("casret", "secret").unwrap());
+        let cbl = ::cbl::parse(
+            r#"<?xml version="1.0"?>
+            <ReadingList>
+              <Name>Space Opera</Name>
+              <Books>
+                <Book Series="saga" Number="2" Volume="1" Year="2012" />
+                <Book Series="Ms. Marvel" Number="1" Volume="2014" Year="2014" />
+                <Book Series="Saga" Number="1" Volume="2012" Year="2012" />
+                <Book Series="Saga" Number="4" Volume="2012" Year="2012" />
+                <Book Series="Saga" Number="3" Volume="2" Year="2012" />
+              </Books>
+            </ReadingList>"#,
+        ).unwrap();
+        let (list_id, unmatched) = db.import_list(owner.id, &cbl).unwrap();
+        let unmatched: Vec<&str> = unmatched.iter().map(|b| b.number.as_str()).collect();
+        assert_eq!(unmatched, vec!["4", "3"]);
+        assert_eq!(
+            titles(&db.get_for_list(&owner, list_id, &Facets::default()).unwrap()),
+            vec!["Saga #2", "Ms. Marvel #1", "Saga #1"]
+        );
+
+        let entries = db.get_for_list(&owner, list_id, &Facets::default()).unwrap();
+        db.mark_read(entries[0].id.unwrap(), owner.id).unwrap();
+        let unread = Facets {
+            read: Some(false),
+            ..Facets::default()
+        };
+        assert_eq!(
+            titles(&db.get_for_list(&owner, list_id, &unread).unwrap()),
+            vec!["Ms. Marvel #1", "Saga #1"]
+        );
+        let list = db.get_list(&owner, list_id).unwrap();
+        assert_eq!((list.issue_count, list.unread_count), (3, 2));
+        assert_eq!(list.owner, "casret");
+
+        // Importing it again replaces what's on it
+        db.update_list(list_id, None, Some(&[entries[2].id.unwrap()])).unwrap();
+        assert_eq!(db.import_list(owner.id, &cbl).unwrap().0, list_id);
+        assert_eq!(db.get_list(&owner, list_id).unwrap().issue_count, 3);
+
+        // The schema keeps names unique, even past find_list
+        let taken = db.create_list(owner.id, "Space Opera", &[]).unwrap_err();
+        assert!(taken.downcast_ref::<Invalid>().is_some());
+        let other_id = db.create_list(owner.id, "Other", &[]).unwrap();
+        let taken = db.update_list(other_id, Some("Space Opera"), Some(&[])).unwrap_err();
+        assert!(taken.downcast_ref::<Invalid>().is_some());
+        assert_eq!(db.get_list(&owner, other_id).unwrap().name, "Other");
+        db.delete_list(other_id).unwrap();
+
+        let mut kid = user(2);
+        kid.restrictions.publishers = vec!["Marvel".to_owned()];
+        assert_eq!(db.get_lists(&kid).unwrap()[0].issue_count, 1);
+        db.delete_user("casret").unwrap();
+        assert!(db.get_lists(&kid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shelves() {
+        let db = test_library();
+        let owner = user(db.create_user("casret", "secret").unwrap());
+        let shelves = db.get_shelves(&owner).unwrap();
+        let kinds: Vec<ShelfKind> = shelves.iter().map(|s| s.kind).collect();
+        assert_eq!(kinds, vec![ShelfKind::Favorites, ShelfKind::WantToRead]);
+        let (favorites, want) = (shelves[0].id, shelves[1].id);
+
+        let saga = db.find_series(&owner, &saga_1_key()).unwrap();
+        db.shelve_series(favorites, saga.id, true).unwrap();
+        let marvel = db.query_issues(&IssueQuery::new(&owner).search("marvel")).unwrap();
+        let marvel_id = marvel[0].id.unwrap();
+        db.shelve_issue(favorites, marvel_id, true).unwrap();
+        db.shelve_issue(want, marvel_id, true).unwrap();
+        db.shelve_issue(want, marvel_id, true).unwrap();
+        db.mark_read(marvel_id, owner.id).unwrap();
+
+        let shelf = db.get_shelf(&owner, favorites).unwrap();
+        assert_eq!((shelf.issue_count, shelf.unread_count), (4, 3));
+        assert_eq!(db.get_series_for_shelf(&owner, favorites).unwrap()[0].id, saga.id);
+        assert_eq!(titles(&db.get_for_shelf(&owner, favorites).unwrap()), vec!["Ms. Marvel #1"]);
+        db.shelve_issue(want, marvel_id, false).unwrap();
+        assert_eq!(db.get_shelf(&owner, want).unwrap().issue_count, 0);
+
+        // A series that's gone comes off the shelf rather than leave its rowid behind
+        for &(file, number) in &[("Saga 1.cbz", 1), ("Saga 2.cbz", 2), ("Saga 3.cbr", 3)] {
+            store(&db, &["Image", file], "Saga Deluxe", number, Some("Image"), "2012-03-01");
+        }
+        db.refresh_series().unwrap();
+        let shelved: i64 = db
+            .pool
+            .get()
+            .unwrap()
+            .query_row("select count(*) from shelf_series", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(shelved, 0);
+
+        // Shelves are private
+        let other = user(db.create_user("other", "secret").unwrap());
+        assert!(db.get_shelf(&other, favorites).is_err());
+        assert_eq!(db.get_shelves(&other).unwrap()[0].issue_count, 0);
+
+        let custom = db.create_shelf(owner.id, "Capes").unwrap();
+        assert_eq!(db.find_shelf(&owner, "Capes").unwrap().unwrap().kind, ShelfKind::Custom);
+        assert_eq!(db.get_shelves(&owner).unwrap()[2].id, custom);
+        db.delete_shelf(custom).unwrap();
+        assert_eq!(db.get_shelves(&owner).unwrap().len(), 2);
+        db.delete_user("casret").unwrap();
+        assert!(db.get_shelves(&owner).unwrap().is_empty());
+    }
+
+    #[test]
+    fn genres_tags_and_collections() {
+        let db = test_library();
+        let user = user(1);
+        let xml = "<ComicInfo><Series>Paper Girls</Series><Number>1</Number>\
+                   <Genre>Science Fiction,  Coming of Age</Genre>\
+                   <Tags>time travel, Time  Travel,,newspapers</Tags></ComicInfo>";
+        let file = path(&["comics", "Paper Girls 1.cbz"]);
+        let mut info = ComicInfo::new(Path::new(&file), Some(xml.to_owned())).unwrap();
+        info.library_id = Some(1);
+        assert_eq!(info.genres, vec!["Science Fiction", "Coming of Age"]);
+        assert_eq!(info.tags, vec!["time travel", "newspapers"]);
+        db.store_comic(&info, &[]).unwrap();
+        let mut saga = comic(&["Image", "Saga 1.cbz"], "Saga", 1, Some("Image"), "2012-03-01");
+        saga.genres = vec!["science fiction".to_owned()];
+        db.store_comic(&saga, &[]).unwrap();
+        db.refresh_series().unwrap();
+
+        let genres = db.get_categories(&user, CategoryKind::Genre).unwrap();
+        let names: Vec<&str> = genres.iter().map(|g| g.name.as_str()).collect();
+        assert_eq!(names, vec!["Coming of Age", "Science Fiction"]);
+        assert_eq!(genres[1].issue_count, 2);
+        let entries = db.get_for_category(&user, genres[1].id, &Facets::default()).unwrap();
+        assert_eq!(titles(&entries[1..]), vec!["Saga #1"]);
+        assert_eq!(entries[1].genres, vec!["Science Fiction"]);
+        assert!(db.get_category(&user, CategoryKind::Tag, genres[1].id).is_err());
+
+        // Genres nothing has anymore go away
+        saga.genres.clear();
+        db.store_comic(&saga, &[]).unwrap();
+        db.refresh_series().unwrap();
+        assert_eq!(db.get_categories(&user, CategoryKind::Genre).unwrap()[1].issue_count, 1);
+        assert_eq!(db.get_categories(&user, CategoryKind::Tag).unwrap().len(), 2);
+
+        let collection = db.create_collection("Space").unwrap();
+        let saga = db.find_series(&user, &saga_1_key()).unwrap();
+        db.set_collection_series(collection, &[saga.id, saga.id]).unwrap();
+        let series = db.get_series_for_collection(&user, collection).unwrap();
+        assert_eq!(series.len(), 1);
+        assert_eq!(series[0].id, saga.id);
+        let mut kid = user;
+        kid.restrictions.publishers = vec!["Marvel".to_owned()];
+        assert!(db.get_series_for_collection(&kid, collection).unwrap().is_empty());
+        for &(file, number) in &[("Saga 1.cbz", 1), ("Saga 2.cbz", 2), ("Saga 3.cbr", 3)] {
+            store(&db, &["Image", file], "Saga Deluxe", number, Some("Image"), "2012-03-01");
+        }
+        db.refresh_series().unwrap();
+        let collected: i64 = db
+            .pool
+            .get()
+            .unwrap()
+            .query_row("select count(*) from collection_series", &[], |row| row.get(0))
+            .unwrap();
+        assert_eq!(collected, 0);
+        db.delete_collection(collection).unwrap();
+        assert!(db.find_collection("Space").unwrap().is_none());
+    }
+
+    #[test]
+    fn characters_across_series() {
+        let db = test_library();
+        let user = user(1);
+        let mut saga = comic(&["Image", "Saga 1.cbz"], "Saga", 1, Some("Image"), "2012-03-01");
+        saga.characters = ::split_list("Alana, Marko, Hazel");
+        saga.locations = vec!["Wreath".to_owned()];
+        db.store_comic(&saga, &[]).unwrap();
+        let mut marvel =
+            comic(&["Marvel", "Ms. Marvel 1.cbz"], "Ms. Marvel", 1, Some("Marvel"), "2014-02-01");
+        marvel.characters = vec!["Kamala Khan".to_owned(), "hazel".to_owned()];
+        marvel.teams = vec!["Avengers".to_owned()];
+        db.store_comic(&marvel, &[]).unwrap();
+
+        let characters = db.get_categories(&user, CategoryKind::Character).unwrap();
+        let names: Vec<&str> = characters.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["Alana", "Hazel", "Kamala Khan", "Marko"]);
+        let entries = db.get_for_category(&user, characters[1].id, &Facets::default()).unwrap();
+        assert_eq!(titles(&entries), vec!["Ms. Marvel #1", "Saga #1"]);
+        // Hazel is written the way Saga had her first
+        assert_eq!(entries[0].characters, vec!["Hazel", "Kamala Khan"]);
+        assert_eq!(entries[0].teams, vec!["Avengers"]);
+        assert_eq!(entries[1].characters, vec!["Alana", "Hazel", "Marko"]);
+        assert_eq!(entries[1].locations, vec!["Wreath"]);
+        assert!(entries[1].teams.is_empty());
+        assert_eq!(db.get_categories(&user, CategoryKind::Team).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn years() {
+        let db = test_library();
+        let user = user(1);
+        store(&db, &["Image", "Saga 4.cbz"], "Saga", 4, Some("Image"), "2012-12-31");
+        store(&db, &["Image", "Saga Sampler.cbz"], "Saga", 0, Some("Image"), "2013-01-01");
+        let years: Vec<(i32, i64)> = db.get_years(&user)
+            .unwrap()
+            .iter()
+            .map(|y| (y.year, y.issue_count))
+            .collect();
+        assert_eq!(years, vec![(1999, 1), (2012, 4), (2013, 1), (2014, 1)]);
+        assert_eq!(
+            titles(&db.get_for_year(&user, 2012, &Facets::default()).unwrap()),
+            vec!["Saga #1", "Saga #2", "Saga #3", "Saga #4"]
+        );
+        let mut kid = user;
+        kid.restrictions.publishers = vec!["Marvel".to_owned()];
+        assert_eq!(db.get_years(&kid).unwrap().len(), 1);
+        assert!(db.get_for_year(&kid, 2012, &Facets::default()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn manga_direction() {
+        let db = test_library();
+        let user = user(1);
+        for (number, manga) in &[(1, "<Manga>YesAndRightToLeft</Manga>"), (2, "")] {
+            let xml = format!(
+                "<ComicInfo><Series>Berserk</Series><Number>{}</Number>{}</ComicInfo>",
+                number, manga
+            );
+            let file = path(&["comics", &format!("Berserk {}.cbz", number)]);
+            let mut info = ComicInfo::new(Path::new(&file), Some(xml)).unwrap();
+            info.library_id = Some(1);
+            db.store_comic(&info, &[]).unwrap();
+        }
+        db.refresh_series().unwrap();
+
+        // The untagged issue follows its series
+        let key = SeriesKey {
+            name: Some("Berserk".to_owned()),
+            volume: None,
+            publisher: None,
+        };
+        assert_eq!(db.find_series(&user, &key).unwrap().direction, Some(Direction::Rtl));
+        let berserk = db.get_for_series(&user, &key, &Facets::default()).unwrap();
+        let directions: Vec<_> = berserk.iter().map(|e| e.direction).collect();
+        assert_eq!(directions, vec![Some(Direction::Rtl); 2]);
+        let saga = db.find_series(&user, &saga_1_key()).unwrap();
+        assert_eq!(saga.direction, None);
+        let saga = db.get_for_series(&user, &saga_1_key(), &Facets::default()).unwrap();
+        assert_eq!(saga[0].direction, None);
+
+        // Unless its tagged issues don't agree
+        let mut no = comic(&["Berserk 3.cbz"], "Berserk", 3, None, "1991-01-01");
+        no.volume = None;
+        no.direction = Some(Direction::Ltr);
+        db.store_comic(&no, &[]).unwrap();
+        db.refresh_series().unwrap();
+        assert_eq!(db.find_series(&user, &key).unwrap().direction, None);
+        assert_eq!(db.get(&user, berserk[1].id.unwrap()).unwrap().direction, None);
+
+        // and the library's direction wins over everything
+        db.store_library(&LibraryConfig {
+            name: "Comics".to_owned(),
+            path: path(&["comics"]).into(),
+            scan_minutes: None,
+            direction: Some(Direction::Rtl),
+        }).unwrap();
+        let berserk = db.get_for_series(&user, &key, &Facets::default()).unwrap();
+        let directions: Vec<_> = berserk.iter().map(|e| e.direction).collect();
+        assert_eq!(directions, vec![Some(Direction::Rtl); 3]);
+        assert_eq!(db.get(&user, saga[0].id.unwrap()).unwrap().direction, Some(Direction::Rtl));
+    }
+
+    #[test]
+    fn series_and_publishers() {
+        let db = test_library();
+        let user = user(1);
+        let series = db.get_series(&user).unwrap();
+        let names: Vec<&str> = series.iter().map(|s| s.get_name()).collect();
+        assert_eq!(names, vec!["Ms. Marvel", "Saga", "Zine"]);
+
+        let saga = db.get_series_by_id(&user, series[1].id).unwrap();
+        assert_eq!(saga.start_year, Some(2012));
+        assert_eq!(
+            titles(&db.get_for_series(&user, &saga.key(), &Facets::default()).unwrap()),
+            vec!["Saga #1", "Saga #2", "Saga #3"]
+        );
+
+        let publishers = db.get_publishers(&user).unwrap();
+        let names: Vec<&str> = publishers.iter().map(|p| p.get_name()).collect();
+        assert_eq!(names, vec!["None", "Image", "Marvel"]);
+        let none = db.find_publisher(&user, &None).unwrap();
+        assert_eq!(none.id, publishers[0].id);
+        let zine = db.get_series_for_publisher(&user, &none.name).unwrap();
+        assert_eq!(zine.len(), 1);
+        assert_eq!(zine[0].get_name(), "Zine");
+
+        let found = db.find_series_by_name(&user, None, Some("Saga".to_owned())).unwrap();
+        assert_eq!(found.id, saga.id);
+        let image = Some(Some("Image".to_owned()));
+        assert!(db.find_series_by_name(&user, image, Some("Zine".to_owned())).is_err());
+        let zine = db.find_series_by_name(&user, Some(None), Some("Zine".to_owned())).unwrap();
+        assert_eq!(zine.get_name(), "Zine");
+    }
+
+    #[test]
+    fn restrictions_hide_issues() {
+        let db = test_library();
+        let mut kid = user(1);
+        kid.restrictions.publishers = vec!["Marvel".to_owned()];
+        let entries = db.get_all(&kid, &Facets::default()).unwrap();
+        assert_eq!(titles(&entries), vec!["Ms. Marvel #1"]);
+
+        let everything = db.get_all(&user(2), &Facets::default()).unwrap();
+        let saga = everything.iter().find(|e| e.series == Some("Saga".to_owned())).unwrap();
+        assert!(db.get(&kid, saga.id.unwrap()).is_err());
+        assert!(db.get(&kid, entries[0].id.unwrap()).is_ok());
+        assert!(db.find_series(&kid, &saga_1_key()).is_err());
+        assert_eq!(db.get_publishers(&kid).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn synced_libraries_keep_only_their_issues() {
+        let db = test_library();
+        let user = user(1);
+        let library = |name: &str, folders: &[&str]| LibraryConfig {
+            name: name.to_owned(),
+            path: path(folders).into(),
+            scan_minutes: None,
+            direction: None,
+        };
+        let marvel = db.query_issues(&IssueQuery::new(&user).search("marvel")).unwrap();
+        db.mark_read(marvel[0].id.unwrap(), user.id).unwrap();
+
+        // Renamed, so the issues go with the path
+        db.sync_libraries(&[library("Everything", &["comics"])]).unwrap();
+        db.refresh_series().unwrap();
+        assert_eq!(db.get_all(&user, &Facets::default()).unwrap().len(), 5);
+        assert_eq!(db.get_libraries(&user).unwrap()[0].name, "Everything");
+
+        db.sync_libraries(&[library("Image", &["comics", "Image"])]).unwrap();
+        db.refresh_series().unwrap();
+        let mut left = titles(&db.get_all(&user, &Facets::default()).unwrap());
+        left.sort();
+        assert_eq!(left, vec!["Saga #1", "Saga #2", "Saga #3"]);
+        assert_eq!(db.get_publishers(&user).unwrap().len(), 1);
+        assert!(db.get_read_ids(user.id).unwrap().is_empty());
+    }
+
+    #[test]
+    fn series_covers_follow_restrictions() {
+        let db = test_library();
+        let preview =
+            store(&db, &["Previews", "Saga 0.cbz"], "Saga", 0, Some("Image"), "2012-01-01");
+        db.refresh_series().unwrap();
+        let saga = db.find_series(&user(1), &saga_1_key()).unwrap();
+        assert_eq!(saga.cover_issue_id, Some(preview));
+
+        let mut kid = user(2);
+        kid.restrictions.paths = vec![restriction_path(&path(&["comics", "Image"]))];
+        let saga = db.find_series(&kid, &saga_1_key()).unwrap();
+        assert_eq!(saga.issue_count, 3);
+        assert!(saga.cover_issue_id.is_some());
+        assert_ne!(saga.cover_issue_id, Some(preview));
+    }
+
+    #[test]
+    fn path_restrictions_match_whole_folders() {
+        let db = test_library();
+        store(&db, &["MiscMature", "Later.cbz"], "Later", 1, None, "2001-01-01");
+        db.create_user("kid", "secret").unwrap();
+        db.add_restriction("kid", RestrictionKind::Path, &path(&["comics", "Misc"])).unwrap();
+        let kid = db.get_user_details("kid").unwrap();
+        assert_eq!(titles(&db.get_all(&kid, &Facets::default()).unwrap()), vec!["Zine #1"]);
+        db.remove_restriction("kid", RestrictionKind::Path, &path(&["comics", "Misc"])).unwrap();
+        assert!(db.get_user_details("kid").unwrap().restrictions.paths.is_empty());
+    }
+
+    #[test]
+    fn api_tokens() {
+        let db = test_db();
+        let user_id = db.create_user("casret", "secret").unwrap();
+        let reader = db.create_token(user_id, "reader", TokenScope::Read).unwrap();
+        let sync = db.create_token(user_id, "sync", TokenScope::Progress).unwrap();
+        assert_ne!(reader, sync);
+
+        let user = db.check_token(&reader).unwrap().unwrap();
+        assert_eq!((user.username.as_str(), user.read_only), ("casret", true));
+        assert!(!db.check_token(&sync).unwrap().unwrap().read_only);
+        assert!(db.check_token("not a token").unwrap().is_none());
+        let tokens = db.list_tokens(user_id).unwrap();
+        assert_eq!(tokens.len(), 2);
+        assert!(tokens.iter().all(|t| t.last_used_at.unwrap() >= t.created_at));
+
+        db.revoke_token(user_id, "reader").unwrap();
+        assert!(db.check_token(&reader).unwrap().is_none());
+        assert!(db.revoke_token(user_id, "reader").unwrap_err().downcast::<NotFound>().is_ok());
+        assert!(db.check_token(&sync).unwrap().is_some());
+    }
+
+    #[test]
+    fn user_changes_bump_generation() {
+        let db = test_db();
+        let id = db.create_user("kid", "secret").unwrap();
+        let generation = db.check_user("kid", "secret").unwrap().unwrap().generation;
+        db.add_restriction("kid", RestrictionKind::Publisher, "Marvel").unwrap();
+        db.set_role("kid", Role::Guest).unwrap();
+        db.set_password("kid", "hunter2").unwrap();
+        assert_eq!(db.get_user_generation(id).unwrap(), Some(generation + 3));
+        db.delete_user("kid").unwrap();
+        assert_eq!(db.get_user_generation(id).unwrap(), None);
+    }
+
+    #[test]
+    fn folders() {
+        let db = test_library();
+        let user = user(1);
+        let folders: Vec<String> = db.get_subfolders(&user, 1, "")
+            .unwrap()
+            .into_iter()
+            .map(|f| f.0)
+            .collect();
+        assert_eq!(folders, vec!["Image", "Marvel", "Misc"]);
+        assert_eq!(
+            titles(&db.get_for_folder(&user, 1, "Image").unwrap()),
+            vec!["Saga #1", "Saga #2", "Saga #3"]
+        );
+        assert!(db.get_for_folder(&user, 1, "Misc").unwrap().is_empty());
+        assert_eq!(titles(&db.get_for_folder(&user, 1, "Misc/Old").unwrap()), vec!["Zine #1"]);
+    }
+
+    #[test]
+    fn search_and_paging() {
+        let db = test_library();
+        let user = user(1);
+        let search = |q: &str| titles(&db.query_issues(&IssueQuery::new(&user).search(q)).unwrap());
+        assert_eq!(search("zine"), vec!["Zine #1"]);
+        // Punctuation is searched for, not taken as FTS syntax
+        assert!(search("ms. \"marvel").is_empty());
+        assert_eq!(search("marvel ms."), vec!["Ms. Marvel #1"]);
+        assert!(search("  ").is_empty());
+
+        let query = IssueQuery::new(&user)
+            .publisher(Some("Image".to_owned()))
+            .sort(Sort::Series);
+        assert_eq!(db.count_issues(&query.clone().page(1, 1)).unwrap(), 3);
+        assert_eq!(titles(&db.query_issues(&query.page(1, 1)).unwrap()), vec!["Saga #2"]);
+
+        let query = IssueQuery::new(&user).series_name(Some("Saga".to_owned())).read(false);
+        assert_eq!(db.count_issues(&query).unwrap(), 3);
     }
 }
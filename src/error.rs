@@ -0,0 +1,144 @@
+use failure::Error;
+use hyper::header;
+use hyper::{Body, Response, StatusCode};
+
+/// Raised by the DB and friends when a lookup comes up empty, so the web
+/// layer can tell a missing page apart from a real failure
+#[derive(Debug, Fail)]
+#[fail(display = "No such {}", _0)]
+pub struct NotFound(pub &'static str);
+
+/// Raised by the DB when the client asks for something that can't be, like a
+/// page past the end of an issue
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct Invalid(pub String);
+
+/// Everything that can go wrong handling a request, each mapping to a status code
+#[derive(Debug, Fail)]
+pub enum HttpError {
+    #[fail(display = "{}", _0)]
+    BadRequest(String),
+    #[fail(display = "Please provide username and password.")]
+    Unauthorized,
+    #[fail(display = "{}", _0)]
+    Forbidden(String),
+    #[fail(display = "{}", _0)]
+    NotFound(String),
+    #[fail(display = "{}", _0)]
+    MethodNotAllowed(String),
+    #[fail(display = "{}", _0)]
+    Internal(Error),
+}
+
+/// RFC 7807 problem document, which is what OPDS 2 clients expect on errors
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: &'a str,
+    title: &'a str,
+    status: u16,
+    detail: String,
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HttpError::Unauthorized => StatusCode::UNAUTHORIZED,
+            HttpError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HttpError::NotFound(_) => StatusCode::NOT_FOUND,
+            HttpError::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            HttpError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn into_response(self) -> Response<Body> {
+        let status = self.status();
+        let detail = match self {
+            HttpError::Internal(ref e) => {
+                error!("Error handling request: {}, {}", e, e.backtrace());
+                // Don't leak paths and SQL to the client
+                "Something went wrong, check the server log".to_owned()
+            }
+            ref e => e.to_string(),
+        };
+
+        let problem = Problem {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail,
+        };
+
+        let mut response = Response::builder();
+        response
+            .status(status)
+            .header(header::CONTENT_TYPE, "application/problem+json");
+        if status == StatusCode::UNAUTHORIZED {
+            response.header(header::WWW_AUTHENTICATE, r#"Basic realm="Rust OPDS""#);
+        }
+        response
+            .body(Body::from(::serde_json::to_string(&problem).unwrap_or_default()))
+            .unwrap()
+    }
+}
+
+impl From<Error> for HttpError {
+    fn from(e: Error) -> HttpError {
+        if let Some(NotFound(what)) = e.downcast_ref::<NotFound>() {
+            return HttpError::NotFound(format!("No such {}", what));
+        }
+        if let Some(Invalid(why)) = e.downcast_ref::<Invalid>() {
+            return HttpError::BadRequest(why.clone());
+        }
+        match e.downcast::<::rusqlite::Error>() {
+            Ok(::rusqlite::Error::QueryReturnedNoRows) => HttpError::NotFound("Not found".into()),
+            Ok(e) => HttpError::Internal(e.into()),
+            Err(e) => HttpError::Internal(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{Future, Stream};
+
+    fn body(response: Response<Body>) -> ::serde_json::Value {
+        let body = response.into_body().concat2().wait().unwrap();
+        ::serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn missing_things_are_404() {
+        let e = HttpError::from(Error::from(NotFound("series")));
+        assert_eq!(e.status(), StatusCode::NOT_FOUND);
+        assert_eq!(e.to_string(), "No such series");
+        let e = HttpError::from(Error::from(::rusqlite::Error::QueryReturnedNoRows));
+        assert_eq!(e.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn invalid_requests_are_400() {
+        let e = HttpError::from(Error::from(Invalid("Page 20 is past the end".to_owned())));
+        assert_eq!(e.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(e.to_string(), "Page 20 is past the end");
+    }
+
+    #[test]
+    fn everything_else_is_500_without_the_details() {
+        let e = HttpError::from(::failure::err_msg("no such table: /secret/path"));
+        assert_eq!(e.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let response = e.into_response();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            response.headers()[header::CONTENT_TYPE],
+            "application/problem+json"
+        );
+        let problem = body(response);
+        assert_eq!(problem["status"], 500);
+        assert_eq!(problem["title"], "Internal Server Error");
+        assert!(!problem["detail"].as_str().unwrap().contains("secret"));
+    }
+}
@@ -0,0 +1,374 @@
+use super::admin::AdminReport;
+use super::db::{Role, Series, User};
+use super::router::Route;
+use super::{AuthMode, ComicInfo};
+use chrono::prelude::*;
+
+static STYLE: &str = include_str!("../static/style.css");
+static READER: &str = include_str!("../static/reader.js");
+
+/// The files under /web/static, along with their content type
+pub fn static_file(name: &str) -> Option<(&'static str, &'static str)> {
+    match name {
+        "style.css" => Some(("text/css; charset=utf-8", STYLE)),
+        "reader.js" => Some(("application/javascript; charset=utf-8", READER)),
+        _ => None,
+    }
+}
+
+pub fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Where a route lives for the browser, url_prefix works like it does for feeds
+fn href(url_prefix: &str, route: &Route) -> String {
+    escape(&format!("{}{}", url_prefix, route.url()))
+}
+
+fn layout(url_prefix: &str, title: &str, body: &str) -> String {
+    let nav = [
+        (Route::WebStart, "Rust OPDS"),
+        (Route::WebPublishers, "Publishers"),
+        (Route::WebUnread, "Unread"),
+        (Route::WebRecent, "Recent"),
+    ].iter()
+        .map(|&(ref route, name)| format!(r#"<a href="{}">{}</a>"#, href(url_prefix, route), name))
+        .collect::<Vec<_>>()
+        .join(" ");
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<link rel="stylesheet" href="{style}">
+</head>
+<body>
+<nav>{nav}</nav>
+<main>
+<h1>{title}</h1>
+{body}
+</main>
+</body>
+</html>
+"#,
+        title = escape(title),
+        style = href(url_prefix, &Route::WebStatic("style.css".to_owned())),
+        nav = nav,
+        body = body
+    )
+}
+
+pub fn make_start_page(url_prefix: &str, user: &User) -> String {
+    let body = format!(
+        r#"<p>Logged in as {}.
+Point your OPDS reader at <code>{}/</code> to read on other devices.</p>
+<ul class="sections">
+<li><a href="{}">Unread comics by series</a></li>
+<li><a href="{}">Comics by publisher</a></li>
+<li><a href="{}">Recent comics</a></li>
+{}</ul>"#,
+        escape(&user.username),
+        escape(url_prefix),
+        href(url_prefix, &Route::WebUnread),
+        href(url_prefix, &Route::WebPublishers),
+        href(url_prefix, &Route::WebRecent),
+        if user.role == Role::Admin {
+            format!(r#"<li><a href="{}">Admin</a></li>"#, href(url_prefix, &Route::Admin))
+        } else {
+            String::new()
+        }
+    );
+    layout(url_prefix, "Rust OPDS", &body)
+}
+
+/// A list of links, like the subsection feeds
+pub fn make_subsection_page(
+    url_prefix: &str,
+    title: &str,
+    subs: &[(String, DateTime<Utc>, Route)],
+) -> String {
+    let mut body = String::from(r#"<ul class="sections">"#);
+    for sub in subs {
+        body.push_str(&format!(
+            r#"<li><a href="{}">{}</a></li>"#,
+            href(url_prefix, &sub.2),
+            escape(&sub.0)
+        ));
+    }
+    body.push_str("</ul>");
+    layout(url_prefix, title, &body)
+}
+
+/// A grid of covers, like the acquisition feeds
+pub fn make_issues_page(url_prefix: &str, title: &str, entries: &[ComicInfo]) -> String {
+    let mut body = String::from(r#"<div class="issues">"#);
+    for entry in entries {
+        let issue_id = entry.id.unwrap_or(0);
+        body.push_str(&format!(
+            r#"<a class="issue" href="{}"><img loading="lazy" src="{}" alt=""><span>{}</span></a>"#,
+            href(url_prefix, &Route::WebIssue(issue_id)),
+            href(url_prefix, &Route::Cover(issue_id)),
+            escape(&entry.get_display_title())
+        ));
+    }
+    body.push_str("</div>");
+    layout(url_prefix, title, &body)
+}
+
+/// Covers for each series, with how much of it is left to read
+pub fn make_series_page<F>(
+    url_prefix: &str,
+    title: &str,
+    series: &[Series],
+    series_route: F,
+) -> String
+where
+    F: Fn(&Series) -> Route,
+{
+    let mut body = String::from(r#"<div class="issues">"#);
+    for s in series {
+        let cover = match s.cover_issue_id {
+            Some(issue_id) => format!(r#" src="{}""#, href(url_prefix, &Route::Cover(issue_id))),
+            None => String::new(),
+        };
+        body.push_str(&format!(
+            r#"<a class="issue" href="{}"><img loading="lazy"{} alt=""><span>{}</span><small>{} issues, {} unread</small></a>"#,
+            href(url_prefix, &series_route(s)),
+            cover,
+            escape(&s.get_title()),
+            s.issue_count,
+            s.unread_count
+        ));
+    }
+    body.push_str("</div>");
+    layout(url_prefix, title, &body)
+}
+
+/// progress is the page the user stopped at, if they've started reading
+pub fn make_issue_page(url_prefix: &str, entry: &ComicInfo, progress: Option<i32>) -> String {
+    let issue_id = entry.id.unwrap_or(0);
+    let mut details = String::new();
+    let fields = [
+        ("Title", &entry.title),
+        ("Publisher", &entry.publisher),
+        ("Writer", &entry.writer),
+        ("Penciller", &entry.penciller),
+        ("Inker", &entry.inker),
+        ("Colorist", &entry.colorist),
+        ("Cover Artist", &entry.cover_artist),
+    ];
+    for &(name, value) in &fields {
+        if let Some(ref value) = *value {
+            details.push_str(&format!("<dt>{}</dt><dd>{}</dd>", name, escape(value)));
+        }
+    }
+    if let Some(released_at) = entry.released_at {
+        details.push_str(&format!("<dt>Released</dt><dd>{}</dd>", released_at));
+    }
+    if let Some(page_count) = entry.page_count {
+        details.push_str(&format!("<dt>Pages</dt><dd>{}</dd>", page_count));
+    }
+
+    let read_label = match progress {
+        Some(page) if page > 0 => format!("Continue from page {}", page + 1),
+        _ => "Read".to_owned(),
+    };
+    let body = format!(
+        r#"<div class="details">
+<img class="cover" src="{cover}" alt="">
+<div>
+<dl>{details}</dl>
+<p>{summary}</p>
+<p class="actions"><a href="{read}">{read_label}</a> <a href="{download}">Download</a></p>
+</div>
+</div>"#,
+        cover = href(url_prefix, &Route::Cover(issue_id)),
+        details = details,
+        summary = escape(entry.summary.as_ref().map_or("", |s| &**s)),
+        read = href(url_prefix, &Route::WebRead(issue_id)),
+        read_label = read_label,
+        download = href(url_prefix, &Route::Download(issue_id, entry.get_filename())),
+    );
+    layout(url_prefix, &entry.get_display_title(), &body)
+}
+
+/// The reader itself is all in reader.js, this just hands it what it needs
+pub fn make_reader_page(
+    url_prefix: &str,
+    entry: &ComicInfo,
+    page_count: i32,
+    start_page: i32,
+) -> String {
+    let issue_id = entry.id.unwrap_or(0);
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>{title}</title>
+<link rel="stylesheet" href="{style}">
+</head>
+<body class="reading">
+<div id="reader" data-pages="{pages}" data-start="{start}" data-direction="{direction}"
+  data-stream="{stream}" data-progress="{progress}" data-back="{back}">
+<img id="page" alt="">
+<div id="status"><a href="{back}">Back</a> <span id="counter"></span></div>
+</div>
+<script src="{script}"></script>
+</body>
+</html>
+"#,
+        title = escape(&entry.get_display_title()),
+        style = href(url_prefix, &Route::WebStatic("style.css".to_owned())),
+        pages = page_count,
+        start = start_page,
+        direction = entry.direction.unwrap_or_default().as_str(),
+        stream = escape(&format!(
+            "{}{}",
+            url_prefix,
+            Route::Page(issue_id, 0).url_template("page", "{pageNumber}")
+        )),
+        progress = escape(&format!(
+            "{}{}",
+            url_prefix,
+            Route::Progress(issue_id, 0).url_template("page", "{pageNumber}")
+        )),
+        back = href(url_prefix, &Route::WebIssue(issue_id)),
+        script = href(url_prefix, &Route::WebStatic("reader.js".to_owned())),
+    )
+}
+
+pub fn make_admin_page(url_prefix: &str, report: &AdminReport) -> String {
+    let mut body = String::from("<h2>Libraries</h2>\n<table>");
+    body.push_str("<tr><th>Name</th><th>Path</th><th>Scan</th><th>Processed</th></tr>");
+    for scan in &report.scans {
+        let state = if scan.running {
+            format!("running since {}", format_time(scan.started_at))
+        } else {
+            format!("finished {}", format_time(scan.finished_at))
+        };
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>",
+            escape(&scan.name),
+            escape(&scan.path),
+            state,
+            scan.processed
+        ));
+        for error in &scan.errors {
+            body.push_str(&format!(
+                r#"<tr class="error"><td></td><td colspan="3">{}: {}</td></tr>"#,
+                escape(&error.path),
+                escape(&error.message)
+            ));
+        }
+    }
+    body.push_str(&format!(
+        r#"</table>
+<form method="post" action="{}"><button>Rescan</button></form>"#,
+        href(url_prefix, &Route::AdminRescan)
+    ));
+
+    let stats = &report.stats;
+    body.push_str(&format!(
+        "<h2>Stats</h2>\n<p>{} issues, {} without a series, {} missing files</p>",
+        stats.issues, stats.untagged, report.missing_count
+    ));
+    if !report.missing_files.is_empty() {
+        body.push_str("<details><summary>Missing files</summary><ul>");
+        for path in &report.missing_files {
+            body.push_str(&format!("<li>{}</li>", escape(path)));
+        }
+        body.push_str("</ul></details>");
+    }
+    body.push_str("<table><tr><th>Publisher</th><th>Issues</th></tr>");
+    for &(ref publisher, count) in &stats.publishers {
+        body.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td></tr>",
+            escape(publisher),
+            count
+        ));
+    }
+    body.push_str("</table>");
+
+    body.push_str("<h2>Users</h2>\n<table><tr><th>Username</th><th>Role</th><th></th></tr>");
+    for user in &report.users {
+        body.push_str(&format!(
+            r#"<tr><td>{}</td><td>{}</td><td>
+<form method="post" action="{}" onsubmit="return confirm('Delete this user?')">
+<button>Delete</button></form></td></tr>"#,
+            escape(&user.username),
+            user.role,
+            href(url_prefix, &Route::AdminDeleteUser(user.username.clone())),
+        ));
+    }
+    body.push_str("</table>");
+
+    let settings = &report.settings;
+    let modes = [AuthMode::Open, AuthMode::Closed, AuthMode::Anonymous]
+        .iter()
+        .map(|mode| {
+            format!(
+                r#"<option{}>{}</option>"#,
+                if *mode == settings.auth_mode { " selected" } else { "" },
+                mode.as_str()
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("");
+    body.push_str(&format!(
+        r#"<h2>Settings</h2>
+<form method="post" action="{}">
+<p><label>Auth mode <select name="auth_mode">{}</select></label></p>
+<p><label>Session minutes <input type="number" min="1" name="session_minutes" value="{}"></label></p>
+<p><button>Save</button></p>
+</form>"#,
+        href(url_prefix, &Route::AdminSettings),
+        modes,
+        settings.session_minutes
+    ));
+
+    layout(url_prefix, "Admin", &body)
+}
+
+fn format_time(time: Option<DateTime<Local>>) -> String {
+    time.map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| "never".to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn reader_page(xml: &str) -> String {
+        let xml = format!("<ComicInfo><Series>Berserk &amp; Co</Series>{}</ComicInfo>", xml);
+        let mut entry = ComicInfo::new(Path::new("Berserk 1.cbz"), Some(xml)).unwrap();
+        entry.id = Some(7);
+        make_reader_page("/t/abc", &entry, 24, 3)
+    }
+
+    #[test]
+    fn reader_page_direction() {
+        let page = reader_page("<Number>1</Number><Manga>YesAndRightToLeft</Manga>");
+        assert!(page.contains(r#"data-pages="24" data-start="3" data-direction="rtl""#));
+        assert!(page.contains("Berserk &amp; Co"));
+        // Untagged and plain "Yes" read left to right
+        for manga in &["", "<Manga>Yes</Manga>", "<Manga>No</Manga>"] {
+            assert!(reader_page(manga).contains(r#"data-direction="ltr""#));
+        }
+    }
+}
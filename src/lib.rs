@@ -6,6 +6,7 @@ extern crate base64;
 extern crate chrono;
 extern crate env_logger;
 extern crate serde;
+extern crate serde_json;
 #[macro_use]
 extern crate failure;
 extern crate futures;
@@ -31,41 +32,215 @@ extern crate zip;
 
 use chrono::prelude::*;
 use failure::Error;
+use scanner::Scanner;
 use std::io::prelude::*;
 use std::net::SocketAddr;
 use std::path::Path;
 use std::path::PathBuf;
 use std::str;
 use std::sync::Arc;
-use std::thread;
 use walkdir::{DirEntry, WalkDir};
 use xml::reader::{EventReader, XmlEvent};
 
+mod admin;
+mod auth;
+mod cbl;
 pub mod db;
+mod error;
+mod html;
 mod opds;
+mod router;
+mod scanner;
 pub mod web;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
     addr: SocketAddr,
-    pub comics_path: PathBuf,
+    /// The one library from before there could be several, same as a
+    /// `[[library]]` named Comics
+    pub comics_path: Option<PathBuf>,
+    #[serde(default, rename = "library")]
+    pub libraries: Vec<LibraryConfig>,
     pub database_path: PathBuf,
     pub tag_authority: String,
     pub import_comicrack: Option<ImportConfig>,
+    #[serde(default)]
+    pub auth: AuthConfig,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Config {
+    /// Nothing on disk and no libraries, for the tests of feeds and handlers
+    #[cfg(test)]
+    pub fn for_tests() -> Config {
+        Config {
+            addr: "127.0.0.1:8080".parse().unwrap(),
+            comics_path: None,
+            libraries: Vec::new(),
+            database_path: PathBuf::from("opds.db"),
+            tag_authority: "example.com,2018".to_owned(),
+            import_comicrack: None,
+            auth: AuthConfig::default(),
+        }
+    }
+
+    /// Every library, starting with the one in comics_path if it's set
+    pub fn libraries(&self) -> Vec<LibraryConfig> {
+        let mut libraries = Vec::new();
+        if let Some(ref path) = self.comics_path {
+            libraries.push(LibraryConfig {
+                name: "Comics".to_owned(),
+                path: path.clone(),
+                scan_minutes: None,
+                direction: None,
+            });
+        }
+        libraries.extend(self.libraries.iter().cloned());
+        libraries
+    }
+
+    /// Changes one of the settings that can be changed while running, named
+    /// like they are in config.toml.  See DB::get_settings.
+    pub fn apply_setting(&mut self, name: &str, value: &str) -> Result<(), Error> {
+        match name {
+            "auth.mode" => self.auth.mode = value.parse()?,
+            "auth.session_minutes" => self.auth.session_minutes = value.parse()?,
+            _ => return Err(format_err!("{} can't be changed while running", name)),
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LibraryConfig {
+    pub name: String,
+    pub path: PathBuf,
+    /// Rescan this often, otherwise the library is only scanned at startup
+    pub scan_minutes: Option<u64>,
+    /// How every issue in this library is read, whatever its ComicInfo.xml says
+    #[serde(default)]
+    pub direction: Option<Direction>,
+}
+
+/// Reading direction, right to left is mostly for manga
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Ltr,
+    Rtl,
+}
+
+impl Direction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Direction::Ltr => "ltr",
+            Direction::Rtl => "rtl",
+        }
+    }
+}
+
+impl Default for Direction {
+    fn default() -> Direction {
+        Direction::Ltr
+    }
+}
+
+impl str::FromStr for Direction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Direction, Error> {
+        match s {
+            "ltr" => Ok(Direction::Ltr),
+            "rtl" => Ok(Direction::Rtl),
+            _ => Err(format_err!("Unknown reading direction {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthConfig {
+    #[serde(default)]
+    pub mode: AuthMode,
+    /// How long a login is remembered, counted from when they logged in
+    #[serde(default = "default_session_minutes")]
+    pub session_minutes: u64,
+}
+
+impl Default for AuthConfig {
+    fn default() -> AuthConfig {
+        AuthConfig {
+            mode: AuthMode::default(),
+            session_minutes: default_session_minutes(),
+        }
+    }
+}
+
+fn default_session_minutes() -> u64 {
+    60
+}
+
+/// How the web layer treats usernames it hasn't seen before
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Unknown usernames get an account with whatever password they log in with
+    Open,
+    /// Only users created with the opds_users tool can log in
+    Closed,
+    /// Like closed, but requests without credentials can browse and download
+    /// as a read only anonymous user
+    Anonymous,
+}
+
+impl AuthMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuthMode::Open => "open",
+            AuthMode::Closed => "closed",
+            AuthMode::Anonymous => "anonymous",
+        }
+    }
+}
+
+impl Default for AuthMode {
+    fn default() -> AuthMode {
+        AuthMode::Open
+    }
+}
+
+impl str::FromStr for AuthMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<AuthMode, Error> {
+        match s {
+            "open" => Ok(AuthMode::Open),
+            "closed" => Ok(AuthMode::Closed),
+            "anonymous" => Ok(AuthMode::Anonymous),
+            _ => Err(format_err!("Unknown auth mode {}", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ImportConfig {
+    /// Which library the ComicRack paths are under, defaults to the first
+    pub library: Option<String>,
     pub strip_prefix: Option<String>,
     pub read_user: Option<String>,
 }
 
+#[derive(Serialize)]
 pub struct ComicInfo {
     pub id: Option<i64>,
+    pub library_id: Option<i64>,
+    #[serde(skip)]
     pub comic_info: Option<String>,
+    // Where things are on the server is nobody else's business
+    #[serde(skip)]
     pub filepath: String,
     pub size: i32,
     pub modified_at: DateTime<Local>,
+    /// When it first showed up in the library, None until it's stored
+    pub added_at: Option<DateTime<Utc>>,
     pub comicvine_id: Option<i64>,
     pub comicvine_url: Option<String>,
     pub series: Option<String>,
@@ -81,15 +256,29 @@ pub struct ComicInfo {
     pub cover_artist: Option<String>,
     pub publisher: Option<String>,
     pub page_count: Option<i32>,
+    /// AgeRating as the minimum age, see parse_age_rating
+    pub age_rating: Option<i32>,
+    /// Genre and Tags split up, see split_list
+    pub genres: Vec<String>,
+    pub tags: Vec<String>,
+    /// Characters, Teams and Locations, usually from ComicVine tagging
+    pub characters: Vec<String>,
+    pub teams: Vec<String>,
+    pub locations: Vec<String>,
+    /// Manga as a reading direction, see parse_manga.  Coming out of the DB it's
+    /// whichever way its library, then the issue, then its series says to read it.
+    pub direction: Option<Direction>,
 }
 
 impl ComicInfo {
     pub fn new(entry: &Path, comic_info: Option<String>) -> Result<ComicInfo, Error> {
         let mut info = ComicInfo {
             id: None, // You don't get an Id until you are in the DB
+            library_id: None,
             comic_info,
             filepath: entry.to_string_lossy().to_string(),
             modified_at: entry_modified(entry),
+            added_at: None,
             size: entry_size(entry) as i32,
             comicvine_id: None,
             comicvine_url: None,
@@ -106,6 +295,13 @@ impl ComicInfo {
             cover_artist: None,
             publisher: None,
             page_count: None,
+            age_rating: None,
+            genres: Vec::new(),
+            tags: Vec::new(),
+            characters: Vec::new(),
+            teams: Vec::new(),
+            locations: Vec::new(),
+            direction: None,
         };
         if let Some(ref comic_info) = info.comic_info {
             let parser = EventReader::from_str(comic_info);
@@ -135,6 +331,13 @@ impl ComicInfo {
                             "CoverArtist" => info.cover_artist = Some(current_string.clone()),
                             "Publisher" => info.publisher = Some(current_string.clone()),
                             "PageCount" => info.page_count = current_string.parse().ok(),
+                            "AgeRating" => info.age_rating = parse_age_rating(&current_string),
+                            "Manga" => info.direction = parse_manga(&current_string),
+                            "Genre" => info.genres = split_list(&current_string),
+                            "Tags" => info.tags = split_list(&current_string),
+                            "Characters" => info.characters = split_list(&current_string),
+                            "Teams" => info.teams = split_list(&current_string),
+                            "Locations" => info.locations = split_list(&current_string),
                             _ => (),
                         }
                     }
@@ -163,18 +366,80 @@ impl ComicInfo {
             None => self.get_filename()
         }
     }
+
+    /// Series, volume and issue number, what readers mostly go by
+    pub fn get_display_title(&self) -> String {
+        format!(
+            "{} v{} {}",
+            self.get_series(),
+            self.volume.unwrap_or(1),
+            self.issue_number.unwrap_or(1)
+        )
+    }
+}
+
+/// Turns the ComicInfo AgeRating names into the minimum age they're meant for,
+/// so restrictions can be a simple comparison.  Unknown and pending come back None.
+pub fn parse_age_rating(rating: &str) -> Option<i32> {
+    match rating.trim() {
+        "Everyone" | "G" | "Early Childhood" => Some(0),
+        "Kids to Adults" => Some(6),
+        "Everyone 10+" | "PG" => Some(10),
+        "Teen" => Some(13),
+        "MA15+" => Some(15),
+        "M" | "Mature 17+" => Some(17),
+        "R18+" | "Adults Only 18+" | "X18+" => Some(18),
+        _ => None,
+    }
+}
+
+/// Manga only says which way to read for YesAndRightToLeft and No, a plain Yes
+/// (or Unknown) leaves it to the series or library
+pub fn parse_manga(manga: &str) -> Option<Direction> {
+    match manga.trim() {
+        "YesAndRightToLeft" => Some(Direction::Rtl),
+        "No" => Some(Direction::Ltr),
+        _ => None,
+    }
 }
 
-pub fn run(config: Config) -> Result<(), Error> {
+/// Splits comma separated fields like Genre and Characters, squeezing the whitespace in
+/// each value and dropping empty ones and repeats, ignoring case
+pub fn split_list(list: &str) -> Vec<String> {
+    let mut values: Vec<String> = Vec::new();
+    for value in list.split(',') {
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        let lower = value.to_lowercase();
+        if !value.is_empty() && !values.iter().any(|v| v.to_lowercase() == lower) {
+            values.push(value);
+        }
+    }
+    values
+}
+
+pub fn run(mut config: Config) -> Result<(), Error> {
     let db = Arc::new(db::DB::new(config.database_path.as_path())?);
+    for (name, value) in db.get_settings()? {
+        if let Err(e) = config.apply_setting(&name, &value) {
+            error!("Ignoring saved setting {}: {}", name, e);
+        }
+    }
     let config = Arc::new(config);
-    let scan_db = Arc::clone(&db);
-    let comics_path = config.comics_path.clone();
-    thread::spawn(move || match scan_dir(comics_path.as_path(), &scan_db) {
-        Err(e) => error!("Error scanning: {}, {}", e, e.backtrace()),
-        _ => info!("Done scanning directory"),
-    });
-    web::start_web_service(Arc::clone(&db), &config)?;
+    let libraries = config.libraries();
+    if libraries.is_empty() {
+        return Err(failure::err_msg(
+            "No libraries, set comics_path or add a [[library]] to config.toml",
+        ));
+    }
+    let library_ids = db.sync_libraries(&libraries)?;
+    // Series feeds shouldn't have to wait for the first scan to finish
+    db.refresh_series()?;
+    let scanner = Scanner::new(
+        Arc::clone(&db),
+        library_ids.into_iter().zip(libraries).collect(),
+    );
+    scanner.start();
+    web::start_web_service(Arc::clone(&db), &config, scanner)?;
     Ok(())
 }
 
@@ -199,7 +464,11 @@ pub fn get_bytes_for_entry(filepath: &str, entry: &str) -> Result<Vec<u8>, Error
     }
 }
 
-fn scan_dir(dir: &Path, db: &Arc<db::DB>) -> Result<(), Error> {
+/// on_file is told about every comic that was (re)processed, and whether that worked
+fn scan_dir<F>(dir: &Path, library_id: i64, db: &db::DB, on_file: &mut F) -> Result<(), Error>
+where
+    F: FnMut(&Path, Option<&Error>),
+{
     for entry in WalkDir::new(dir)
         .into_iter()
         .filter_map(|e| e.ok())
@@ -220,11 +489,18 @@ fn scan_dir(dir: &Path, db: &Arc<db::DB>) -> Result<(), Error> {
         };
         match comic_info {
             Ok((comic_info, entries)) => {
-                db.store_comic(&ComicInfo::new(&entry.path(), comic_info)?, &entries)?;
+                let mut info = ComicInfo::new(&entry.path(), comic_info)?;
+                info.library_id = Some(library_id);
+                db.store_comic(&info, &entries)?;
+                on_file(entry.path(), None);
+            }
+            Err(e) => {
+                error!("Skipping {}: {}", entry.path().display(), e);
+                on_file(entry.path(), Some(&e));
             }
-            Err(e) => error!("Skipping {}: {}", entry.path().display(), e),
         }
     }
+    db.refresh_series()?;
     db.analyze()?;
     Ok(())
 }
@@ -1,10 +1,13 @@
+use super::db::{Facets, Format, Library, Series, Shelf, Sort};
+use super::html::escape;
+use super::router::Route;
 use super::ComicInfo;
 use super::Config;
 use chrono::prelude::*;
 use failure::Error;
 use std::borrow::Cow;
+use std::collections::BTreeSet;
 use std::io::prelude::*;
-use url::percent_encoding::{utf8_percent_encode, DEFAULT_ENCODE_SET};
 use xml::name::Name;
 use xml::writer::{EventWriter, XmlEvent};
 
@@ -18,6 +21,8 @@ pub enum Rel {
     Thumbnail,
     Acquisition,
     Stream,
+    Facet,
+    Alternate,
 }
 
 impl Rel {
@@ -31,6 +36,8 @@ impl Rel {
             Rel::Thumbnail => "http://opds-spec.org/image/thumbnail",
             Rel::Acquisition => "http://opds-spec.org/acquisition",
             Rel::Stream => "http://vaemendis.net/opds-pse/stream",
+            Rel::Facet => "http://opds-spec.org/facet",
+            Rel::Alternate => "alternate",
         }
     }
 }
@@ -41,6 +48,9 @@ pub enum LinkType {
     Acquisition,
     Navigation,
     OctetStream,
+    /// Plain Atom, for feed readers rather than OPDS clients
+    Atom,
+    Html,
 }
 
 impl LinkType {
@@ -50,16 +60,34 @@ impl LinkType {
             LinkType::Acquisition => "application/atom+xml; profile=opds-catalog; kind=acquisition",
             LinkType::Navigation => "application/atom+xml; profile=opds-catalog; kind=navigation",
             LinkType::OctetStream => "application/octet-stream",
+            LinkType::Atom => "application/atom+xml",
+            LinkType::Html => "text/html",
         }
     }
 }
 
+/// Pages for streaming links, issues for series
+#[derive(Debug)]
+enum LinkCount {
+    Pages(i32),
+    Issues(i64),
+}
+
 #[derive(Debug)]
 struct OpdsLink<'a> {
     link_type: LinkType,
     rel: Rel,
     url: Cow<'a, str>,
-    count: Option<i32>,
+    count: Option<LinkCount>,
+}
+
+/// A link to the same feed sorted or filtered differently
+#[derive(Debug)]
+struct OpdsFacet {
+    group: &'static str,
+    title: String,
+    url: String,
+    active: bool,
 }
 
 #[derive(Debug)]
@@ -96,8 +124,11 @@ impl<'a> OpdsEntry<'a> {
 struct OpdsFeed<'a> {
     id: String,
     title: &'a str,
+    /// Prepended to every link, for clients that authenticate with a token in the path
+    url_prefix: &'a str,
     entries: Vec<OpdsEntry<'a>>,
     links: Vec<OpdsLink<'a>>,
+    facets: Vec<OpdsFacet>,
     updated: DateTime<Utc>,
 }
 
@@ -109,24 +140,47 @@ fn make_id_from_url(tag_authority: &str, url: &str) -> String {
     )
 }
 
+/// Which sets of facet links a feed offers, those that can't change what's
+/// in it are left out
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FacetGroup {
+    Sort,
+    Read,
+    Publisher,
+    Year,
+    Format,
+}
+
+/// facets are the ones the client picked, used to link to the other choices in groups
 pub fn make_acquisition_feed(
     config: &Config,
-    url: &str,
+    url_prefix: &str,
+    route: &Route,
     title: &str,
     entries: &[ComicInfo],
+    facets: &Facets,
+    groups: &[FacetGroup],
 ) -> Result<String, Error> {
-    let id = make_id_from_url(&config.tag_authority, url);
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
+    let facet_links = make_facets(&url, facets, groups, entries);
+    let query = facets.to_query();
+    let self_url = if query.is_empty() {
+        url
+    } else {
+        format!("{}?{}", url, query)
+    };
     let links = vec![
         OpdsLink {
             link_type: LinkType::Acquisition,
             rel: Rel::RelSelf,
-            url: Cow::Borrowed(url),
+            url: Cow::Owned(self_url),
             count: None,
         },
         OpdsLink {
             link_type: LinkType::Navigation,
             rel: Rel::Start,
-            url: Cow::Borrowed("/"),
+            url: Cow::Owned(Route::Start.url()),
             count: None,
         },
     ];
@@ -138,83 +192,473 @@ pub fn make_acquisition_feed(
     let feed = OpdsFeed {
         id,
         title,
+        url_prefix,
+        updated: Utc::now(),
+        links,
+        facets: facet_links,
+        entries,
+    };
+    write_opds(&feed)
+}
+
+/// A plain Atom feed of the issues most recently added, linking to the web UI
+/// so it makes sense in a feed reader
+pub fn make_new_arrivals_feed(
+    config: &Config,
+    url_prefix: &str,
+    route: &Route,
+    entries: &[ComicInfo],
+) -> Result<String, Error> {
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
+    let links = vec![
+        OpdsLink {
+            link_type: LinkType::Atom,
+            rel: Rel::RelSelf,
+            url: Cow::Owned(url),
+            count: None,
+        },
+        OpdsLink {
+            link_type: LinkType::Html,
+            rel: Rel::Alternate,
+            url: Cow::Owned(Route::WebStart.url()),
+            count: None,
+        },
+    ];
+    let entries = entries
+        .iter()
+        .map(|e| make_arrival_entry(&config.tag_authority, url_prefix, e))
+        .collect();
+
+    let feed = OpdsFeed {
+        id,
+        title: "New arrivals",
+        url_prefix,
         updated: Utc::now(),
         links,
+        facets: Vec::new(),
         entries,
     };
     write_opds(&feed)
 }
 
+/// Links for each sort and filter in groups, publishers and years come from
+/// the entries so only ones that narrow things down are offered
+fn make_facets(
+    url: &str,
+    facets: &Facets,
+    groups: &[FacetGroup],
+    entries: &[ComicInfo],
+) -> Vec<OpdsFacet> {
+    let mut links = Vec::new();
+    {
+        let mut push = |group: &'static str, title: &str, choice: Facets| {
+            let query = choice.to_query();
+            links.push(OpdsFacet {
+                group,
+                title: title.to_owned(),
+                url: if query.is_empty() {
+                    url.to_owned()
+                } else {
+                    format!("{}?{}", url, query)
+                },
+                active: choice == *facets,
+            });
+        };
+
+        if groups.contains(&FacetGroup::Sort) {
+            let sorts = [
+                (Sort::Released, "Release date"),
+                (Sort::Added, "Recently added"),
+                (Sort::Series, "Series"),
+                (Sort::Title, "Title"),
+            ];
+            for &(sort, title) in &sorts {
+                push("Sort", title, Facets { sort: Some(sort), ..facets.clone() });
+            }
+        }
+
+        if groups.contains(&FacetGroup::Read) {
+            let reads = [(None, "All"), (Some(false), "Unread"), (Some(true), "Read")];
+            for &(read, title) in &reads {
+                push("Read", title, Facets { read, ..facets.clone() });
+            }
+        }
+
+        if groups.contains(&FacetGroup::Publisher) {
+            let publishers: BTreeSet<&str> = entries
+                .iter()
+                .filter_map(|e| e.publisher.as_ref().map(|p| &**p))
+                .collect();
+            push("Publisher", "All publishers", Facets { publisher: None, ..facets.clone() });
+            for publisher in publishers {
+                let choice = Facets {
+                    publisher: Some(publisher.to_owned()),
+                    ..facets.clone()
+                };
+                push("Publisher", publisher, choice);
+            }
+        }
+
+        if groups.contains(&FacetGroup::Year) {
+            let years: BTreeSet<i32> = entries
+                .iter()
+                .filter_map(|e| e.released_at.map(|r| r.year()))
+                .collect();
+            push("Year", "All years", Facets { year: None, ..facets.clone() });
+            for year in years.into_iter().rev() {
+                push("Year", &year.to_string(), Facets { year: Some(year), ..facets.clone() });
+            }
+        }
+
+        if groups.contains(&FacetGroup::Format) {
+            let formats = [
+                (None, "All formats"),
+                (Some(Format::Cbz), "CBZ"),
+                (Some(Format::Cbr), "CBR"),
+            ];
+            for &(format, title) in &formats {
+                push("Format", title, Facets { format, ..facets.clone() });
+            }
+        }
+    }
+    links
+}
+
+/// subsection_route maps each subsection name to where its entry should link
+/// subs are the name, last update and where each subsection is
 pub fn make_subsection_feed(
     config: &Config,
     url_prefix: &str,
+    route: &Route,
     title: &str,
-    subs: &mut Vec<(String, DateTime<Utc>)>,
+    subs: &[(String, DateTime<Utc>, Route)],
 ) -> Result<String, Error> {
-    let id = make_id_from_url(&config.tag_authority, url_prefix);
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
+    let links = vec![
+        OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::RelSelf,
+            url: Cow::Owned(url),
+            count: None,
+        },
+        OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::Start,
+            url: Cow::Owned(Route::Start.url()),
+            count: None,
+        },
+    ];
+
+    let entries = subs.iter()
+        .map(|sub| make_subsection_entry(&config.tag_authority, sub))
+        .collect();
+
+    let feed = OpdsFeed {
+        id,
+        title: &title,
+        url_prefix,
+        updated: Utc::now(),
+        links,
+        facets: Vec::new(),
+        entries,
+    };
+    write_opds(&feed)
+}
+
+/// Like make_subsection_feed, but each series gets its first cover and counts
+pub fn make_series_feed<F>(
+    config: &Config,
+    url_prefix: &str,
+    route: &Route,
+    title: &str,
+    series: &[Series],
+    series_route: F,
+) -> Result<String, Error>
+where
+    F: Fn(&Series) -> Route,
+{
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
+    let links = vec![
+        OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::RelSelf,
+            url: Cow::Owned(url),
+            count: None,
+        },
+        OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::Start,
+            url: Cow::Owned(Route::Start.url()),
+            count: None,
+        },
+    ];
+
+    let entries = series
+        .iter()
+        .map(|s| make_series_entry(&config.tag_authority, s, &series_route))
+        .collect();
+
+    let feed = OpdsFeed {
+        id,
+        title,
+        url_prefix,
+        updated: Utc::now(),
+        links,
+        facets: Vec::new(),
+        entries,
+    };
+    write_opds(&feed)
+}
+
+/// The user's shelves with how much is unread on each
+pub fn make_shelves_feed<F>(
+    config: &Config,
+    url_prefix: &str,
+    route: &Route,
+    shelves: &[Shelf],
+    shelf_route: F,
+) -> Result<String, Error>
+where
+    F: Fn(&Shelf) -> Route,
+{
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
     let links = vec![
         OpdsLink {
             link_type: LinkType::Navigation,
             rel: Rel::RelSelf,
-            url: Cow::Borrowed(url_prefix),
+            url: Cow::Owned(url),
             count: None,
         },
         OpdsLink {
             link_type: LinkType::Navigation,
             rel: Rel::Start,
-            url: Cow::Borrowed("/"),
+            url: Cow::Owned(Route::Start.url()),
             count: None,
         },
     ];
 
-    let entries = subs.iter_mut()
-        .map(|sub| {
-            let url = utf8_percent_encode(&format!("{}/{}", url_prefix, sub.0), DEFAULT_ENCODE_SET)
-                .to_string();
-            let id = make_id_from_url(&config.tag_authority, &url);
-            OpdsEntry::new(
-                id,
-                &sub.0,
-                &sub.0,
-                Vec::new(),
-                vec![OpdsLink {
+    let entries = shelves
+        .iter()
+        .map(|shelf| {
+            let url = shelf_route(shelf).url();
+            OpdsEntry {
+                id: make_id_from_url(&config.tag_authority, &url),
+                updated: shelf.updated_at,
+                title: Cow::Borrowed(&shelf.name),
+                content: Cow::Owned(format!(
+                    "{} issues, {} unread",
+                    shelf.issue_count, shelf.unread_count
+                )),
+                authors: Vec::new(),
+                links: vec![OpdsLink {
                     link_type: LinkType::Navigation,
                     rel: Rel::Subsection,
                     url: Cow::Owned(url),
-                    count: None,
+                    count: Some(LinkCount::Issues(shelf.issue_count)),
                 }],
-                sub.1,
-            )
+            }
         })
         .collect();
 
     let feed = OpdsFeed {
         id,
-        title: &title,
+        title: "Shelves",
+        url_prefix,
         updated: Utc::now(),
         links,
+        facets: Vec::new(),
         entries,
     };
     write_opds(&feed)
 }
-pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
+
+/// A shelf's series followed by the issues put on it, see make_folder_feed
+pub fn make_shelf_feed<F>(
+    config: &Config,
+    url_prefix: &str,
+    route: &Route,
+    title: &str,
+    series: &[Series],
+    entries: &[ComicInfo],
+    series_route: F,
+) -> Result<String, Error>
+where
+    F: Fn(&Series) -> Route,
+{
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
+    let links = vec![
+        OpdsLink {
+            link_type: LinkType::Acquisition,
+            rel: Rel::RelSelf,
+            url: Cow::Owned(url),
+            count: None,
+        },
+        OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::Start,
+            url: Cow::Owned(Route::Start.url()),
+            count: None,
+        },
+    ];
+
+    let entries = series
+        .iter()
+        .map(|s| make_series_entry(&config.tag_authority, s, &series_route))
+        .chain(entries.iter().map(|e| make_entry(&config.tag_authority, e)))
+        .collect();
+
+    let feed = OpdsFeed {
+        id,
+        title,
+        url_prefix,
+        updated: Utc::now(),
+        links,
+        facets: Vec::new(),
+        entries,
+    };
+    write_opds(&feed)
+}
+
+/// A folder's subfolders followed by the issues in it, see make_subsection_feed
+pub fn make_folder_feed(
+    config: &Config,
+    url_prefix: &str,
+    route: &Route,
+    title: &str,
+    folders: &[(String, DateTime<Utc>, Route)],
+    entries: &[ComicInfo],
+) -> Result<String, Error> {
+    let url = route.url();
+    let id = make_id_from_url(&config.tag_authority, &url);
+    let links = vec![
+        OpdsLink {
+            link_type: LinkType::Acquisition,
+            rel: Rel::RelSelf,
+            url: Cow::Owned(url),
+            count: None,
+        },
+        OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::Start,
+            url: Cow::Owned(Route::Start.url()),
+            count: None,
+        },
+    ];
+
+    let entries = folders
+        .iter()
+        .map(|folder| make_subsection_entry(&config.tag_authority, folder))
+        .chain(entries.iter().map(|e| make_entry(&config.tag_authority, e)))
+        .collect();
+
+    let feed = OpdsFeed {
+        id,
+        title,
+        url_prefix,
+        updated: Utc::now(),
+        links,
+        facets: Vec::new(),
+        entries,
+    };
+    write_opds(&feed)
+}
+
+fn make_subsection_entry<'a>(
+    tag_authority: &str,
+    sub: &'a (String, DateTime<Utc>, Route),
+) -> OpdsEntry<'a> {
+    let url = sub.2.url();
+    let id = make_id_from_url(tag_authority, &url);
+    OpdsEntry::new(
+        id,
+        &sub.0,
+        &sub.0,
+        Vec::new(),
+        vec![OpdsLink {
+            link_type: LinkType::Navigation,
+            rel: Rel::Subsection,
+            url: Cow::Owned(url),
+            count: None,
+        }],
+        sub.1,
+    )
+}
+
+fn make_series_entry<'a, F>(
+    tag_authority: &str,
+    series: &'a Series,
+    series_route: &F,
+) -> OpdsEntry<'a>
+where
+    F: Fn(&Series) -> Route,
+{
+    let url = series_route(series).url();
+    let id = make_id_from_url(tag_authority, &url);
+    let mut links = Vec::new();
+    if let Some(cover_issue_id) = series.cover_issue_id {
+        let cover = Route::Cover(cover_issue_id).url();
+        links.push(OpdsLink {
+            link_type: LinkType::Jpeg,
+            rel: Rel::Image,
+            url: Cow::Owned(cover.clone()),
+            count: None,
+        });
+        links.push(OpdsLink {
+            link_type: LinkType::Jpeg,
+            rel: Rel::Thumbnail,
+            url: Cow::Owned(cover),
+            count: None,
+        });
+    }
+    links.push(OpdsLink {
+        link_type: LinkType::Acquisition,
+        rel: Rel::Subsection,
+        url: Cow::Owned(url),
+        count: Some(LinkCount::Issues(series.issue_count)),
+    });
+
+    let counts = format!("{} issues, {} unread", series.issue_count, series.unread_count);
+    OpdsEntry {
+        id,
+        updated: series.updated_at,
+        title: Cow::Owned(series.get_title()),
+        content: Cow::Owned(match series.description {
+            Some(ref description) => format!("{}<br/>{}", counts, escape(description)),
+            None => counts,
+        }),
+        authors: Vec::new(),
+        links,
+    }
+}
+
+/// The top level feed, with an entry for each library after the usual ones
+pub fn make_navigation_feed(
+    config: &Config,
+    url_prefix: &str,
+    libraries: &[Library],
+) -> Result<String, Error> {
     let id = format!("tag:{}:top", config.tag_authority);
     let links = vec![
         OpdsLink {
             link_type: LinkType::Navigation,
             rel: Rel::RelSelf,
-            url: Cow::Borrowed("/"),
+            url: Cow::Owned(Route::Start.url()),
             count: None,
         },
         OpdsLink {
             link_type: LinkType::Navigation,
             rel: Rel::Start,
-            url: Cow::Borrowed("/"),
+            url: Cow::Owned(Route::Start.url()),
             count: None,
         },
     ];
 
-    let entries = vec![
+    let mut entries = vec![
         OpdsEntry::new(
             format!("tag:{}:all", config.tag_authority),
             "All comics",
@@ -223,7 +667,7 @@ pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
             vec![OpdsLink {
                 link_type: LinkType::Acquisition,
                 rel: Rel::Subsection,
-                url: Cow::Borrowed("/all"),
+                url: Cow::Owned(Route::All.url()),
                 count: None,
             }],
             Utc::now(),
@@ -231,12 +675,25 @@ pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
         OpdsEntry::new(
             format!("tag:{}:recent", config.tag_authority),
             "Recent comics",
-            "All comics sorted by recency",
+            "All comics sorted by release date",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Acquisition,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Recent.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:added", config.tag_authority),
+            "Recently added",
+            "All comics, newest to the library first",
             Vec::new(),
             vec![OpdsLink {
                 link_type: LinkType::Acquisition,
                 rel: Rel::SortNew,
-                url: Cow::Borrowed("/recent"),
+                url: Cow::Owned(Route::Added.url()),
                 count: None,
             }],
             Utc::now(),
@@ -249,7 +706,20 @@ pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
             vec![OpdsLink {
                 link_type: LinkType::Navigation,
                 rel: Rel::SortNew,
-                url: Cow::Borrowed("/publishers"),
+                url: Cow::Owned(Route::Publishers.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:folders", config.tag_authority),
+            "Comics by folder",
+            "All comics as they are laid out on disk",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Folders.url()),
                 count: None,
             }],
             Utc::now(),
@@ -262,7 +732,7 @@ pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
             vec![OpdsLink {
                 link_type: LinkType::Acquisition,
                 rel: Rel::Subsection,
-                url: Cow::Borrowed("/unread_all"),
+                url: Cow::Owned(Route::UnreadAll.url()),
                 count: None,
             }],
             Utc::now(),
@@ -275,7 +745,7 @@ pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
             vec![OpdsLink {
                 link_type: LinkType::Navigation,
                 rel: Rel::Subsection,
-                url: Cow::Borrowed("/unread"),
+                url: Cow::Owned(Route::Unread.url()),
                 count: None,
             }],
             Utc::now(),
@@ -288,18 +758,180 @@ pub fn make_navigation_feed(config: &Config) -> Result<String, Error> {
             vec![OpdsLink {
                 link_type: LinkType::Navigation,
                 rel: Rel::Subsection,
-                url: Cow::Borrowed("/recent_unread_series"),
+                url: Cow::Owned(Route::RecentUnreadSeries.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:continue", config.tag_authority),
+            "Continue reading",
+            "Comics you're in the middle of",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Acquisition,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::ContinueReading.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:on_deck", config.tag_authority),
+            "On deck",
+            "The next comic in each series you've been reading lately",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Acquisition,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::OnDeck.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:lists", config.tag_authority),
+            "Reading lists",
+            "Reading orders made on the server or imported from ComicRack",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Lists.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:shelves", config.tag_authority),
+            "Shelves",
+            "Your favorites, what you want to read and any shelves of your own",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Shelves.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:genres", config.tag_authority),
+            "Genres",
+            "Comics by the genres in their ComicInfo.xml",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Genres.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:tags", config.tag_authority),
+            "Tags",
+            "Comics by the tags in their ComicInfo.xml",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Tags.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:characters", config.tag_authority),
+            "Characters",
+            "Every comic a character shows up in, whatever the series",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Characters.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:teams", config.tag_authority),
+            "Teams",
+            "Comics by the teams in them",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Teams.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:locations", config.tag_authority),
+            "Locations",
+            "Comics by where they happen",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Locations.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:collections", config.tag_authority),
+            "Collections",
+            "Series grouped together by an admin",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Collections.url()),
+                count: None,
+            }],
+            Utc::now(),
+        ),
+        OpdsEntry::new(
+            format!("tag:{}:years", config.tag_authority),
+            "By year",
+            "Comics by the decade and year they came out",
+            Vec::new(),
+            vec![OpdsLink {
+                link_type: LinkType::Navigation,
+                rel: Rel::Subsection,
+                url: Cow::Owned(Route::Years.url()),
                 count: None,
             }],
             Utc::now(),
         ),
     ];
 
+    entries.extend(libraries.iter().map(|library| {
+        let url = Route::Library(library.id).url();
+        OpdsEntry {
+            id: make_id_from_url(&config.tag_authority, &url),
+            updated: library.updated_at,
+            title: Cow::Borrowed(&library.name),
+            content: Cow::Owned(format!("All comics in {}", library.name)),
+            authors: Vec::new(),
+            links: vec![OpdsLink {
+                link_type: LinkType::Acquisition,
+                rel: Rel::Subsection,
+                url: Cow::Owned(url),
+                count: None,
+            }],
+        }
+    }));
+
     let feed = OpdsFeed {
         id,
         title: "Rust OPDS",
+        url_prefix,
         updated: Utc::now(),
         links,
+        facets: Vec::new(),
         entries,
     };
     write_opds(&feed)
@@ -324,33 +956,32 @@ fn make_entry<'a>(tag_authority: &str, entry: &'a ComicInfo) -> OpdsEntry<'a> {
         authors.push(&cover_artist);
     }
 
-    let url_prefix = format!("/comic/{}", entry.id.unwrap_or(0));
-    let filename: String =
-        utf8_percent_encode(&entry.get_filename(), DEFAULT_ENCODE_SET).to_string();
+    let issue_id = entry.id.unwrap_or(0);
+    let cover = Route::Cover(issue_id).url();
     let links = vec![
         OpdsLink {
             link_type: LinkType::Jpeg,
             rel: Rel::Image,
-            url: Cow::Owned(format!("/stream/{}/0/cover.jpg", entry.id.unwrap_or(0))),
+            url: Cow::Owned(cover.clone()),
             count: None,
         },
         OpdsLink {
             link_type: LinkType::Jpeg,
             rel: Rel::Thumbnail,
-            url: Cow::Owned(format!("/stream/{}/0/cover.jpg", entry.id.unwrap_or(0))),
+            url: Cow::Owned(cover),
             count: None,
         },
         OpdsLink {
             link_type: LinkType::OctetStream,
             rel: Rel::Acquisition,
-            url: Cow::Owned(format!("{}/download/{}", url_prefix, filename)),
+            url: Cow::Owned(Route::Download(issue_id, entry.get_filename()).url()),
             count: None,
         },
         OpdsLink {
             link_type: LinkType::Jpeg,
             rel: Rel::Stream,
-            url: Cow::Owned(format!("/stream/{}/{{pageNumber}}", entry.id.unwrap_or(0))),
-            count: entry.page_count,
+            url: Cow::Owned(Route::Page(issue_id, 0).url_template("page", "{pageNumber}")),
+            count: entry.page_count.map(LinkCount::Pages),
         },
     ];
 
@@ -359,39 +990,87 @@ fn make_entry<'a>(tag_authority: &str, entry: &'a ComicInfo) -> OpdsEntry<'a> {
     OpdsEntry {
         id,
         updated: entry.modified_at.with_timezone(&Utc),
-        title: Cow::Owned(format!(
-            "{} v{} {}",
-            entry.get_series(),
-            entry.volume.unwrap_or(1),
-            entry.issue_number.unwrap_or(1)
-        )),
+        title: Cow::Owned(entry.get_display_title()),
         content: Cow::Owned(format!("{} {}", title, summary)),
         authors,
         links,
     }
 }
 
-fn write_links<W: Write>(writer: &mut EventWriter<W>, links: &[OpdsLink]) -> Result<(), Error> {
+/// An issue for make_new_arrivals_feed, dated when it was added rather than
+/// when the file last changed
+fn make_arrival_entry<'a>(
+    tag_authority: &str,
+    url_prefix: &str,
+    entry: &'a ComicInfo,
+) -> OpdsEntry<'a> {
+    let issue_id = entry.id.unwrap_or(0);
+    let cover = Route::Cover(issue_id).url();
+    let mut content = format!("<img src=\"{}{}\"/>", url_prefix, cover);
+    if let Some(ref summary) = entry.summary {
+        content.push_str(&format!("<p>{}</p>", escape(summary)));
+    }
+    let mut authors: Vec<&str> = Vec::new();
+    if let Some(ref writer) = entry.writer {
+        authors.push(writer);
+    }
+
+    OpdsEntry {
+        id: format!("tag:{}:entry:{}", tag_authority, issue_id),
+        updated: entry
+            .added_at
+            .unwrap_or_else(|| entry.modified_at.with_timezone(&Utc)),
+        title: Cow::Owned(entry.get_display_title()),
+        content: Cow::Owned(content),
+        authors,
+        links: vec![
+            OpdsLink {
+                link_type: LinkType::Html,
+                rel: Rel::Alternate,
+                url: Cow::Owned(Route::WebIssue(issue_id).url()),
+                count: None,
+            },
+            OpdsLink {
+                link_type: LinkType::Jpeg,
+                rel: Rel::Image,
+                url: Cow::Owned(cover),
+                count: None,
+            },
+        ],
+    }
+}
+
+fn write_links<W: Write>(
+    writer: &mut EventWriter<W>,
+    links: &[OpdsLink],
+    url_prefix: &str,
+) -> Result<(), Error> {
     lazy_static! {
         static ref TYPE_NAME: Name<'static> = Name::local("type");
         static ref REL_NAME: Name<'static> = Name::local("rel");
         static ref HREF_NAME: Name<'static> = Name::local("href");
         static ref COUNT_NAME: Name<'static> = Name::prefixed("count", "pse");
+        static ref THR_COUNT_NAME: Name<'static> = Name::prefixed("count", "thr");
     }
 
     for link in links.iter() {
+        let url = format!("{}{}", url_prefix, link.url);
         let mut event = XmlEvent::start_element("link")
             .attr(*TYPE_NAME, link.link_type.as_str())
             .attr(*REL_NAME, link.rel.as_str())
-            .attr(*HREF_NAME, &link.url);
+            .attr(*HREF_NAME, &url);
 
         let count_str;
 
         let event = match link.count {
-            Some(count) => {
+            Some(LinkCount::Pages(count)) => {
                 count_str = count.to_string();
                 event.attr(*COUNT_NAME, &count_str)
             }
+            Some(LinkCount::Issues(count)) => {
+                count_str = count.to_string();
+                event.attr(*THR_COUNT_NAME, &count_str)
+            }
             None => event,
         };
 
@@ -401,6 +1080,35 @@ fn write_links<W: Write>(writer: &mut EventWriter<W>, links: &[OpdsLink]) -> Res
     Ok(())
 }
 
+fn write_facets<W: Write>(
+    writer: &mut EventWriter<W>,
+    facets: &[OpdsFacet],
+    url_prefix: &str,
+) -> Result<(), Error> {
+    lazy_static! {
+        static ref GROUP_NAME: Name<'static> = Name::prefixed("facetGroup", "opds");
+        static ref ACTIVE_NAME: Name<'static> = Name::prefixed("activeFacet", "opds");
+    }
+
+    for facet in facets {
+        let url = format!("{}{}", url_prefix, facet.url);
+        let event = XmlEvent::start_element("link")
+            .attr("type", LinkType::Acquisition.as_str())
+            .attr("rel", Rel::Facet.as_str())
+            .attr("href", &url)
+            .attr("title", &facet.title)
+            .attr(*GROUP_NAME, facet.group);
+        let event = if facet.active {
+            event.attr(*ACTIVE_NAME, "true")
+        } else {
+            event
+        };
+        writer.write(event)?;
+        writer.write(XmlEvent::end_element())?;
+    }
+    Ok(())
+}
+
 fn write_opds(opds: &OpdsFeed) -> Result<String, Error> {
     let raw = Vec::new();
     let mut writer = EventWriter::new(raw);
@@ -408,7 +1116,8 @@ fn write_opds(opds: &OpdsFeed) -> Result<String, Error> {
         XmlEvent::start_element("feed")
             .default_ns("http://www.w3.org/2005/Atom")
             .ns("opds", "http://opds-spec.org/2010/catalog")
-            .ns("pse", "http://vaemendis.net/opds-pse/ns"),
+            .ns("pse", "http://vaemendis.net/opds-pse/ns")
+            .ns("thr", "http://purl.org/syndication/thread/1.0"),
     )?;
 
     writer.write(XmlEvent::start_element("id"))?;
@@ -423,7 +1132,8 @@ fn write_opds(opds: &OpdsFeed) -> Result<String, Error> {
     writer.write(XmlEvent::characters(&opds.updated.to_rfc3339()))?;
     writer.write(XmlEvent::end_element())?;
 
-    write_links(&mut writer, &opds.links)?;
+    write_links(&mut writer, &opds.links, opds.url_prefix)?;
+    write_facets(&mut writer, &opds.facets, opds.url_prefix)?;
 
     for entry in &opds.entries {
         writer.write(XmlEvent::start_element("entry"))?;
@@ -452,7 +1162,7 @@ fn write_opds(opds: &OpdsFeed) -> Result<String, Error> {
         }
         writer.write(XmlEvent::end_element())?;
 
-        write_links(&mut writer, &entry.links)?;
+        write_links(&mut writer, &entry.links, opds.url_prefix)?;
 
         writer.write(XmlEvent::end_element())?; // entry
     }
@@ -460,3 +1170,126 @@ fn write_opds(opds: &OpdsFeed) -> Result<String, Error> {
     writer.write(XmlEvent::end_element())?; // feed
     Ok(String::from_utf8(writer.into_inner())?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::tests::{test_library, user};
+    use router::slug;
+
+    /// The facet links as (group, title, href), with a * on active titles
+    fn facet_links(feed: &str) -> Vec<(String, String, String)> {
+        let attr = |link: &str, name: &str| {
+            let start = link.find(&format!("{}=\"", name)).unwrap() + name.len() + 2;
+            link[start..start + link[start..].find('"').unwrap()].to_owned()
+        };
+        feed.split("<link ")
+            .filter(|link| link.contains(r#"rel="http://opds-spec.org/facet""#))
+            .map(|link| {
+                let mut title = attr(link, "title");
+                if link.contains(r#"opds:activeFacet="true""#) {
+                    title.push('*');
+                }
+                (attr(link, "opds:facetGroup"), title, attr(link, "href"))
+            })
+            .collect()
+    }
+
+    fn series(description: Option<&str>) -> Series {
+        Series {
+            id: 1,
+            name: Some("Saga".to_owned()),
+            volume: Some(1),
+            publisher: Some("Image".to_owned()),
+            start_year: Some(2012),
+            description: description.map(|d| d.to_owned()),
+            cover_issue_id: None,
+            issue_count: 3,
+            unread_count: 2,
+            updated_at: Utc::now(),
+            direction: None,
+        }
+    }
+
+    #[test]
+    fn series_description_is_escaped() {
+        let description = "Alana & <b>Marko</b>";
+        let feed = make_series_feed(
+            &Config::for_tests(),
+            "",
+            &Route::Series(1, "saga".to_owned()),
+            "Saga",
+            &[series(Some(description))],
+            |s| Route::Series(s.id, slug(s.get_name())),
+        ).unwrap();
+        // escaped once as html, then again as the xml text of the content
+        assert!(feed.contains("unread&lt;br/&gt;Alana &amp;amp; &amp;lt;b&amp;gt;Marko&amp;lt;/b"));
+        assert!(!feed.contains("<b>"));
+    }
+
+    #[test]
+    fn acquisition_feed_facets() {
+        let entries = test_library().get_all(&user(1), &Facets::default()).unwrap();
+        let facets = Facets {
+            sort: Some(Sort::Title),
+            publisher: Some("Image".to_owned()),
+            ..Facets::default()
+        };
+        let groups = [FacetGroup::Sort, FacetGroup::Publisher, FacetGroup::Year];
+        let feed = make_acquisition_feed(
+            &Config::for_tests(),
+            "/t/abc",
+            &Route::All,
+            "All",
+            &entries,
+            &facets,
+            &groups,
+        ).unwrap();
+        assert!(feed.contains(r#"rel="self" href="/t/abc/all?sort=title&amp;publisher=Image""#));
+
+        let links = facet_links(&feed);
+        let titles: Vec<(&str, &str)> =
+            links.iter().map(|l| (l.0.as_str(), l.1.as_str())).collect();
+        // Publishers and years are only the ones in the entries, newest years first
+        assert_eq!(
+            titles,
+            vec![
+                ("Sort", "Release date"),
+                ("Sort", "Recently added"),
+                ("Sort", "Series"),
+                ("Sort", "Title*"),
+                ("Publisher", "All publishers"),
+                ("Publisher", "Image*"),
+                ("Publisher", "Marvel"),
+                ("Year", "All years*"),
+                ("Year", "2014"),
+                ("Year", "2012"),
+                ("Year", "1999"),
+            ]
+        );
+        // Each link changes its own facet and keeps the others
+        assert_eq!(links[0].2, "/t/abc/all?sort=released&amp;publisher=Image");
+        assert_eq!(links[4].2, "/t/abc/all?sort=title");
+        assert_eq!(links[6].2, "/t/abc/all?sort=title&amp;publisher=Marvel");
+        assert_eq!(links[10].2, "/t/abc/all?sort=title&amp;publisher=Image&amp;year=1999");
+    }
+
+    #[test]
+    fn new_arrivals_are_plain_atom() {
+        let mut entries = test_library().get_all(&user(1), &Facets::default()).unwrap();
+        entries.truncate(1);
+        let added = "2018-05-01T12:00:00Z".parse().unwrap();
+        entries[0].added_at = Some(added);
+        entries[0].summary = Some("Love & <war>".to_owned());
+        let id = entries[0].id.unwrap();
+        let feed =
+            make_new_arrivals_feed(&Config::for_tests(), "/t/abc", &Route::AddedAtom, &entries)
+                .unwrap();
+        assert!(feed.contains(&format!("<updated>{}</updated>", added.to_rfc3339())));
+        assert!(feed.contains(&format!(r#"rel="alternate" href="/t/abc/web/comic/{}""#, id)));
+        assert!(feed.contains(r#"rel="self" href="/t/abc/added.atom""#));
+        assert!(feed.contains("&lt;p&gt;Love &amp;amp; &amp;lt;war&amp;gt;&lt;/p&gt;"));
+        // Feed readers can't do anything with acquisition links
+        assert!(!feed.contains("opds-spec.org/acquisition"));
+    }
+}
@@ -0,0 +1,410 @@
+use super::error::HttpError;
+use hyper::Method;
+use std::str::FromStr;
+use url::percent_encoding::{percent_decode, utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+/// Declares the Route enum along with recognizing and generating its URLs.
+/// Each route is `Name(param: Type, ...) => METHOD "/pattern/{param}";`
+/// where every `{param}` in the pattern must be one of the declared params.
+/// The last segment can be `{*param}`, which takes the rest of the path (possibly
+/// nothing) with the segments joined by /.
+macro_rules! routes {
+    ($($name:ident $(($($param:ident: $ty:ty),*))* => $method:ident $pattern:expr;)*) => {
+        #[derive(Debug, Clone, PartialEq)]
+        pub enum Route {
+            $($name $(($($ty),*))*,)*
+        }
+
+        impl Route {
+            /// Finds the first route matching the path, HEAD is treated as GET
+            pub fn recognize(method: &Method, path: &str) -> Result<Route, HttpError> {
+                let segments = split_path(path);
+                let mut wrong_method = false;
+                $(
+                    if let Some(params) = match_pattern($pattern, &segments) {
+                        if *method == Method::$method
+                            || (*method == Method::HEAD && Method::$method == Method::GET)
+                        {
+                            let _ = &params; // unused for routes without params
+                            return Ok(Route::$name
+                                $(($(params.get::<$ty>(stringify!($param))?),*))*);
+                        }
+                        wrong_method = true;
+                    }
+                )*
+                if wrong_method {
+                    let message = format!("{} not allowed on {}", method, path);
+                    Err(HttpError::MethodNotAllowed(message))
+                } else {
+                    Err(HttpError::NotFound(format!("Nothing at {}", path)))
+                }
+            }
+
+            /// The path for this route with every parameter percent encoded
+            pub fn url(&self) -> String {
+                match *self {
+                    $(Route::$name $(($(ref $param),*))* => {
+                        build_url(
+                            $pattern,
+                            &[$($((stringify!($param), $param.to_string())),*)*],
+                            None,
+                        )
+                    })*
+                }
+            }
+
+            /// Like url, but leaves `placeholder` unencoded in place of `param`,
+            /// for URI templates like the PSE page link
+            pub fn url_template(&self, param: &str, placeholder: &str) -> String {
+                match *self {
+                    $(Route::$name $(($(ref $param),*))* => {
+                        build_url(
+                            $pattern,
+                            &[$($((stringify!($param), $param.to_string())),*)*],
+                            Some((param, placeholder)),
+                        )
+                    })*
+                }
+            }
+        }
+    };
+}
+
+routes! {
+    Start => GET "/";
+    All => GET "/all";
+    Library(id: i64) => GET "/libraries/{id}";
+    Recent => GET "/recent";
+    Added => GET "/added";
+    AddedAtom => GET "/added.atom";
+    ContinueReading => GET "/continue";
+    OnDeck => GET "/on_deck";
+    Lists => GET "/lists";
+    List(id: i64) => GET "/lists/{id}";
+    Shelves => GET "/shelves";
+    Shelf(id: i64) => GET "/shelves/{id}";
+    Genres => GET "/genres";
+    Genre(id: i64, slug: String) => GET "/genres/{id}/{slug}";
+    Tags => GET "/tags";
+    Tag(id: i64, slug: String) => GET "/tags/{id}/{slug}";
+    Characters => GET "/characters";
+    Character(id: i64, slug: String) => GET "/characters/{id}/{slug}";
+    Teams => GET "/teams";
+    Team(id: i64, slug: String) => GET "/teams/{id}/{slug}";
+    Locations => GET "/locations";
+    Location(id: i64, slug: String) => GET "/locations/{id}/{slug}";
+    Collections => GET "/collections";
+    Collection(id: i64, slug: String) => GET "/collections/{id}/{slug}";
+    Years => GET "/years";
+    Decade(decade: i32) => GET "/years/decades/{decade}";
+    Year(year: i32) => GET "/years/{year}";
+    Folders => GET "/folders";
+    Folder(library: i64, path: String) => GET "/folders/{library}/{*path}";
+    Publishers => GET "/publishers";
+    Publisher(id: i64, slug: String) => GET "/publisher/{id}/{slug}";
+    Series(id: i64, slug: String) => GET "/series/{id}/{slug}";
+    Unread => GET "/unread";
+    UnreadForSeries(id: i64, slug: String) => GET "/series/{id}/{slug}/unread";
+    UnreadAll => GET "/unread_all";
+    RecentUnreadSeries => GET "/recent_unread_series";
+    Comic(id: i64) => GET "/comic/{id}";
+    Download(id: i64, filename: String) => GET "/comic/{id}/download/{filename}";
+    Cover(id: i64) => GET "/stream/{id}/0/cover.jpg";
+    Page(id: i64, page: i32) => GET "/stream/{id}/{page}";
+    Progress(id: i64, page: i32) => POST "/comic/{id}/progress/{page}";
+    Admin => GET "/admin";
+    AdminRescan => POST "/admin/rescan";
+    AdminSettings => POST "/admin/settings";
+    AdminDeleteUser(username: String) => POST "/admin/users/{username}/delete";
+    ApiIssues => GET "/api/v1/issues";
+    ApiIssue(id: i64) => GET "/api/v1/issues/{id}";
+    ApiMarkRead(id: i64) => PUT "/api/v1/issues/{id}/read";
+    ApiMarkUnread(id: i64) => DELETE "/api/v1/issues/{id}/read";
+    ApiProgress(id: i64) => PUT "/api/v1/issues/{id}/progress";
+    ApiSearch => GET "/api/v1/search";
+    ApiSeries => GET "/api/v1/series";
+    ApiPublishers => GET "/api/v1/publishers";
+    ApiLists => GET "/api/v1/lists";
+    ApiCreateList => POST "/api/v1/lists";
+    ApiImportList => POST "/api/v1/lists/import";
+    ApiList(id: i64) => GET "/api/v1/lists/{id}";
+    ApiUpdateList(id: i64) => PUT "/api/v1/lists/{id}";
+    ApiDeleteList(id: i64) => DELETE "/api/v1/lists/{id}";
+    ApiShelves => GET "/api/v1/shelves";
+    ApiCreateShelf => POST "/api/v1/shelves";
+    ApiShelf(id: i64) => GET "/api/v1/shelves/{id}";
+    ApiDeleteShelf(id: i64) => DELETE "/api/v1/shelves/{id}";
+    ApiShelveIssue(id: i64, issue: i64) => PUT "/api/v1/shelves/{id}/issues/{issue}";
+    ApiUnshelveIssue(id: i64, issue: i64) => DELETE "/api/v1/shelves/{id}/issues/{issue}";
+    ApiShelveSeries(id: i64, series: i64) => PUT "/api/v1/shelves/{id}/series/{series}";
+    ApiUnshelveSeries(id: i64, series: i64) => DELETE "/api/v1/shelves/{id}/series/{series}";
+    ApiCollections => GET "/api/v1/collections";
+    ApiCreateCollection => POST "/api/v1/collections";
+    ApiCollection(id: i64) => GET "/api/v1/collections/{id}";
+    ApiUpdateCollection(id: i64) => PUT "/api/v1/collections/{id}";
+    ApiDeleteCollection(id: i64) => DELETE "/api/v1/collections/{id}";
+    WebStart => GET "/web";
+    WebRecent => GET "/web/recent";
+    WebPublishers => GET "/web/publishers";
+    WebPublisher(id: i64, slug: String) => GET "/web/publisher/{id}/{slug}";
+    WebSeries(id: i64, slug: String) => GET "/web/series/{id}/{slug}";
+    WebUnread => GET "/web/unread";
+    WebUnreadForSeries(id: i64, slug: String) => GET "/web/series/{id}/{slug}/unread";
+    WebIssue(id: i64) => GET "/web/comic/{id}";
+    WebRead(id: i64) => GET "/web/read/{id}";
+    WebStatic(file: String) => GET "/web/static/{file}";
+    // From before publishers and series had ids, these redirect to the routes above.
+    // The first feeds only had the series name, and the publisher under /publishers.
+    PublisherSeriesByName(publisher: String, series: String) => GET "/publishers/{publisher}/{series}";
+    UnreadSeriesByName(series: String) => GET "/unread/{series}";
+    WebPublisherSeriesByName(publisher: String, series: String) => GET "/web/publishers/{publisher}/{series}";
+    WebUnreadSeriesByName(series: String) => GET "/web/unread/{series}";
+    PublisherByName(publisher: String) => GET "/publishers/{publisher}";
+    SeriesByName(publisher: String, series: String, volume: String) => GET "/publishers/{publisher}/{series}/{volume}";
+    UnreadForSeriesByName(publisher: String, series: String, volume: String) => GET "/unread/{publisher}/{series}/{volume}";
+    WebPublisherByName(publisher: String) => GET "/web/publishers/{publisher}";
+    WebSeriesByName(publisher: String, series: String, volume: String) => GET "/web/publishers/{publisher}/{series}/{volume}";
+    WebUnreadForSeriesByName(publisher: String, series: String, volume: String) => GET "/web/unread/{publisher}/{series}/{volume}";
+}
+
+/// The readable part of id based URLs, which is ignored when recognizing them
+pub fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in name.chars().flat_map(|c| c.to_lowercase()) {
+        if c.is_alphanumeric() {
+            slug.push(c);
+        } else if !slug.is_empty() && !slug.ends_with('-') {
+            slug.push('-');
+        }
+    }
+    let len = slug.trim_right_matches('-').len();
+    slug.truncate(len);
+    if slug.is_empty() {
+        slug.push('-');
+    }
+    slug
+}
+
+struct Params(Vec<(&'static str, String)>);
+
+impl Params {
+    fn get<T: FromStr>(&self, name: &str) -> Result<T, HttpError> {
+        let value = self.0
+            .iter()
+            .find(|p| p.0 == name)
+            .map(|p| p.1.as_str())
+            .unwrap_or_default();
+        value
+            .parse::<T>()
+            .map_err(|_| HttpError::BadRequest(format!("{} is not a valid {}", value, name)))
+    }
+}
+
+// Decode after splitting, so an encoded / stays part of its segment
+fn split_path(path: &str) -> Vec<String> {
+    let path = path.trim_left_matches('/');
+    let path = path.trim_right_matches('/');
+    if path.is_empty() {
+        return Vec::new();
+    }
+    path.split('/')
+        .map(|s| percent_decode(s.as_bytes()).decode_utf8_lossy().into_owned())
+        .collect()
+}
+
+fn pattern_segments(pattern: &'static str) -> Vec<&'static str> {
+    let pattern = pattern.trim_left_matches('/');
+    if pattern.is_empty() {
+        Vec::new()
+    } else {
+        pattern.split('/').collect()
+    }
+}
+
+fn param_name(segment: &'static str) -> Option<&'static str> {
+    if segment.starts_with('{') && segment.ends_with('}') {
+        Some(segment[1..segment.len() - 1].trim_left_matches('*'))
+    } else {
+        None
+    }
+}
+
+fn is_tail(segment: &str) -> bool {
+    segment.starts_with("{*")
+}
+
+fn match_pattern(pattern: &'static str, segments: &[String]) -> Option<Params> {
+    let mut pattern = pattern_segments(pattern);
+    let tail = match pattern.last() {
+        Some(last) if is_tail(last) => param_name(last),
+        _ => None,
+    };
+    if tail.is_some() {
+        pattern.pop();
+        if segments.len() < pattern.len() {
+            return None;
+        }
+    } else if pattern.len() != segments.len() {
+        return None;
+    }
+    let mut params = Vec::new();
+    for (p, s) in pattern.iter().zip(segments) {
+        match param_name(p) {
+            Some(name) => params.push((name, s.clone())),
+            None if p == s => (),
+            None => return None,
+        }
+    }
+    if let Some(name) = tail {
+        params.push((name, segments[pattern.len()..].join("/")));
+    }
+    Some(Params(params))
+}
+
+fn build_url(
+    pattern: &'static str,
+    params: &[(&str, String)],
+    template: Option<(&str, &str)>,
+) -> String {
+    let mut url = String::new();
+    for segment in pattern_segments(pattern) {
+        match param_name(segment) {
+            Some(name) => match template {
+                Some((param, placeholder)) if param == name => {
+                    url.push('/');
+                    url.push_str(placeholder);
+                }
+                _ => {
+                    let value = params
+                        .iter()
+                        .find(|p| p.0 == name)
+                        .map(|p| p.1.as_str())
+                        .unwrap_or_default();
+                    if is_tail(segment) {
+                        // Each part is encoded on its own so the / between them survive
+                        for part in value.split('/').filter(|p| !p.is_empty()) {
+                            url.push('/');
+                            url.extend(utf8_percent_encode(part, PATH_SEGMENT_ENCODE_SET));
+                        }
+                    } else {
+                        url.push('/');
+                        url.extend(utf8_percent_encode(value, PATH_SEGMENT_ENCODE_SET));
+                    }
+                }
+            },
+            None => {
+                url.push('/');
+                url.push_str(segment);
+            }
+        }
+    }
+    if url.is_empty() {
+        url.push('/');
+    }
+    url
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(route: Route) {
+        let url = route.url();
+        assert_eq!(Route::recognize(&Method::GET, &url).unwrap(), route, "{}", url);
+    }
+
+    #[test]
+    fn urls_are_recognized_as_the_same_route() {
+        round_trip(Route::Start);
+        round_trip(Route::Series(3, "ac-dc".to_owned()));
+        round_trip(Route::Download(7, "What If? #1.cbz".to_owned()));
+        round_trip(Route::Folder(1, "AC/DC/What If?".to_owned()));
+        round_trip(Route::Folder(1, "".to_owned()));
+        round_trip(Route::UnreadSeriesByName("AC/DC".to_owned()));
+        round_trip(Route::SeriesByName(
+            "Marvel".to_owned(),
+            "What If?".to_owned(),
+            "1977".to_owned(),
+        ));
+        assert_eq!(
+            Route::UnreadSeriesByName("AC/DC".to_owned()).url(),
+            "/unread/AC%2FDC"
+        );
+        assert_eq!(
+            Route::Folder(1, "AC/DC/What If?".to_owned()).url(),
+            "/folders/1/AC/DC/What%20If%3F"
+        );
+    }
+
+    #[test]
+    fn methods_and_bad_params() {
+        assert_eq!(
+            Route::recognize(&Method::HEAD, "/comic/1").unwrap(),
+            Route::Comic(1)
+        );
+        assert_eq!(
+            Route::recognize(&Method::PUT, "/api/v1/lists/2").unwrap(),
+            Route::ApiUpdateList(2)
+        );
+        match Route::recognize(&Method::POST, "/comic/1") {
+            Err(HttpError::MethodNotAllowed(_)) => (),
+            other => panic!("{:?}", other),
+        }
+        match Route::recognize(&Method::GET, "/comic/one") {
+            Err(HttpError::BadRequest(_)) => (),
+            other => panic!("{:?}", other),
+        }
+        match Route::recognize(&Method::GET, "/nothing/here") {
+            Err(HttpError::NotFound(_)) => (),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn legacy_routes_by_name() {
+        let recognize = |path| Route::recognize(&Method::GET, path).unwrap();
+        assert_eq!(
+            recognize("/publishers/Image/Saga"),
+            Route::PublisherSeriesByName("Image".to_owned(), "Saga".to_owned())
+        );
+        assert_eq!(
+            recognize("/unread/Ms.%20Marvel"),
+            Route::UnreadSeriesByName("Ms. Marvel".to_owned())
+        );
+        assert_eq!(
+            recognize("/publishers/Image"),
+            Route::PublisherByName("Image".to_owned())
+        );
+        assert_eq!(
+            recognize("/web/unread/Image/Saga/1"),
+            Route::WebUnreadForSeriesByName(
+                "Image".to_owned(),
+                "Saga".to_owned(),
+                "1".to_owned()
+            )
+        );
+        // The id routes live under /publisher and /series, so they don't clash
+        assert_eq!(recognize("/publisher/2/image"), Route::Publisher(2, "image".to_owned()));
+    }
+
+    #[test]
+    fn slugs() {
+        assert_eq!(slug("AC/DC"), "ac-dc");
+        assert_eq!(slug("What If? (1977)"), "what-if-1977");
+        assert_eq!(slug("  Saga  "), "saga");
+        assert_eq!(slug("?!"), "-");
+        assert_eq!(slug("Ms. Marvel"), "ms-marvel");
+    }
+
+    #[test]
+    fn url_templates() {
+        assert_eq!(
+            Route::Page(7, 0).url_template("page", "{pageNumber}"),
+            "/stream/7/{pageNumber}"
+        );
+        assert_eq!(
+            Route::Download(7, "What If?.cbz".to_owned()).url_template("id", "{id}"),
+            "/comic/{id}/download/What%20If%3F.cbz"
+        );
+    }
+}
@@ -0,0 +1,134 @@
+use super::db::DB;
+use super::LibraryConfig;
+use chrono::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// How many errors to keep per library, the log has the rest
+const MAX_ERRORS: usize = 100;
+
+/// Where a library's scan is at, for the admin pages
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanStatus {
+    pub library_id: i64,
+    pub name: String,
+    pub path: String,
+    pub running: bool,
+    pub started_at: Option<DateTime<Local>>,
+    pub finished_at: Option<DateTime<Local>>,
+    /// Comics processed by the current or last scan, unchanged ones are skipped
+    pub processed: usize,
+    pub errors: Vec<ScanError>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ScanError {
+    pub path: String,
+    pub message: String,
+}
+
+/// Scans the libraries in the background, at startup, every scan_minutes
+/// and whenever an admin asks.  A library is never scanned twice at once.
+#[derive(Clone)]
+pub struct Scanner {
+    db: Arc<DB>,
+    libraries: Arc<Vec<(i64, LibraryConfig)>>,
+    status: Arc<Mutex<Vec<ScanStatus>>>,
+}
+
+impl Scanner {
+    pub fn new(db: Arc<DB>, libraries: Vec<(i64, LibraryConfig)>) -> Scanner {
+        let status = libraries
+            .iter()
+            .map(|&(library_id, ref library)| ScanStatus {
+                library_id,
+                name: library.name.clone(),
+                path: library.path.to_string_lossy().into(),
+                running: false,
+                started_at: None,
+                finished_at: None,
+                processed: 0,
+                errors: Vec::new(),
+            })
+            .collect();
+        Scanner {
+            db,
+            libraries: Arc::new(libraries),
+            status: Arc::new(Mutex::new(status)),
+        }
+    }
+
+    /// Scans everything now, and keeps scanning the libraries with a scan_minutes
+    pub fn start(&self) {
+        for (index, &(_, ref library)) in self.libraries.iter().enumerate() {
+            self.scan(index);
+            if let Some(minutes) = library.scan_minutes {
+                let scanner = self.clone();
+                thread::spawn(move || loop {
+                    thread::sleep(Duration::from_secs(minutes * 60));
+                    scanner.scan(index);
+                });
+            }
+        }
+    }
+
+    /// Starts scanning every library that isn't being scanned already
+    pub fn rescan_all(&self) {
+        for index in 0..self.libraries.len() {
+            self.scan(index);
+        }
+    }
+
+    pub fn status(&self) -> Vec<ScanStatus> {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn scan(&self, index: usize) {
+        {
+            let mut status = self.status.lock().unwrap();
+            let status = &mut status[index];
+            if status.running {
+                return;
+            }
+            status.running = true;
+            status.started_at = Some(Local::now());
+            status.processed = 0;
+            status.errors.clear();
+        }
+
+        let scanner = self.clone();
+        thread::spawn(move || {
+            let (library_id, ref library) = scanner.libraries[index];
+            let result = super::scan_dir(&library.path, library_id, &scanner.db, &mut |path, e| {
+                let mut status = scanner.status.lock().unwrap();
+                let status = &mut status[index];
+                status.processed += 1;
+                if let Some(e) = e {
+                    push_error(status, &path.to_string_lossy(), &e.to_string());
+                }
+            });
+
+            let mut status = scanner.status.lock().unwrap();
+            let status = &mut status[index];
+            match result {
+                Err(e) => {
+                    error!("Error scanning {}: {}, {}", library.name, e, e.backtrace());
+                    push_error(status, &library.path.to_string_lossy(), &e.to_string());
+                }
+                _ => info!("Done scanning {}", library.name),
+            }
+            status.running = false;
+            status.finished_at = Some(Local::now());
+        });
+    }
+}
+
+fn push_error(status: &mut ScanStatus, path: &str, message: &str) {
+    if status.errors.len() < MAX_ERRORS {
+        status.errors.push(ScanError {
+            path: path.to_owned(),
+            message: message.to_owned(),
+        });
+    }
+}
@@ -1,39 +1,196 @@
-use super::db::DB;
-use super::opds;
-use super::Config;
+use super::admin::{AdminReport, Settings, UserSummary};
+use super::auth::{self, Sessions};
+use super::cbl::{self, CblBook};
+use super::db::{
+    CategoryKind, Collection, Facets, IssueQuery, ReadingList, Role, Series, SeriesKey, Shelf,
+    ShelfKind, Sort, User, DB,
+};
+use super::error::HttpError;
+use super::html;
+use super::opds::{self, FacetGroup};
+use super::router::{slug, Route};
+use super::scanner::Scanner;
+use super::{AuthMode, ComicInfo, Config};
+use chrono::prelude::*;
 use failure::Error;
-use futures::{future, Future};
+use futures::{future, Async, Future, Stream};
 use hyper::header;
 use hyper::service::service_fn;
 use hyper::{Body, Method, Request, Response, Server, StatusCode};
 use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::sync::Arc;
-use url::percent_encoding::percent_decode;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use url::form_urlencoded;
 
 type ResponseFuture = Box<Future<Item = Response<Body>, Error = io::Error> + Send>;
+type HttpFuture<T> = Box<Future<Item = T, Error = HttpError> + Send>;
 
-static NOTFOUND: &[u8] = b"Not Found";
+/// Everything shared between requests
+struct Context {
+    db: Arc<DB>,
+    /// Replaced when an admin changes a setting, see Config::apply_setting
+    config: RwLock<Arc<Config>>,
+    sessions: Sessions,
+    scanner: Scanner,
+}
 
-fn not_found() -> ResponseFuture {
-    Box::new(future::ok(
-        Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body(NOTFOUND.into())
-            .unwrap(),
-    ))
+impl Context {
+    fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.config.read().unwrap())
+    }
 }
 
-fn unauthorized() -> ResponseFuture {
-    Box::new(future::ok(
-        Response::builder()
-            .status(StatusCode::UNAUTHORIZED)
-            .header(header::WWW_AUTHENTICATE, r#"Basic realm="Rust OPDS""#)
-            .body("Please provide username and password.".into())
-            .unwrap(),
-    ))
+/// A request once we know who it's from
+struct Incoming {
+    /// The body has been read into body
+    req: Request<Body>,
+    body: Vec<u8>,
+    /// The path without any token prefix
+    path: String,
+    /// Has to go in front of every link we hand out, see split_token_prefix
+    url_prefix: String,
+    user: User,
+}
+
+/// Every list in the API comes a page at a time
+#[derive(Serialize)]
+struct ApiPage<T> {
+    items: Vec<T>,
+    offset: usize,
+    limit: usize,
+    /// How many there are altogether
+    total: usize,
+}
+
+impl<T> ApiPage<T> {
+    fn map<U, F: FnMut(T) -> U>(self, f: F) -> ApiPage<U> {
+        ApiPage {
+            items: self.items.into_iter().map(f).collect(),
+            offset: self.offset,
+            limit: self.limit,
+            total: self.total,
+        }
+    }
+}
+
+/// An issue along with where the requesting user is with it
+#[derive(Serialize)]
+struct ApiIssue {
+    #[serde(flatten)]
+    issue: ComicInfo,
+    filename: String,
+    read: bool,
+    /// The page they are on, if they've started it
+    progress: Option<i32>,
+    cover_url: String,
+    download_url: String,
+}
+
+impl ApiIssue {
+    fn new(url_prefix: &str, issue: ComicInfo, read: bool, progress: Option<i32>) -> ApiIssue {
+        let issue_id = issue.id.unwrap_or(0);
+        let filename = issue.get_filename();
+        ApiIssue {
+            cover_url: format!("{}{}", url_prefix, Route::Cover(issue_id).url()),
+            download_url: format!(
+                "{}{}",
+                url_prefix,
+                Route::Download(issue_id, filename.clone()).url()
+            ),
+            filename,
+            issue,
+            read,
+            progress,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiProgress {
+    page: i32,
+}
+
+/// Makes or changes a reading list, anything left out stays as it is
+#[derive(Deserialize)]
+struct ApiListChange {
+    name: Option<String>,
+    /// Every issue on the list in reading order, replacing what was there
+    issues: Option<Vec<i64>>,
+}
+
+/// A reading list with the issues on it the user can see, in order
+#[derive(Serialize)]
+struct ApiList {
+    #[serde(flatten)]
+    list: ReadingList,
+    issue_ids: Vec<i64>,
+}
+
+#[derive(Serialize)]
+struct ApiImport {
+    list: ApiList,
+    /// Books on the list we couldn't find an issue for
+    unmatched: Vec<CblBook>,
+}
+
+#[derive(Deserialize)]
+struct ApiShelfChange {
+    name: String,
+}
+
+/// A shelf with the issues and series put on it that the user can see
+#[derive(Serialize)]
+struct ApiShelf {
+    #[serde(flatten)]
+    shelf: Shelf,
+    issue_ids: Vec<i64>,
+    series_ids: Vec<i64>,
 }
 
+/// Makes or changes a collection, anything left out stays as it is
+#[derive(Deserialize)]
+struct ApiCollectionChange {
+    name: Option<String>,
+    /// Every series in the collection, replacing what was there
+    series: Option<Vec<i64>>,
+}
+
+/// A collection with the series in it the user can see
+#[derive(Serialize)]
+struct ApiCollection {
+    #[serde(flatten)]
+    collection: Collection,
+    series_ids: Vec<i64>,
+}
+
+/// How many issues the new arrivals Atom feed has
+const NEW_ARRIVALS: i64 = 50;
+const DEFAULT_LIMIT: usize = 50;
+const MAX_LIMIT: usize = 500;
+
+/// Form fields for each setting and what they are called in Config::apply_setting
+static SETTINGS: &[(&str, &str)] = &[
+    ("auth_mode", "auth.mode"),
+    ("session_minutes", "auth.session_minutes"),
+];
+const MAX_MISSING: usize = 100;
+
+// Feeds have to be revalidated every time, but a page of an issue only changes
+// if the file itself does, which changes its ETag anyway
+static FEED_CACHE_CONTROL: &str = "private, no-cache";
+static PAGE_CACHE_CONTROL: &str = "private, max-age=31536000, immutable";
+static STATIC_CACHE_CONTROL: &str = "public, max-age=3600";
+static HTML_CONTENT_TYPE: &str = "text/html; charset=utf-8";
+static ATOM_CONTENT_TYPE: &str = "application/atom+xml; charset=utf-8";
+const MAX_BODY: usize = 64 * 1024;
+/// ComicRack reading lists can run to thousands of books
+const MAX_LIST_BODY: usize = 1024 * 1024;
+
 fn parse_auth_header(auth: &str) -> Option<(String, String)> {
     use base64::decode;
     lazy_static! {
@@ -43,174 +200,1666 @@ fn parse_auth_header(auth: &str) -> Option<(String, String)> {
         let auth = caps.get(1).unwrap().as_str();
         if let Ok(auth) = decode(auth) {
             if let Ok(auth) = String::from_utf8(auth) {
-                let parts: Vec<&str> = auth.splitn(2, ':').collect();
-                return Some((parts[0].to_owned(), parts[1].to_owned()));
+                let mut parts = auth.splitn(2, ':');
+                if let (Some(username), Some(password)) = (parts.next(), parts.next()) {
+                    return Some((username.to_owned(), password.to_owned()));
+                }
             }
         }
     }
     None
 }
 
+/// FNV-1a, DefaultHasher can change between Rust releases and an upgrade
+/// shouldn't make every client fetch everything again
+struct EtagHasher(u64);
+
+impl Default for EtagHasher {
+    fn default() -> EtagHasher {
+        EtagHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for EtagHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// ETag and Last-Modified for a response, so we can answer conditional
+/// GETs without regenerating feeds or extracting pages
+struct Validator {
+    etag: String,
+    last_modified: DateTime<Utc>,
+}
+
+impl Validator {
+    /// key should be anything that changes whenever the response would
+    /// other than the modification time
+    fn new<K: Hash>(key: K, last_modified: DateTime<Utc>) -> Validator {
+        let mut hasher = EtagHasher::default();
+        key.hash(&mut hasher);
+        last_modified.timestamp().hash(&mut hasher);
+        Validator {
+            etag: format!("\"{:x}\"", hasher.finish()),
+            last_modified,
+        }
+    }
+
+    /// For feeds of issues. last_read should be passed for any feed filtered
+    /// on the user's read status
+    fn for_entries(
+        path: &str,
+        entries: &[ComicInfo],
+        last_read: Option<DateTime<Utc>>,
+    ) -> Validator {
+        // An old file copied in is new to the feed without being modified
+        let modified = entries
+            .iter()
+            .flat_map(|e| e.added_at.into_iter().chain(Some(e.modified_at.with_timezone(&Utc))))
+            .max();
+        Validator::new((path, entries.len()), latest(modified, last_read))
+    }
+
+    /// For feeds of subsections, see for_entries
+    fn for_subsections(
+        path: &str,
+        subs: &[(String, DateTime<Utc>, Route)],
+        last_read: Option<DateTime<Utc>>,
+    ) -> Validator {
+        let modified = subs.iter().map(|s| s.1).max();
+        Validator::new((path, subs.len()), latest(modified, last_read))
+    }
+
+    /// For feeds of series, which change with the user's read status
+    fn for_series(path: &str, series: &[Series], last_read: Option<DateTime<Utc>>) -> Validator {
+        let modified = series.iter().map(|s| s.updated_at).max();
+        Validator::new((path, series.len()), latest(modified, last_read))
+    }
+
+    /// For feeds with both subsections and issues, like folders
+    fn for_folder(
+        path: &str,
+        subs: &[(String, DateTime<Utc>, Route)],
+        entries: &[ComicInfo],
+    ) -> Validator {
+        let modified = subs.iter()
+            .map(|s| s.1)
+            .chain(entries.iter().map(|e| e.modified_at.with_timezone(&Utc)))
+            .max();
+        Validator::new((path, subs.len(), entries.len()), latest(modified, None))
+    }
+
+    /// For the list of shelves, whose counts change with the user's read status
+    fn for_shelves(path: &str, shelves: &[Shelf], last_read: Option<DateTime<Utc>>) -> Validator {
+        let modified = shelves.iter().map(|s| s.updated_at).max();
+        Validator::new((path, shelves.len()), latest(modified, last_read))
+    }
+
+    /// For a shelf's series and issues, last_read should include when the shelf changed
+    fn for_shelf(
+        path: &str,
+        series: &[Series],
+        entries: &[ComicInfo],
+        last_read: Option<DateTime<Utc>>,
+    ) -> Validator {
+        let modified = series.iter()
+            .map(|s| s.updated_at)
+            .chain(entries.iter().map(|e| e.modified_at.with_timezone(&Utc)))
+            .max();
+        Validator::new((path, series.len(), entries.len()), latest(modified, last_read))
+    }
+
+    /// For pages and downloads, which only change when the file does
+    fn for_issue(path: &str, issue: &ComicInfo) -> Validator {
+        Validator::new(
+            (path, issue.id, issue.size),
+            issue.modified_at.with_timezone(&Utc),
+        )
+    }
+
+    fn http_date(&self) -> String {
+        self.last_modified
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string()
+    }
+
+    /// If-None-Match wins over If-Modified-Since when both are sent
+    fn is_fresh(&self, req: &Request<Body>) -> bool {
+        if let Some(tags) = req.headers().get(header::IF_NONE_MATCH) {
+            return tags.to_str()
+                .map(|tags| {
+                    tags.split(',')
+                        .map(|t| t.trim().trim_left_matches("W/"))
+                        .any(|t| t == "*" || t == self.etag)
+                })
+                .unwrap_or(false);
+        }
+        if let Some(since) = req.headers().get(header::IF_MODIFIED_SINCE) {
+            if let Ok(since) = since.to_str() {
+                if let Ok(since) = DateTime::parse_from_rfc2822(since) {
+                    return self.last_modified.timestamp() <= since.timestamp();
+                }
+            }
+        }
+        false
+    }
+
+    /// Answers with a 304 if the client is up to date, otherwise builds the body
+    fn respond<F>(
+        &self,
+        req: &Request<Body>,
+        cache_control: &str,
+        content_type: Option<&str>,
+        body: F,
+    ) -> Result<ResponseFuture, HttpError>
+    where
+        F: FnOnce() -> Result<Body, Error>,
+    {
+        let mut response = Response::builder();
+        response
+            .header(header::ETAG, self.etag.as_str())
+            .header(header::LAST_MODIFIED, self.http_date().as_str())
+            .header(header::CACHE_CONTROL, cache_control);
+
+        if self.is_fresh(req) {
+            return Ok(Box::new(future::ok(
+                response
+                    .status(StatusCode::NOT_MODIFIED)
+                    .body(Body::empty())
+                    .unwrap(),
+            )));
+        }
+
+        if let Some(content_type) = content_type {
+            response.header(header::CONTENT_TYPE, content_type);
+        }
+        Ok(Box::new(future::ok(response.body(body()?).unwrap())))
+    }
+}
+
+fn latest(a: Option<DateTime<Utc>>, b: Option<DateTime<Utc>>) -> DateTime<Utc> {
+    // Empty feeds get the epoch, so they stay cacheable until something shows up
+    a.into_iter()
+        .chain(b)
+        .max()
+        .unwrap_or_else(|| Utc.timestamp(0, 0))
+}
+
 // TODO: figure out Stream
-fn serve_opds(req: &Request<Body>, db: &DB, config: &Config) -> ResponseFuture {
+fn serve_opds(req: Request<Body>, ctx: &Arc<Context>) -> ResponseFuture {
     debug!("Handling request {:#?}", req);
-    let user_id = match req.headers().get(header::AUTHORIZATION) {
-        None => {
-            return unauthorized();
-        }
-        Some(auth) => {
-            if let Some((username, password)) = parse_auth_header(auth.to_str().unwrap_or_default())
-            {
-                match db.check_or_provision_user(&username, &password) {
-                    Ok(0) => return unauthorized(),
-                    Ok(user_id) => user_id,
-                    _ => return unauthorized(),
+    let ctx = Arc::clone(ctx);
+    let (path_token, path) = split_token_prefix(req.uri().path());
+    let url_prefix = path_token
+        .map(|token| format!("/t/{}", token))
+        .unwrap_or_default();
+    let path = path.to_owned();
+    let path_token = path_token.map(|token| token.to_owned());
+    let body_limit = body_limit(req.method(), &path);
+
+    Box::new(
+        authenticate(&req, path_token, &ctx)
+            .and_then(move |(user, session)| {
+                let (parts, body) = req.into_parts();
+                read_body(body, body_limit).map(move |body| {
+                    let req = Request::from_parts(parts, Body::empty());
+                    (req, body, user, session)
+                })
+            })
+            .and_then(move |(req, body, user, session)| {
+                let session = session.map(|token| (token, ctx.sessions.ttl()));
+                let incoming = Incoming {
+                    req,
+                    body,
+                    path,
+                    url_prefix,
+                    user,
+                };
+                // Feeds hit the DB and pages hit the disk, so keep them off the event loop too
+                blocking(move || route(&incoming, &ctx)).map(move |response| (response, session))
+            })
+            .then(|result| -> ResponseFuture {
+                match result {
+                    Ok((response, session)) => Box::new(response.map(move |mut response| {
+                        if let Some((token, ttl)) = session {
+                            let cookie = format!(
+                                "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Lax",
+                                auth::SESSION_COOKIE,
+                                token,
+                                ttl.as_secs()
+                            );
+                            if let Ok(cookie) = header::HeaderValue::from_str(&cookie) {
+                                response.headers_mut().insert(header::SET_COOKIE, cookie);
+                            }
+                        }
+                        response
+                    })),
+                    Err(e) => Box::new(future::ok(e.into_response())),
                 }
-            } else {
-                return unauthorized();
-            }
+            }),
+    )
+}
+
+/// Reading lists can be long, everything else we take is a form or a bit of JSON
+fn body_limit(method: &Method, path: &str) -> usize {
+    match Route::recognize(method, path) {
+        Ok(Route::ApiImportList) => MAX_LIST_BODY,
+        _ => MAX_BODY,
+    }
+}
+
+/// Collects the request body, we only expect forms, JSON and reading lists so there is a limit
+fn read_body(body: Body, limit: usize) -> HttpFuture<Vec<u8>> {
+    Box::new(
+        body.map_err(|e| HttpError::Internal(e.into()))
+            .fold(Vec::new(), move |mut buf, chunk| {
+                if buf.len() + chunk.len() > limit {
+                    return Err(HttpError::BadRequest("Request body is too large".into()));
+                }
+                buf.extend_from_slice(&chunk);
+                Ok(buf)
+            }),
+    )
+}
+
+/// Clients that can't send an Authorization header can put an API token
+/// at the start of the path, as in /t/{token}/unread
+fn split_token_prefix(path: &str) -> (Option<&str>, &str) {
+    if !path.starts_with("/t/") {
+        return (None, path);
+    }
+    let rest = &path[3..];
+    match rest.find('/') {
+        Some(i) => (Some(&rest[..i]), &rest[i..]),
+        None => (Some(rest), "/"),
+    }
+}
+
+/// Runs f on the thread pool's blocking threads, or inline if we aren't on the pool
+fn blocking<F, T>(f: F) -> HttpFuture<T>
+where
+    F: FnOnce() -> Result<T, HttpError> + Send + 'static,
+    T: Send + 'static,
+{
+    let mut f = Some(f);
+    Box::new(future::poll_fn(move || {
+        match ::tokio_threadpool::blocking(|| (f.take().unwrap())()) {
+            Ok(Async::Ready(result)) => result.map(Async::Ready),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(_) => (f.take().unwrap())().map(Async::Ready),
+        }
+    }))
+}
+
+/// Resolves to the user, and a new session token if they just logged in with a password
+fn authenticate(
+    req: &Request<Body>,
+    path_token: Option<String>,
+    ctx: &Arc<Context>,
+) -> HttpFuture<(User, Option<String>)> {
+    let session = req.headers()
+        .get(header::COOKIE)
+        .and_then(|cookies| cookies.to_str().ok())
+        .and_then(auth::parse_session_cookie)
+        .and_then(|token| ctx.sessions.get_session(token))
+        .and_then(|user| still_current(user, ctx));
+    if let Some(user) = session {
+        return Box::new(future::ok((user, None)));
+    }
+
+    let header = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|auth| auth.to_str().ok())
+        .map(|auth| auth.to_owned());
+    let authorization = match (path_token, header) {
+        (Some(token), _) => format!("Bearer {}", token),
+        (None, Some(authorization)) => authorization,
+        (None, None) if ctx.config().auth.mode == AuthMode::Anonymous => {
+            return Box::new(future::ok((User::anonymous(), None)))
         }
+        (None, None) => return Box::new(future::err(HttpError::Unauthorized)),
     };
 
-    // Why doesn't hyper do this for me?
-    let path = &percent_decode(req.uri().path().as_bytes()).decode_utf8_lossy();
-    let mut path_parts = path.split('/');
-    path_parts.next(); // first part is always empty
-
-    match (req.method(), path_parts.next()) {
-        (&Method::GET, None) | (&Method::GET, Some("")) => {
-            let body = Body::from(opds::make_navigation_feed(config).unwrap());
-            Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("all")) => {
-            let entries = db.get_all().unwrap();
-            let body = Body::from(
-                opds::make_acquisition_feed(config, "/all", "All Comics", &entries).unwrap(),
-            );
-            Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("recent")) => {
-            let entries = db.get_recent().unwrap();
-            let body = Body::from(
-                opds::make_acquisition_feed(config, "/recent", "Recent Comics", &entries).unwrap(),
-            );
-            Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("publishers")) => {
-            let body = match path_parts.next() {
-                Some(publisher) => match path_parts.next() {
-                    Some(series) => {
-                        let entries = db.get_for_publisher_series(&publisher, &series).unwrap();
-                        Body::from(
-                            opds::make_acquisition_feed(config, path, series, &entries).unwrap(),
-                        )
-                    }
-                    None => {
-                        let mut entries = db.get_series_for_publisher(&publisher).unwrap();
-                        Body::from(
-                            opds::make_subsection_feed(config, path, publisher, &mut entries)
-                                .unwrap(),
-                        )
+    let cached = ctx.sessions
+        .get_cached_credentials(&authorization)
+        .and_then(|user| still_current(user, ctx));
+    if let Some(user) = cached {
+        return Box::new(future::ok((user, None)));
+    }
+
+    let credentials = if authorization.starts_with("Bearer ") {
+        Credentials::Token(authorization["Bearer ".len()..].trim().to_owned())
+    } else {
+        match parse_auth_header(&authorization) {
+            Some((username, password)) => Credentials::Password(username, password),
+            None => return Box::new(future::err(HttpError::Unauthorized)),
+        }
+    };
+
+    // Argon2 is deliberately slow, don't hold up the event loop with it
+    let ctx = Arc::clone(ctx);
+    blocking(move || {
+        let (user, session) = match credentials {
+            Credentials::Token(token) => (ctx.db.check_token(&token)?, false),
+            Credentials::Password(username, password) => {
+                // Clients that only do Basic auth can use a token as the password
+                match ctx.db.check_token(&password)? {
+                    Some(ref user) if user.username == username => (Some(user.clone()), false),
+                    _ => {
+                        let user = match ctx.config().auth.mode {
+                            AuthMode::Open => ctx.db.check_or_provision_user(&username, &password)?,
+                            _ => ctx.db.check_user(&username, &password)?,
+                        };
+                        (user, true)
                     }
+                }
+            }
+        };
+        let user = user.ok_or(HttpError::Unauthorized)?;
+        // API tokens are for apps and scripts, there is no point handing them cookies.
+        // They aren't cached either, so revoking one takes effect on the next request.
+        let token = if session {
+            ctx.sessions.cache_credentials(&authorization, &user);
+            Some(ctx.sessions.start_session(&user))
+        } else {
+            None
+        };
+        Ok((user, token))
+    })
+}
+
+/// Drops every cached login for the user once opds_users (or anything else) has
+/// changed them, so they have to log in again and pick up the change
+fn still_current(user: User, ctx: &Context) -> Option<User> {
+    match ctx.db.get_user_generation(user.id) {
+        Ok(Some(generation)) if generation == user.generation => Some(user),
+        _ => {
+            ctx.sessions.forget_user(user.id);
+            None
+        }
+    }
+}
+
+enum Credentials {
+    Token(String),
+    Password(String, String),
+}
+
+fn route(incoming: &Incoming, ctx: &Context) -> Result<ResponseFuture, HttpError> {
+    let db = &*ctx.db;
+    let config = ctx.config();
+    let config = &*config;
+    let user = &incoming.user;
+    let route = Route::recognize(incoming.req.method(), &incoming.path)?;
+    let method = incoming.req.method();
+    if *method != Method::GET && *method != Method::HEAD {
+        check_same_origin(&incoming.req)?;
+    }
+
+    match route {
+        Route::Start if wants_html(&incoming.req) => {
+            // Browsers get the web UI instead of a wall of XML
+            let location = format!("{}{}", incoming.url_prefix, Route::WebStart.url());
+            Ok(Box::new(future::ok(
+                Response::builder()
+                    .status(StatusCode::SEE_OTHER)
+                    .header(header::LOCATION, location.as_str())
+                    .body(Body::empty())
+                    .unwrap(),
+            )))
+        }
+        Route::Start => {
+            let libraries = db.get_libraries(user)?;
+            let body = Body::from(opds::make_navigation_feed(
+                config,
+                &incoming.url_prefix,
+                &libraries,
+            )?);
+            Ok(Box::new(future::ok(Response::new(body))))
+        }
+        Route::All => {
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_all(user, &facets)?;
+            acquisition_feed(incoming, config, &route, "All Comics", &entries, &facets, None)
+        }
+        Route::Library(library_id) => {
+            let library = db.get_libraries(user)?
+                .into_iter()
+                .find(|l| l.id == library_id)
+                .ok_or_else(|| HttpError::NotFound(format!("No such library {}", library_id)))?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_for_library(user, library_id, &facets)?;
+            acquisition_feed(incoming, config, &route, &library.name, &entries, &facets, None)
+        }
+        Route::Recent => {
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_recent(user, &facets)?;
+            acquisition_feed(incoming, config, &route, "Recent Comics", &entries, &facets, None)
+        }
+        Route::Added => {
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_added(user, &facets)?;
+            acquisition_feed(incoming, config, &route, "Recently Added", &entries, &facets, None)
+        }
+        Route::ContinueReading => {
+            let last_read = last_activity(db, user)?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_continue_reading(user, &facets)?;
+            let title = "Continue Reading";
+            acquisition_feed(incoming, config, &route, title, &entries, &facets, last_read)
+        }
+        Route::OnDeck => {
+            let last_read = last_activity(db, user)?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_on_deck(user, &facets)?;
+            acquisition_feed(incoming, config, &route, "On Deck", &entries, &facets, last_read)
+        }
+        Route::Lists => {
+            let lists = db.get_lists(user)?;
+            let subs: Vec<(String, DateTime<Utc>, Route)> = lists
+                .iter()
+                .map(|l| (l.name.clone(), l.updated_at, Route::List(l.id)))
+                .collect();
+            subsection_feed(incoming, config, &route, "Reading lists", &subs, None)
+        }
+        Route::List(list_id) => {
+            let list = db.get_list(user, list_id)?;
+            let last_read = Some(latest(Some(list.updated_at), db.get_last_read_at(user.id)?));
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_for_list(user, list_id, &facets)?;
+            acquisition_feed(incoming, config, &route, &list.name, &entries, &facets, last_read)
+        }
+        Route::Shelves => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let shelves = db.get_shelves(user)?;
+            let url = format!("{}{}", incoming.url_prefix, route.url());
+            Validator::for_shelves(&url, &shelves, last_read).respond(
+                &incoming.req,
+                FEED_CACHE_CONTROL,
+                None,
+                || {
+                    Ok(Body::from(opds::make_shelves_feed(
+                        config,
+                        &incoming.url_prefix,
+                        &route,
+                        &shelves,
+                        |s| Route::Shelf(s.id),
+                    )?))
+                },
+            )
+        }
+        Route::Shelf(shelf_id) => {
+            let shelf = db.get_shelf(user, shelf_id)?;
+            let last_read = Some(latest(Some(shelf.updated_at), db.get_last_read_at(user.id)?));
+            let series = db.get_series_for_shelf(user, shelf_id)?;
+            let entries = db.get_for_shelf(user, shelf_id)?;
+            let url = format!("{}{}", incoming.url_prefix, route.url());
+            Validator::for_shelf(&url, &series, &entries, last_read).respond(
+                &incoming.req,
+                FEED_CACHE_CONTROL,
+                None,
+                || {
+                    Ok(Body::from(opds::make_shelf_feed(
+                        config,
+                        &incoming.url_prefix,
+                        &route,
+                        &shelf.name,
+                        &series,
+                        &entries,
+                        |s| Route::Series(s.id, slug(&s.get_title())),
+                    )?))
+                },
+            )
+        }
+        Route::Genres | Route::Tags | Route::Characters | Route::Teams | Route::Locations => {
+            let (kind, title) = match route {
+                Route::Genres => (CategoryKind::Genre, "Comics by genre"),
+                Route::Tags => (CategoryKind::Tag, "Comics by tag"),
+                Route::Characters => (CategoryKind::Character, "Comics by character"),
+                Route::Teams => (CategoryKind::Team, "Comics by team"),
+                _ => (CategoryKind::Location, "Comics by location"),
+            };
+            let categories = db.get_categories(user, kind)?;
+            let subs: Vec<(String, DateTime<Utc>, Route)> = categories
+                .iter()
+                .map(|c| (c.name.clone(), c.updated_at, category_route(kind, c.id, &c.name)))
+                .collect();
+            subsection_feed(incoming, config, &route, title, &subs, None)
+        }
+        Route::Genre(id, _)
+        | Route::Tag(id, _)
+        | Route::Character(id, _)
+        | Route::Team(id, _)
+        | Route::Location(id, _) => {
+            let kind = match route {
+                Route::Genre(..) => CategoryKind::Genre,
+                Route::Tag(..) => CategoryKind::Tag,
+                Route::Character(..) => CategoryKind::Character,
+                Route::Team(..) => CategoryKind::Team,
+                _ => CategoryKind::Location,
+            };
+            let category = db.get_category(user, kind, id)?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_for_category(user, id, &facets)?;
+            acquisition_feed(incoming, config, &route, &category.name, &entries, &facets, None)
+        }
+        Route::Collections => {
+            let collections = db.get_collections()?;
+            let subs: Vec<(String, DateTime<Utc>, Route)> = collections
+                .iter()
+                .map(|c| (c.name.clone(), c.updated_at, Route::Collection(c.id, slug(&c.name))))
+                .collect();
+            subsection_feed(incoming, config, &route, "Collections", &subs, None)
+        }
+        Route::Collection(id, _) => {
+            let collection = db.get_collection(id)?;
+            let last_read = db.get_last_read_at(user.id)?;
+            let last_read = Some(latest(Some(collection.updated_at), last_read));
+            let series = db.get_series_for_collection(user, id)?;
+            series_feed(incoming, config, &route, &collection.name, &series, last_read, |s| {
+                Route::Series(s.id, slug(&s.get_title()))
+            })
+        }
+        Route::Years => {
+            let mut decades: BTreeMap<i32, DateTime<Utc>> = BTreeMap::new();
+            for year in db.get_years(user)? {
+                let updated_at = decades.entry(year.year / 10 * 10).or_insert(year.updated_at);
+                *updated_at = (*updated_at).max(year.updated_at);
+            }
+            let subs: Vec<(String, DateTime<Utc>, Route)> = decades
+                .iter()
+                .map(|(&decade, &updated_at)| {
+                    (format!("{}s", decade), updated_at, Route::Decade(decade))
+                })
+                .collect();
+            subsection_feed(incoming, config, &route, "Comics by decade", &subs, None)
+        }
+        Route::Decade(decade) => {
+            let subs: Vec<(String, DateTime<Utc>, Route)> = db.get_years(user)?
+                .into_iter()
+                .filter(|y| y.year / 10 * 10 == decade)
+                .map(|y| (y.year.to_string(), y.updated_at, Route::Year(y.year)))
+                .collect();
+            if subs.is_empty() {
+                return Err(HttpError::NotFound(format!("Nothing from the {}s", decade)));
+            }
+            let title = format!("Comics from the {}s", decade);
+            subsection_feed(incoming, config, &route, &title, &subs, None)
+        }
+        Route::Year(year) => {
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_for_year(user, year, &facets)?;
+            let title = format!("Comics from {}", year);
+            acquisition_feed(incoming, config, &route, &title, &entries, &facets, None)
+        }
+        Route::AddedAtom => {
+            let query = IssueQuery::new(user).sort(Sort::Added).page(0, NEW_ARRIVALS);
+            let entries = db.query_issues(&query)?;
+            let url = format!("{}{}", incoming.url_prefix, route.url());
+            Validator::for_entries(&url, &entries, None).respond(
+                &incoming.req,
+                FEED_CACHE_CONTROL,
+                Some(ATOM_CONTENT_TYPE),
+                || {
+                    Ok(Body::from(opds::make_new_arrivals_feed(
+                        config,
+                        &incoming.url_prefix,
+                        &route,
+                        &entries,
+                    )?))
                 },
-                None => {
-                    let mut entries = db.get_publishers().unwrap();
-                    Body::from(
-                        opds::make_subsection_feed(
-                            config,
-                            "/publishers",
-                            "Comics by publisher",
-                            &mut entries,
-                        ).unwrap(),
-                    )
+            )
+        }
+        Route::Folders => {
+            let libraries = db.get_libraries(user)?;
+            let subs: Vec<(String, DateTime<Utc>, Route)> = libraries
+                .iter()
+                .map(|l| (l.name.clone(), l.updated_at, Route::Folder(l.id, String::new())))
+                .collect();
+            subsection_feed(incoming, config, &route, "Comics by folder", &subs, None)
+        }
+        Route::Folder(library_id, ref folder) => {
+            let library = db.get_libraries(user)?
+                .into_iter()
+                .find(|l| l.id == library_id)
+                .ok_or_else(|| HttpError::NotFound(format!("No such library {}", library_id)))?;
+            let folders: Vec<(String, DateTime<Utc>, Route)> = db
+                .get_subfolders(user, library_id, folder)?
+                .into_iter()
+                .map(|(sub, updated_at)| {
+                    let route = Route::Folder(library_id, format!("{}/{}", folder, sub));
+                    (sub, updated_at, route)
+                })
+                .collect();
+            let entries = db.get_for_folder(user, library_id, folder)?;
+            let title = folder
+                .rsplit('/')
+                .find(|p| !p.is_empty())
+                .unwrap_or(&library.name);
+            let url = format!("{}{}", incoming.url_prefix, route.url());
+            Validator::for_folder(&url, &folders, &entries).respond(
+                &incoming.req,
+                FEED_CACHE_CONTROL,
+                None,
+                || {
+                    Ok(Body::from(opds::make_folder_feed(
+                        config,
+                        &incoming.url_prefix,
+                        &route,
+                        title,
+                        &folders,
+                        &entries,
+                    )?))
+                },
+            )
+        }
+        Route::Publishers => {
+            let publishers = db.get_publishers(user)?;
+            let subs: Vec<(String, DateTime<Utc>, Route)> = publishers
+                .iter()
+                .map(|p| {
+                    let route = Route::Publisher(p.id, slug(p.get_name()));
+                    (p.get_name().to_owned(), p.updated_at, route)
+                })
+                .collect();
+            subsection_feed(incoming, config, &route, "Comics by publisher", &subs, None)
+        }
+        Route::Publisher(id, _) => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let publisher = db.get_publisher(user, id)?;
+            let series = db.get_series_for_publisher(user, &publisher.name)?;
+            let title = publisher.get_name();
+            series_feed(incoming, config, &route, title, &series, last_read, |s| {
+                Route::Series(s.id, slug(&s.get_title()))
+            })
+        }
+        Route::Series(id, _) => {
+            let series = db.get_series_by_id(user, id)?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_for_series(user, &series.key(), &facets)?;
+            let title = series.get_title();
+            acquisition_feed(incoming, config, &route, &title, &entries, &facets, None)
+        }
+        Route::Unread => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let series = db.get_unread_series(user)?;
+            series_feed(
+                incoming,
+                config,
+                &route,
+                "Unread comics by series",
+                &series,
+                last_read,
+                unread_series_route,
+            )
+        }
+        Route::UnreadForSeries(id, _) => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let series = db.get_series_by_id(user, id)?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_unread_for_series(user, &series.key(), &facets)?;
+            let title = series.get_title();
+            acquisition_feed(incoming, config, &route, &title, &entries, &facets, last_read)
+        }
+        Route::UnreadAll => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let facets = facets(&incoming.req)?;
+            let entries = db.get_unread(user, &facets)?;
+            let title = "Unread Comics";
+            acquisition_feed(incoming, config, &route, title, &entries, &facets, last_read)
+        }
+        Route::RecentUnreadSeries => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let series = db.get_recent_unread_series(user)?;
+            series_feed(
+                incoming,
+                config,
+                &route,
+                "Recent unread series",
+                &series,
+                last_read,
+                unread_series_route,
+            )
+        }
+        Route::Comic(id) | Route::Download(id, _) => {
+            let entry = db.get(user, id)?;
+            // Downloading it again still counts as reading it, even from the cache
+            if !user.read_only {
+                db.mark_read(id, user.id)?;
+            }
+            let validator = Validator::for_issue("/comic", &entry);
+            if validator.is_fresh(&incoming.req) {
+                return validator.respond(&incoming.req, PAGE_CACHE_CONTROL, None, || {
+                    Ok(Body::empty())
+                });
+            }
+            Ok(simple_file_send(&entry.filepath, validator))
+        }
+        Route::Cover(issue_id) => send_page(incoming, db, issue_id, 0),
+        Route::Page(issue_id, page_id) => send_page(incoming, db, issue_id, page_id),
+        Route::Progress(issue_id, page_id) => {
+            if user.read_only {
+                return Err(HttpError::Forbidden(
+                    "Progress isn't saved for read only users".into(),
+                ));
+            }
+            db.get(user, issue_id)?; // Not found for issues they can't see
+            db.set_progress(user.id, issue_id, page_id)?;
+            no_content()
+        }
+        Route::Admin => {
+            require_admin(user, false)?;
+            let missing: Vec<String> = db.get_filepaths()?
+                .into_iter()
+                .filter(|p| !Path::new(p).exists())
+                .collect();
+            let report = AdminReport {
+                scans: ctx.scanner.status(),
+                stats: db.get_stats()?,
+                missing_count: missing.len(),
+                missing_files: missing.into_iter().take(MAX_MISSING).collect(),
+                users: db.list_users()?
+                    .into_iter()
+                    .map(|(username, role)| UserSummary {
+                        username,
+                        role: role.as_str(),
+                    })
+                    .collect(),
+                settings: Settings {
+                    auth_mode: config.auth.mode,
+                    session_minutes: config.auth.session_minutes,
+                },
+            };
+            if wants_json(&incoming.req) {
+                json_response(&report)
+            } else {
+                html_response(html::make_admin_page(&incoming.url_prefix, &report))
+            }
+        }
+        Route::AdminRescan => {
+            require_admin(user, true)?;
+            ctx.scanner.rescan_all();
+            admin_done(incoming)
+        }
+        Route::AdminSettings => {
+            require_admin(user, true)?;
+            let form = parse_form(&incoming.body);
+            let mut new_config = config.clone();
+            let mut changed = Vec::new();
+            for &(field, name) in SETTINGS {
+                if let Some(value) = form.get(field) {
+                    new_config
+                        .apply_setting(name, value)
+                        .map_err(|e| HttpError::BadRequest(e.to_string()))?;
+                    changed.push((name, value));
+                }
+            }
+            // Only save once we know they are all good
+            for (name, value) in changed {
+                db.set_setting(name, value)?;
+            }
+            ctx.sessions
+                .set_ttl(Duration::from_secs(new_config.auth.session_minutes * 60));
+            *ctx.config.write().unwrap() = Arc::new(new_config);
+            admin_done(incoming)
+        }
+        Route::AdminDeleteUser(ref username) => {
+            require_admin(user, true)?;
+            if *username == user.username {
+                return Err(HttpError::BadRequest("You can't delete yourself".into()));
+            }
+            let user_id = db.get_user(username)?;
+            db.delete_user(username)?;
+            ctx.sessions.forget_user(user_id);
+            admin_done(incoming)
+        }
+        Route::ApiIssues | Route::ApiSearch => {
+            let params = query_params(&incoming.req);
+            let mut query = IssueQuery::new(user).sort(Sort::Series);
+            match (params.get("q"), &route) {
+                (Some(q), _) => query = query.search(q),
+                (None, &Route::ApiSearch) => {
+                    return Err(HttpError::BadRequest("Search needs a q".into()))
+                }
+                (None, _) => (),
+            }
+            if let Some(publisher) = params.get("publisher") {
+                query = query.publisher(none_param(publisher));
+            }
+            if let Some(series) = params.get("series") {
+                query = query.series_name(none_param(series));
+            }
+            if let Some(id) = params.get("list") {
+                query = query.list(id.parse::<i64>()
+                    .map_err(|_| HttpError::BadRequest(format!("{} is not a list", id)))?);
+            }
+            if let Some(year) = params.get("year") {
+                query = query.year(year.parse::<i32>()
+                    .map_err(|_| HttpError::BadRequest(format!("{} is not a year", year)))?);
+            }
+            if let Some(id) = params.get("library") {
+                query = query.library(id.parse::<i64>()
+                    .map_err(|_| HttpError::BadRequest(format!("{} is not a library", id)))?);
+            }
+            match params.get("read").map(|r| r.as_str()) {
+                Some("true") => query = query.read(true),
+                Some("false") => query = query.read(false),
+                Some(r) => return Err(HttpError::BadRequest(format!("read can't be {}", r))),
+                None => (),
+            }
+            let (offset, limit) = page_params(&params)?;
+            let total = db.count_issues(&query)? as usize;
+            let entries = db.query_issues(&query.page(offset as i64, limit as i64))?;
+            let read = db.get_read_ids(user.id)?;
+            let progress = db.get_all_progress(user.id)?;
+            let page = ApiPage {
+                items: entries,
+                offset,
+                limit,
+                total,
+            };
+            json_response(&page.map(|e| {
+                let issue_id = e.id.unwrap_or(0);
+                let is_read = read.contains(&issue_id);
+                ApiIssue::new(&incoming.url_prefix, e, is_read, progress.get(&issue_id).cloned())
+            }))
+        }
+        Route::ApiIssue(issue_id) => {
+            let entry = db.get(user, issue_id)?;
+            let read = db.get_read_ids(user.id)?.contains(&issue_id);
+            let progress = db.get_progress(user.id, issue_id)?;
+            json_response(&ApiIssue::new(&incoming.url_prefix, entry, read, progress))
+        }
+        Route::ApiMarkRead(issue_id) | Route::ApiMarkUnread(issue_id) => {
+            if user.read_only {
+                return Err(HttpError::Forbidden("Read only users can't change read status".into()));
+            }
+            db.get(user, issue_id)?;
+            if let Route::ApiMarkRead(_) = route {
+                db.mark_read(issue_id, user.id)?;
+            } else {
+                db.mark_unread(issue_id, user.id)?;
+            }
+            no_content()
+        }
+        Route::ApiProgress(issue_id) => {
+            if user.read_only {
+                return Err(HttpError::Forbidden("Progress isn't saved for read only users".into()));
+            }
+            let progress: ApiProgress = ::serde_json::from_slice(&incoming.body)
+                .map_err(|e| HttpError::BadRequest(format!("Expected {{\"page\": n}}: {}", e)))?;
+            db.get(user, issue_id)?;
+            db.set_progress(user.id, issue_id, progress.page)?;
+            no_content()
+        }
+        Route::ApiSeries => {
+            let params = query_params(&incoming.req);
+            let series = match params.get("publisher") {
+                Some(id) => {
+                    let id = id.parse::<i64>()
+                        .map_err(|_| HttpError::BadRequest(format!("{} is not a publisher", id)))?;
+                    let publisher = db.get_publisher(user, id)?;
+                    db.get_series_for_publisher(user, &publisher.name)?
+                }
+                None => db.get_series(user)?,
+            };
+            json_response(&paginate(&params, series)?)
+        }
+        Route::ApiPublishers => {
+            let params = query_params(&incoming.req);
+            let publishers = db.get_publishers(user)?;
+            json_response(&paginate(&params, publishers)?)
+        }
+        Route::ApiLists => {
+            let params = query_params(&incoming.req);
+            json_response(&paginate(&params, db.get_lists(user)?)?)
+        }
+        Route::ApiList(list_id) => json_response(&api_list(db, user, list_id)?),
+        Route::ApiCreateList | Route::ApiUpdateList(_) => {
+            if user.read_only {
+                return Err(HttpError::Forbidden("Read only users can't change lists".into()));
+            }
+            let change: ApiListChange = ::serde_json::from_slice(&incoming.body)
+                .map_err(|e| HttpError::BadRequest(format!("Expected a name and issues: {}", e)))?;
+            let list_id = match route {
+                Route::ApiUpdateList(list_id) => Some(editable_list(db, user, list_id)?.id),
+                _ => None,
+            };
+            if let Some(ref name) = change.name {
+                match db.find_list(user, name)? {
+                    Some(ref other) if Some(other.id) != list_id => {
+                        let message = format!("There's already a list called {}", name);
+                        return Err(HttpError::BadRequest(message));
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(ref issues) = change.issues {
+                for &issue_id in issues {
+                    db.get(user, issue_id)?; // Not found for issues they can't see
                 }
+            }
+            let list_id = match (list_id, change.name) {
+                (Some(list_id), name) => {
+                    let issues = change.issues.as_ref().map(|issues| &issues[..]);
+                    db.update_list(list_id, name.as_ref().map(|name| &name[..]), issues)?;
+                    list_id
+                }
+                (None, Some(name)) => {
+                    db.create_list(user.id, &name, &change.issues.unwrap_or_default())?
+                }
+                (None, None) => return Err(HttpError::BadRequest("A list needs a name".into())),
             };
-            Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("unread")) => {
-            let body = match path_parts.next() {
-                Some(series) => {
-                    let mut entries = db.get_unread_for_series(user_id, &series).unwrap();
-                    Body::from(opds::make_acquisition_feed(config, path, series, &entries).unwrap())
-                }
-                None => {
-                    let mut entries = db.get_unread_series(user_id).unwrap();
-                    Body::from(
-                        opds::make_subsection_feed(
-                            config,
-                            "/unread",
-                            "Unread comics by series",
-                            &mut entries,
-                        ).unwrap(),
-                    )
+            json_response(&api_list(db, user, list_id)?)
+        }
+        Route::ApiDeleteList(list_id) => {
+            editable_list(db, user, list_id)?;
+            db.delete_list(list_id)?;
+            no_content()
+        }
+        Route::ApiImportList => {
+            if user.read_only {
+                return Err(HttpError::Forbidden("Read only users can't change lists".into()));
+            }
+            let list = ::std::str::from_utf8(&incoming.body)
+                .map_err(|e| e.into())
+                .and_then(cbl::parse)
+                .map_err(|e| HttpError::BadRequest(format!("Expected a .cbl file: {}", e)))?;
+            if let Some(existing) = db.find_list(user, &list.name)? {
+                editable_list(db, user, existing.id)?;
+            }
+            let (list_id, unmatched) = db.import_list(user.id, &list)?;
+            json_response(&ApiImport {
+                list: api_list(db, user, list_id)?,
+                unmatched,
+            })
+        }
+        Route::ApiShelves => {
+            let params = query_params(&incoming.req);
+            json_response(&paginate(&params, db.get_shelves(user)?)?)
+        }
+        Route::ApiShelf(shelf_id) => json_response(&api_shelf(db, user, shelf_id)?),
+        Route::ApiCreateShelf => {
+            if user.read_only {
+                return Err(HttpError::Forbidden("Read only users can't change shelves".into()));
+            }
+            let change: ApiShelfChange = ::serde_json::from_slice(&incoming.body)
+                .map_err(|e| HttpError::BadRequest(format!("Expected {{\"name\": ...}}: {}", e)))?;
+            if db.find_shelf(user, &change.name)?.is_some() {
+                let message = format!("You already have a shelf called {}", change.name);
+                return Err(HttpError::BadRequest(message));
+            }
+            let shelf_id = db.create_shelf(user.id, &change.name)?;
+            json_response(&api_shelf(db, user, shelf_id)?)
+        }
+        Route::ApiDeleteShelf(shelf_id) => {
+            let shelf = editable_shelf(db, user, shelf_id)?;
+            if shelf.kind != ShelfKind::Custom {
+                let message = format!("{} is built in, it can only be emptied", shelf.name);
+                return Err(HttpError::BadRequest(message));
+            }
+            db.delete_shelf(shelf_id)?;
+            no_content()
+        }
+        Route::ApiShelveIssue(shelf_id, issue_id) | Route::ApiUnshelveIssue(shelf_id, issue_id) => {
+            editable_shelf(db, user, shelf_id)?;
+            db.get(user, issue_id)?;
+            let shelved = route == Route::ApiShelveIssue(shelf_id, issue_id);
+            db.shelve_issue(shelf_id, issue_id, shelved)?;
+            no_content()
+        }
+        Route::ApiShelveSeries(shelf_id, series_id)
+        | Route::ApiUnshelveSeries(shelf_id, series_id) => {
+            editable_shelf(db, user, shelf_id)?;
+            db.get_series_by_id(user, series_id)?;
+            let shelved = route == Route::ApiShelveSeries(shelf_id, series_id);
+            db.shelve_series(shelf_id, series_id, shelved)?;
+            no_content()
+        }
+        Route::ApiCollections => {
+            let params = query_params(&incoming.req);
+            json_response(&paginate(&params, db.get_collections()?)?)
+        }
+        Route::ApiCollection(collection_id) => {
+            json_response(&api_collection(db, user, collection_id)?)
+        }
+        Route::ApiCreateCollection | Route::ApiUpdateCollection(_) => {
+            require_admin(user, true)?;
+            let change: ApiCollectionChange = ::serde_json::from_slice(&incoming.body)
+                .map_err(|e| HttpError::BadRequest(format!("Expected a name and series: {}", e)))?;
+            let collection_id = match route {
+                Route::ApiUpdateCollection(collection_id) => {
+                    Some(db.get_collection(collection_id)?.id)
                 }
+                _ => None,
             };
-            Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("unread_all")) => {
-            let entries = db.get_unread(user_id).unwrap();
-            let body = Body::from(
-                opds::make_acquisition_feed(config, "/unread", "Unread Comics", &entries).unwrap(),
-            );
-            Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("recent_unread_series")) => {
-            let mut entries = db.get_recent_unread_series(user_id).unwrap();
-            let body = Body::from(
-                opds::make_subsection_feed(
-                    config,
-                    "/unread",
-                    "Recent unread series",
-                    &mut entries,
-                    ).unwrap(),
-                    );
-                Box::new(future::ok(Response::new(body)))
-        }
-        (&Method::GET, Some("comic")) => match path_parts.next() {
-            Some(id) => {
-                let id = id.parse::<i64>().unwrap();
-                let entry = db.get(id).unwrap();
-                db.mark_read(id, user_id).unwrap();
-                simple_file_send(&entry.filepath)
-            }
-            _ => not_found(),
+            if let Some(ref name) = change.name {
+                match db.find_collection(name)? {
+                    Some(ref other) if Some(other.id) != collection_id => {
+                        let message = format!("There's already a collection called {}", name);
+                        return Err(HttpError::BadRequest(message));
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(ref series) = change.series {
+                for &series_id in series {
+                    db.get_series_by_id(user, series_id)?;
+                }
+            }
+            let collection_id = match (collection_id, change.name) {
+                (Some(collection_id), Some(name)) => {
+                    db.rename_collection(collection_id, &name)?;
+                    collection_id
+                }
+                (Some(collection_id), None) => collection_id,
+                (None, Some(name)) => db.create_collection(&name)?,
+                (None, None) => {
+                    return Err(HttpError::BadRequest("A collection needs a name".into()))
+                }
+            };
+            if let Some(ref series) = change.series {
+                db.set_collection_series(collection_id, series)?;
+            }
+            json_response(&api_collection(db, user, collection_id)?)
+        }
+        Route::ApiDeleteCollection(collection_id) => {
+            require_admin(user, true)?;
+            db.get_collection(collection_id)?;
+            db.delete_collection(collection_id)?;
+            no_content()
+        }
+        Route::WebStart => html_response(html::make_start_page(&incoming.url_prefix, user)),
+        Route::WebRecent => {
+            let entries = db.get_recent(user, &Facets::default())?;
+            issues_page(incoming, &route, "Recent Comics", &entries, None)
+        }
+        Route::WebPublishers => {
+            let publishers = db.get_publishers(user)?;
+            let subs: Vec<(String, DateTime<Utc>, Route)> = publishers
+                .iter()
+                .map(|p| {
+                    let route = Route::WebPublisher(p.id, slug(p.get_name()));
+                    (p.get_name().to_owned(), p.updated_at, route)
+                })
+                .collect();
+            subsection_page(incoming, &route, "Comics by publisher", &subs, None)
+        }
+        Route::WebPublisher(id, _) => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let publisher = db.get_publisher(user, id)?;
+            let series = db.get_series_for_publisher(user, &publisher.name)?;
+            let title = publisher.get_name();
+            series_page(incoming, &route, title, &series, last_read, |s| {
+                Route::WebSeries(s.id, slug(&s.get_title()))
+            })
+        }
+        Route::WebSeries(id, _) => {
+            let series = db.get_series_by_id(user, id)?;
+            let entries = db.get_for_series(user, &series.key(), &Facets::default())?;
+            issues_page(incoming, &route, &series.get_title(), &entries, None)
+        }
+        Route::WebUnread => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let series = db.get_unread_series(user)?;
+            series_page(
+                incoming,
+                &route,
+                "Unread comics by series",
+                &series,
+                last_read,
+                |s| Route::WebUnreadForSeries(s.id, slug(&s.get_title())),
+            )
+        }
+        Route::WebUnreadForSeries(id, _) => {
+            let last_read = db.get_last_read_at(user.id)?;
+            let series = db.get_series_by_id(user, id)?;
+            let entries = db.get_unread_for_series(user, &series.key(), &Facets::default())?;
+            issues_page(incoming, &route, &series.get_title(), &entries, last_read)
+        }
+        Route::PublisherByName(ref name) | Route::WebPublisherByName(ref name) => {
+            let name = if name == "None" { None } else { Some(name.clone()) };
+            let publisher = db.find_publisher(user, &name)?;
+            let slug = slug(publisher.get_name());
+            moved_permanently(incoming, &match route {
+                Route::PublisherByName(_) => Route::Publisher(publisher.id, slug),
+                _ => Route::WebPublisher(publisher.id, slug),
+            })
+        }
+        Route::SeriesByName(ref publisher, ref name, ref volume)
+        | Route::UnreadForSeriesByName(ref publisher, ref name, ref volume)
+        | Route::WebSeriesByName(ref publisher, ref name, ref volume)
+        | Route::WebUnreadForSeriesByName(ref publisher, ref name, ref volume) => {
+            let key = SeriesKey::from_parts(publisher, name, volume)?;
+            let series = db.find_series(user, &key)?;
+            let slug = slug(&series.get_title());
+            moved_permanently(incoming, &match route {
+                Route::SeriesByName(..) => Route::Series(series.id, slug),
+                Route::UnreadForSeriesByName(..) => Route::UnreadForSeries(series.id, slug),
+                Route::WebSeriesByName(..) => Route::WebSeries(series.id, slug),
+                _ => Route::WebUnreadForSeries(series.id, slug),
+            })
+        }
+        Route::PublisherSeriesByName(ref publisher, ref name)
+        | Route::WebPublisherSeriesByName(ref publisher, ref name) => {
+            let series =
+                db.find_series_by_name(user, Some(none_param(publisher)), none_param(name))?;
+            let slug = slug(&series.get_title());
+            moved_permanently(incoming, &match route {
+                Route::PublisherSeriesByName(..) => Route::Series(series.id, slug),
+                _ => Route::WebSeries(series.id, slug),
+            })
+        }
+        Route::UnreadSeriesByName(ref name) | Route::WebUnreadSeriesByName(ref name) => {
+            let series = db.find_series_by_name(user, None, none_param(name))?;
+            let slug = slug(&series.get_title());
+            moved_permanently(incoming, &match route {
+                Route::UnreadSeriesByName(_) => Route::UnreadForSeries(series.id, slug),
+                _ => Route::WebUnreadForSeries(series.id, slug),
+            })
+        }
+        Route::WebIssue(issue_id) => {
+            let entry = db.get(user, issue_id)?;
+            let progress = db.get_progress(user.id, issue_id)?;
+            html_response(html::make_issue_page(&incoming.url_prefix, &entry, progress))
+        }
+        Route::WebRead(issue_id) => {
+            let entry = db.get(user, issue_id)?;
+            let page_count = db.count_pages(issue_id)?;
+            let progress = db.get_progress(user.id, issue_id)?;
+            html_response(html::make_reader_page(
+                &incoming.url_prefix,
+                &entry,
+                page_count,
+                progress.unwrap_or(0),
+            ))
+        }
+        Route::WebStatic(ref file) => {
+            let (content_type, body) = html::static_file(file)
+                .ok_or_else(|| HttpError::NotFound(format!("No such file {}", file)))?;
+            Ok(Box::new(future::ok(
+                Response::builder()
+                    .header(header::CONTENT_TYPE, content_type)
+                    .header(header::CACHE_CONTROL, STATIC_CACHE_CONTROL)
+                    .body(Body::from(body))
+                    .unwrap(),
+            )))
+        }
+    }
+}
+
+/// A list the user is allowed to change, which is theirs unless they're an admin
+fn editable_list(db: &DB, user: &User, list_id: i64) -> Result<ReadingList, HttpError> {
+    let list = db.get_list(user, list_id)?;
+    if user.read_only {
+        Err(HttpError::Forbidden("Read only users can't change lists".into()))
+    } else if list.owner_id != user.id && user.role != Role::Admin {
+        Err(HttpError::Forbidden(format!("{} belongs to {}", list.name, list.owner)))
+    } else {
+        Ok(list)
+    }
+}
+
+fn api_list(db: &DB, user: &User, list_id: i64) -> Result<ApiList, HttpError> {
+    let list = db.get_list(user, list_id)?;
+    let issue_ids = db.get_for_list(user, list_id, &Facets::default())?
+        .into_iter()
+        .filter_map(|e| e.id)
+        .collect();
+    Ok(ApiList { list, issue_ids })
+}
+
+/// Shelves only belong to the user, so all that's left is whether they can change anything
+fn editable_shelf(db: &DB, user: &User, shelf_id: i64) -> Result<Shelf, HttpError> {
+    if user.read_only {
+        return Err(HttpError::Forbidden("Read only users can't change shelves".into()));
+    }
+    Ok(db.get_shelf(user, shelf_id)?)
+}
+
+fn api_shelf(db: &DB, user: &User, shelf_id: i64) -> Result<ApiShelf, HttpError> {
+    let shelf = db.get_shelf(user, shelf_id)?;
+    let issue_ids = db.get_for_shelf(user, shelf_id)?
+        .into_iter()
+        .filter_map(|e| e.id)
+        .collect();
+    let series_ids = db.get_series_for_shelf(user, shelf_id)?
+        .into_iter()
+        .map(|s| s.id)
+        .collect();
+    Ok(ApiShelf {
+        shelf,
+        issue_ids,
+        series_ids,
+    })
+}
+
+fn api_collection(db: &DB, user: &User, collection_id: i64) -> Result<ApiCollection, HttpError> {
+    let collection = db.get_collection(collection_id)?;
+    let series_ids = db.get_series_for_collection(user, collection_id)?
+        .into_iter()
+        .map(|s| s.id)
+        .collect();
+    Ok(ApiCollection {
+        collection,
+        series_ids,
+    })
+}
+
+/// Admin pages are for admins, and changing anything takes more than a read only token
+fn require_admin(user: &User, changes: bool) -> Result<(), HttpError> {
+    if user.role != Role::Admin {
+        Err(HttpError::Forbidden("Only admins can do that".into()))
+    } else if changes && user.read_only {
+        Err(HttpError::Forbidden("Read only tokens can't change anything".into()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Forms go back to the admin page, scripts just get told it worked
+fn admin_done(incoming: &Incoming) -> Result<ResponseFuture, HttpError> {
+    if wants_json(&incoming.req) {
+        return no_content();
+    }
+    let location = format!("{}{}", incoming.url_prefix, Route::Admin.url());
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::SEE_OTHER)
+            .header(header::LOCATION, location.as_str())
+            .body(Body::empty())
+            .unwrap(),
+    )))
+}
+
+/// For URLs that have moved for good, like the name based ones for series
+fn moved_permanently(incoming: &Incoming, route: &Route) -> Result<ResponseFuture, HttpError> {
+    let location = format!("{}{}", incoming.url_prefix, route.url());
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::MOVED_PERMANENTLY)
+            .header(header::LOCATION, location.as_str())
+            .body(Body::empty())
+            .unwrap(),
+    )))
+}
+
+fn no_content() -> Result<ResponseFuture, HttpError> {
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::empty())
+            .unwrap(),
+    )))
+}
+
+fn query_params(req: &Request<Body>) -> HashMap<String, String> {
+    parse_form(req.uri().query().unwrap_or_default().as_bytes())
+}
+
+/// The sort and filters picked for an acquisition feed
+fn facets(req: &Request<Body>) -> Result<Facets, HttpError> {
+    Facets::from_query(&query_params(req)).map_err(|e| HttpError::BadRequest(e.to_string()))
+}
+
+/// Filters are exact matches, with None standing in for issues missing the field
+fn none_param(value: &str) -> Option<String> {
+    if value == "None" {
+        None
+    } else {
+        Some(value.to_owned())
+    }
+}
+
+/// The offset and limit query params
+fn page_params(params: &HashMap<String, String>) -> Result<(usize, usize), HttpError> {
+    let number = |name: &str, default: usize| match params.get(name) {
+        Some(value) => value
+            .parse::<usize>()
+            .map_err(|_| HttpError::BadRequest(format!("{} can't be {}", name, value))),
+        None => Ok(default),
+    };
+    Ok((number("offset", 0)?, number("limit", DEFAULT_LIMIT)?.min(MAX_LIMIT)))
+}
+
+/// Cuts items down to the offset and limit query params
+fn paginate<T>(params: &HashMap<String, String>, items: Vec<T>) -> Result<ApiPage<T>, HttpError> {
+    let (offset, limit) = page_params(params)?;
+    let total = items.len();
+    Ok(ApiPage {
+        items: items.into_iter().skip(offset).take(limit).collect(),
+        offset,
+        limit,
+        total,
+    })
+}
+
+fn parse_form(body: &[u8]) -> HashMap<String, String> {
+    form_urlencoded::parse(body).into_owned().collect()
+}
+
+fn wants_json(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.contains("application/json"))
+}
+
+fn json_response<T: Serialize>(value: &T) -> Result<ResponseFuture, HttpError> {
+    let body = ::serde_json::to_string(value).map_err(|e| HttpError::Internal(e.into()))?;
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::CACHE_CONTROL, "private, no-store")
+            .body(Body::from(body))
+            .unwrap(),
+    )))
+}
+
+/// Browsers say where a form post or fetch came from, and will happily send an admin's
+/// cookie or cached Basic login along with one from some other site.  Anything that
+/// changes things has to come from our own pages, while scripts and apps that send
+/// neither header are let through.
+fn check_same_origin(req: &Request<Body>) -> Result<(), HttpError> {
+    let headers = req.headers();
+    let source = match headers
+        .get(header::ORIGIN)
+        .or_else(|| headers.get(header::REFERER))
+        .and_then(|source| source.to_str().ok())
+    {
+        Some(source) => source,
+        None => return Ok(()),
+    };
+    // Reverse proxies that rewrite Host say what it was in X-Forwarded-Host
+    let host = headers
+        .get("x-forwarded-host")
+        .or_else(|| headers.get(header::HOST))
+        .and_then(|host| host.to_str().ok());
+    let source_host = source
+        .splitn(2, "://")
+        .nth(1)
+        .and_then(|rest| rest.split('/').next());
+    match (source_host, host) {
+        (Some(source_host), Some(host)) if source_host.eq_ignore_ascii_case(host) => Ok(()),
+        _ => Err(HttpError::Forbidden(format!(
+            "{} from {} isn't allowed",
+            req.method(),
+            source
+        ))),
+    }
+}
+
+fn wants_html(req: &Request<Body>) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .map_or(false, |accept| accept.starts_with("text/html"))
+}
+
+/// For pages that depend on more than we can cheaply make an ETag from
+fn html_response(body: String) -> Result<ResponseFuture, HttpError> {
+    Ok(Box::new(future::ok(
+        Response::builder()
+            .header(header::CONTENT_TYPE, HTML_CONTENT_TYPE)
+            .header(header::CACHE_CONTROL, "private, no-store")
+            .body(Body::from(body))
+            .unwrap(),
+    )))
+}
+
+fn issues_page(
+    incoming: &Incoming,
+    route: &Route,
+    title: &str,
+    entries: &[ComicInfo],
+    last_read: Option<DateTime<Utc>>,
+) -> Result<ResponseFuture, HttpError> {
+    let url = format!("{}{}", incoming.url_prefix, route.url());
+    Validator::for_entries(&url, entries, last_read).respond(
+        &incoming.req,
+        FEED_CACHE_CONTROL,
+        Some(HTML_CONTENT_TYPE),
+        || {
+            Ok(Body::from(html::make_issues_page(
+                &incoming.url_prefix,
+                title,
+                entries,
+            )))
         },
-        (&Method::GET, Some("stream")) => match path_parts.next() {
-            Some(issue_id) => match path_parts.next() {
-                Some(page_id) => {
-                    let issue_id = issue_id.parse::<i64>().unwrap();
-                    let page_id = page_id.parse::<i32>().unwrap();
-                    let (entry, body) = db.get_page(issue_id, page_id, user_id).unwrap();
-                    let content_type = if entry.ends_with(".png") {
-                        "image/png"
-                    } else if entry.ends_with(".gif") {
-                        "image/gif"
-                    } else {
-                        "image/jpeg"
-                    };
-
-                    Box::new(future::ok(
-                        Response::builder()
-                            .status(200)
-                            .header("Content-Type", content_type)
-                            .body(Body::from(body))
-                            .unwrap(),
-                    ))
-                }
-                _ => not_found(),
-            },
-            _ => not_found(),
+    )
+}
+
+fn subsection_page(
+    incoming: &Incoming,
+    route: &Route,
+    title: &str,
+    subs: &[(String, DateTime<Utc>, Route)],
+    last_read: Option<DateTime<Utc>>,
+) -> Result<ResponseFuture, HttpError> {
+    let url = format!("{}{}", incoming.url_prefix, route.url());
+    Validator::for_subsections(&url, subs, last_read).respond(
+        &incoming.req,
+        FEED_CACHE_CONTROL,
+        Some(HTML_CONTENT_TYPE),
+        || {
+            Ok(Body::from(html::make_subsection_page(
+                &incoming.url_prefix,
+                title,
+                subs,
+            )))
+        },
+    )
+}
+
+/// For feeds that change with the user's progress as well as their read status
+fn last_activity(db: &DB, user: &User) -> Result<Option<DateTime<Utc>>, Error> {
+    let last_read = db.get_last_read_at(user.id)?;
+    Ok(last_read.into_iter().chain(db.get_last_progress_at(user.id)?).max())
+}
+
+fn category_route(kind: CategoryKind, id: i64, name: &str) -> Route {
+    match kind {
+        CategoryKind::Genre => Route::Genre(id, slug(name)),
+        CategoryKind::Tag => Route::Tag(id, slug(name)),
+        CategoryKind::Character => Route::Character(id, slug(name)),
+        CategoryKind::Team => Route::Team(id, slug(name)),
+        CategoryKind::Location => Route::Location(id, slug(name)),
+    }
+}
+
+fn unread_series_route(series: &Series) -> Route {
+    Route::UnreadForSeries(series.id, slug(&series.get_title()))
+}
+
+fn series_page<F>(
+    incoming: &Incoming,
+    route: &Route,
+    title: &str,
+    series: &[Series],
+    last_read: Option<DateTime<Utc>>,
+    series_route: F,
+) -> Result<ResponseFuture, HttpError>
+where
+    F: Fn(&Series) -> Route,
+{
+    let url = format!("{}{}", incoming.url_prefix, route.url());
+    Validator::for_series(&url, series, last_read).respond(
+        &incoming.req,
+        FEED_CACHE_CONTROL,
+        Some(HTML_CONTENT_TYPE),
+        || {
+            Ok(Body::from(html::make_series_page(
+                &incoming.url_prefix,
+                title,
+                series,
+                series_route,
+            )))
+        },
+    )
+}
+
+fn acquisition_feed(
+    incoming: &Incoming,
+    config: &Config,
+    route: &Route,
+    title: &str,
+    entries: &[ComicInfo],
+    facets: &Facets,
+    last_read: Option<DateTime<Utc>>,
+) -> Result<ResponseFuture, HttpError> {
+    let url = format!("{}{}?{}", incoming.url_prefix, route.url(), facets.to_query());
+    Validator::for_entries(&url, entries, last_read).respond(
+        &incoming.req,
+        FEED_CACHE_CONTROL,
+        None,
+        || {
+            Ok(Body::from(opds::make_acquisition_feed(
+                config,
+                &incoming.url_prefix,
+                route,
+                title,
+                entries,
+                facets,
+                facet_groups(route),
+            )?))
+        },
+    )
+}
+
+/// The facets that can change what's in an acquisition feed, there's no
+/// point offering read issues in one that only has unread ones
+fn facet_groups(route: &Route) -> &'static [FacetGroup] {
+    match *route {
+        Route::UnreadAll | Route::UnreadForSeries(..) | Route::OnDeck => &[
+            FacetGroup::Sort,
+            FacetGroup::Publisher,
+            FacetGroup::Year,
+            FacetGroup::Format,
+        ],
+        Route::Year(..) => &[
+            FacetGroup::Sort,
+            FacetGroup::Read,
+            FacetGroup::Publisher,
+            FacetGroup::Format,
+        ],
+        Route::Series(..) => &[
+            FacetGroup::Sort,
+            FacetGroup::Read,
+            FacetGroup::Year,
+            FacetGroup::Format,
+        ],
+        _ => &[
+            FacetGroup::Sort,
+            FacetGroup::Read,
+            FacetGroup::Publisher,
+            FacetGroup::Year,
+            FacetGroup::Format,
+        ],
+    }
+}
+
+fn subsection_feed(
+    incoming: &Incoming,
+    config: &Config,
+    route: &Route,
+    title: &str,
+    subs: &[(String, DateTime<Utc>, Route)],
+    last_read: Option<DateTime<Utc>>,
+) -> Result<ResponseFuture, HttpError> {
+    let url = format!("{}{}", incoming.url_prefix, route.url());
+    Validator::for_subsections(&url, subs, last_read).respond(
+        &incoming.req,
+        FEED_CACHE_CONTROL,
+        None,
+        || {
+            Ok(Body::from(opds::make_subsection_feed(
+                config,
+                &incoming.url_prefix,
+                route,
+                title,
+                subs,
+            )?))
+        },
+    )
+}
+
+fn series_feed<F>(
+    incoming: &Incoming,
+    config: &Config,
+    route: &Route,
+    title: &str,
+    series: &[Series],
+    last_read: Option<DateTime<Utc>>,
+    series_route: F,
+) -> Result<ResponseFuture, HttpError>
+where
+    F: Fn(&Series) -> Route,
+{
+    let url = format!("{}{}", incoming.url_prefix, route.url());
+    Validator::for_series(&url, series, last_read).respond(
+        &incoming.req,
+        FEED_CACHE_CONTROL,
+        None,
+        || {
+            Ok(Body::from(opds::make_series_feed(
+                config,
+                &incoming.url_prefix,
+                route,
+                title,
+                series,
+                series_route,
+            )?))
         },
-        _ => not_found(),
+    )
+}
+
+fn send_page(
+    incoming: &Incoming,
+    db: &DB,
+    issue_id: i64,
+    page_id: i32,
+) -> Result<ResponseFuture, HttpError> {
+    let req = &incoming.req;
+    let issue = db.get(&incoming.user, issue_id)?;
+    let validator = Validator::for_issue(&format!("/stream/{}", page_id), &issue);
+    if validator.is_fresh(req) {
+        return validator.respond(req, PAGE_CACHE_CONTROL, None, || Ok(Body::empty()));
     }
+
+    let (entry, body) = db.get_page(issue_id, page_id, &incoming.user)?;
+    let content_type = if entry.ends_with(".png") {
+        "image/png"
+    } else if entry.ends_with(".gif") {
+        "image/gif"
+    } else {
+        "image/jpeg"
+    };
+    validator.respond(req, PAGE_CACHE_CONTROL, Some(content_type), || {
+        Ok(Body::from(body))
+    })
 }
 
-pub fn start_web_service(db: Arc<DB>, config: &Arc<Config>) -> Result<(), Error> {
-    let inner_config = config.clone();
+pub fn start_web_service(
+    db: Arc<DB>,
+    config: &Arc<Config>,
+    scanner: Scanner,
+) -> Result<(), Error> {
+    let ctx = Arc::new(Context {
+        db,
+        config: RwLock::new(Arc::clone(config)),
+        sessions: Sessions::new(Duration::from_secs(config.auth.session_minutes * 60)),
+        scanner,
+    });
     let new_svc = move || {
-        let db = db.clone();
-        let config = inner_config.clone();
-        service_fn(move |req| serve_opds(&req, &db, &config))
+        let ctx = Arc::clone(&ctx);
+        service_fn(move |req| serve_opds(req, &ctx))
     };
 
     let server = Server::bind(&config.addr)
@@ -224,7 +1873,7 @@ pub fn start_web_service(db: Arc<DB>, config: &Arc<Config>) -> Result<(), Error>
 }
 
 // Taken from the hyper example
-fn simple_file_send(f: &str) -> ResponseFuture {
+fn simple_file_send(f: &str, validator: Validator) -> ResponseFuture {
     // Serve a file by asynchronously reading it entirely into memory.
     // Uses tokio_fs to open file asynchronously, then tokio_io to read into
     // memory asynchronously.
@@ -234,14 +1883,655 @@ fn simple_file_send(f: &str) -> ResponseFuture {
             .and_then(|file| {
                 let buf: Vec<u8> = Vec::new();
                 ::tokio_io::io::read_to_end(file, buf)
-                    .and_then(|item| Ok(Response::new(item.1.into())))
-                    .or_else(|_| {
+                    .and_then(move |item| {
                         Ok(Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(Body::empty())
+                            .header(header::ETAG, validator.etag.as_str())
+                            .header(header::LAST_MODIFIED, validator.http_date().as_str())
+                            .header(header::CACHE_CONTROL, PAGE_CACHE_CONTROL)
+                            .body(item.1.into())
                             .unwrap())
                     })
+                    .or_else(|e| Ok(HttpError::Internal(e.into()).into_response()))
             })
-            .or_else(|_| not_found()),
+            .or_else(|_| Ok(HttpError::NotFound("Comic file is missing".into()).into_response())),
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use db::tests::{comic, path, store, test_library, user};
+    use std::path::Path;
+    use db::TokenScope;
+
+    fn request(uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut request = Request::builder();
+        request.uri(uri);
+        for &(name, value) in headers {
+            request.header(name, value);
+        }
+        request.body(Body::empty()).unwrap()
+    }
+
+    /// The db's test library, with no folders to scan
+    fn context() -> Context {
+        let db = Arc::new(test_library());
+        Context {
+            db: Arc::clone(&db),
+            config: RwLock::new(Arc::new(Config::for_tests())),
+            sessions: Sessions::new(Duration::from_secs(60)),
+            scanner: Scanner::new(db, Vec::new()),
+        }
+    }
+
+    /// Runs req through route as user, like serve_opds does once they're authenticated
+    fn send(ctx: &Context, user: &User, req: Request<Body>, body: &[u8]) -> Response<Body> {
+        let path = req.uri().path().to_owned();
+        let (token, path) = split_token_prefix(&path);
+        let incoming = Incoming {
+            req,
+            body: body.to_vec(),
+            path: path.to_owned(),
+            url_prefix: token.map(|token| format!("/t/{}", token)).unwrap_or_default(),
+            user: user.clone(),
+        };
+        match route(&incoming, ctx) {
+            Ok(response) => response.wait().unwrap(),
+            Err(e) => e.into_response(),
+        }
+    }
+
+    fn get(ctx: &Context, uri: &str) -> Response<Body> {
+        send(ctx, &user(1), request(uri, &[]), b"")
+    }
+
+    fn call(ctx: &Context, method: Method, uri: &str, body: &[u8]) -> Response<Body> {
+        let mut req = request(uri, &[]);
+        *req.method_mut() = method;
+        send(ctx, &user(1), req, body)
+    }
+
+    fn body(response: Response<Body>) -> String {
+        let body = response.into_body().concat2().wait().unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    fn json(response: Response<Body>) -> ::serde_json::Value {
+        ::serde_json::from_str(&body(response)).unwrap()
+    }
+
+    fn location(response: &Response<Body>) -> &str {
+        response.headers()[header::LOCATION].to_str().unwrap()
+    }
+
+    fn validator() -> Validator {
+        Validator::new("/comic", "2018-05-01T12:00:00Z".parse().unwrap())
+    }
+
+    #[test]
+    fn etags_win_over_dates() {
+        let validator = validator();
+        let later = "Wed, 02 May 2018 12:00:00 GMT";
+        let earlier = "Tue, 01 May 2018 11:59:59 GMT";
+        assert!(validator.is_fresh(&request("/", &[("If-Modified-Since", later)])));
+        assert!(!validator.is_fresh(&request("/", &[("If-Modified-Since", earlier)])));
+        assert!(!validator.is_fresh(&request(
+            "/",
+            &[("If-None-Match", "\"stale\""), ("If-Modified-Since", later)],
+        )));
+        assert!(validator.is_fresh(&request(
+            "/",
+            &[("If-None-Match", &validator.etag), ("If-Modified-Since", earlier)],
+        )));
+        assert!(!validator.is_fresh(&request("/", &[])));
+    }
+
+    #[test]
+    fn etag_lists_weak_tags_and_star() {
+        let validator = validator();
+        let weak = format!("W/{}", validator.etag);
+        let list = format!("\"stale\", {} ,\"other\"", validator.etag);
+        for tags in &[&weak[..], &list[..], "*"] {
+            assert!(validator.is_fresh(&request("/", &[("If-None-Match", tags)])), "{}", tags);
+        }
+        assert!(!validator.is_fresh(&request("/", &[("If-None-Match", "\"stale\", W/\"other\"")])));
+    }
+
+    #[test]
+    fn respond_answers_fresh_clients_with_304() {
+        let validator = validator();
+        let fresh = request("/", &[("If-None-Match", &validator.etag)]);
+        let response = validator
+            .respond(&fresh, PAGE_CACHE_CONTROL, Some("image/jpeg"), || {
+                panic!("built a body for a 304")
+            })
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], validator.etag.as_str());
+        assert_eq!(response.headers()[header::LAST_MODIFIED], "Tue, 01 May 2018 12:00:00 GMT");
+        assert!(response.headers().get(header::CONTENT_TYPE).is_none());
+
+        let response = validator
+            .respond(&request("/", &[]), PAGE_CACHE_CONTROL, Some("image/jpeg"), || {
+                Ok(Body::from("page"))
+            })
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "image/jpeg");
+        let body = response.into_body().concat2().wait().unwrap();
+        assert_eq!(&body[..], b"page");
+    }
+
+    #[test]
+    fn legacy_routes_redirect_to_ids() {
+        let ctx = context();
+        let saga = ctx.db.get_series(&user(1)).unwrap();
+        let saga = saga.iter().find(|s| s.get_name() == "Saga").unwrap();
+        let response = get(&ctx, "/publishers/Image/Saga");
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(location(&response), Route::Series(saga.id, slug(&saga.get_title())).url());
+        let response = get(&ctx, "/t/abc/web/unread/Image/Saga/1");
+        assert_eq!(
+            location(&response),
+            format!("/t/abc{}", Route::WebUnreadForSeries(saga.id, slug(&saga.get_title())).url())
+        );
+        let response = get(&ctx, "/unread/Ms.%20Marvel");
+        assert!(location(&response).starts_with("/series/"));
+        assert_eq!(get(&ctx, "/publishers/Image/Nope").status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn sessions_end_when_the_user_changes() {
+        let ctx = Arc::new(context());
+        ctx.db.create_user("casret", "secret").unwrap();
+        let casret = ctx.db.check_user("casret", "secret").unwrap().unwrap();
+        let token = ctx.sessions.start_session(&casret);
+        let cookie = format!("{}={}", auth::SESSION_COOKIE, token);
+        let req = request("/", &[("Cookie", &cookie)]);
+        let (user, session) = authenticate(&req, None, &ctx).wait().unwrap();
+        assert_eq!((user.id, session), (casret.id, None));
+
+        ctx.db.set_role("casret", Role::Admin).unwrap();
+        match authenticate(&req, None, &ctx).wait() {
+            Err(HttpError::Unauthorized) => (),
+            other => panic!("{:?}", other.map(|(user, _)| user)),
+        }
+        assert!(ctx.sessions.get_session(&token).is_none());
+    }
+
+    #[test]
+    fn token_prefix() {
+        assert_eq!(split_token_prefix("/t/abc/unread"), (Some("abc"), "/unread"));
+        assert_eq!(split_token_prefix("/t/abc"), (Some("abc"), "/"));
+        assert_eq!(split_token_prefix("/t/abc/"), (Some("abc"), "/"));
+        assert_eq!(split_token_prefix("/unread"), (None, "/unread"));
+        assert_eq!(split_token_prefix("/tags/1/x"), (None, "/tags/1/x"));
+    }
+
+    #[test]
+    fn api_tokens_authenticate() {
+        let ctx = Arc::new(context());
+        let user_id = ctx.db.create_user("casret", "secret").unwrap();
+        let token = ctx.db.create_token(user_id, "app", TokenScope::Read).unwrap();
+        let check = |req: &Request<Body>, path_token: Option<&str>| {
+            authenticate(req, path_token.map(|t| t.to_owned()), &ctx).wait()
+        };
+
+        let bearer = format!("Bearer {}", token);
+        let (user, session) = check(&request("/", &[("Authorization", &bearer)]), None).unwrap();
+        assert_eq!((user.id, user.read_only, session), (user_id, true, None));
+        let (user, _) = check(&request("/", &[]), Some(&token)).unwrap();
+        assert_eq!(user.id, user_id);
+        // Basic only clients put it in as the password
+        let basic = format!("Basic {}", ::base64::encode(&format!("casret:{}", token)));
+        let (user, session) = check(&request("/", &[("Authorization", &basic)]), None).unwrap();
+        assert_eq!((user.id, session), (user_id, None));
+
+        ctx.db.revoke_token(user_id, "app").unwrap();
+        for &(ref req, path_token) in &[
+            (request("/", &[("Authorization", &bearer)]), None),
+            (request("/", &[]), Some(&token[..])),
+            (request("/", &[("Authorization", &basic)]), None),
+        ] {
+            match check(req, path_token) {
+                Err(HttpError::Unauthorized) => (),
+                other => panic!("{:?}", other.map(|(user, _)| user)),
+            }
+        }
+    }
+
+    #[test]
+    fn same_origin() {
+        let check = |headers: &[(&str, &str)]| check_same_origin(&request("/", headers));
+        let host = ("Host", "comics.example.com:8080");
+        assert!(check(&[host]).is_ok());
+        assert!(check(&[host, ("Origin", "http://comics.example.com:8080")]).is_ok());
+        assert!(check(&[host, ("Origin", "https://COMICS.example.com:8080")]).is_ok());
+        match check(&[host, ("Origin", "https://evil.example.com")]) {
+            Err(HttpError::Forbidden(_)) => (),
+            other => panic!("{:?}", other),
+        }
+        // Without an Origin the Referer has to match instead
+        assert!(check(&[host, ("Referer", "http://comics.example.com:8080/web/read/1")]).is_ok());
+        assert!(check(&[host, ("Referer", "http://evil.example.com/comics.example.com:8080")])
+            .is_err());
+        assert!(check(&[("Origin", "http://comics.example.com:8080")]).is_err());
+
+        // Behind a proxy the browser sees the proxy's host
+        let proxied = [
+            ("Host", "127.0.0.1:8080"),
+            ("X-Forwarded-Host", "comics.example.com"),
+            ("Origin", "https://comics.example.com"),
+        ];
+        assert!(check(&proxied).is_ok());
+        assert!(check(&[("Host", "comics.example.com"), ("X-Forwarded-Host", "proxy")]).is_ok());
+        assert!(check(&[proxied[0], ("Origin", "https://comics.example.com")]).is_err());
+    }
+
+    #[test]
+    fn cross_site_posts_are_forbidden() {
+        let ctx = context();
+        let mut req = request("/api/v1/issues/1/read", &[("Origin", "https://evil.example.com")]);
+        *req.method_mut() = Method::PUT;
+        let response = send(&ctx, &user(1), req, b"");
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let mut req = request("/api/v1/issues/1/read", &[]);
+        *req.method_mut() = Method::PUT;
+        assert_eq!(send(&ctx, &user(1), req, b"").status(), StatusCode::NO_CONTENT);
+    }
+
+    #[test]
+    fn publishers_and_series_by_id() {
+        let ctx = context();
+        let publishers = ctx.db.get_publishers(&user(1)).unwrap();
+        let feed = body(get(&ctx, "/publishers"));
+        for publisher in &publishers {
+            let url = Route::Publisher(publisher.id, slug(publisher.get_name())).url();
+            assert!(feed.contains(&format!("href=\"{}\"", url)), "{}", url);
+        }
+
+        // Issues without a publisher have one called None, which is just a name now
+        let none = publishers.iter().find(|p| p.name.is_none()).unwrap();
+        let feed = body(get(&ctx, &format!("/publisher/{}/none", none.id)));
+        let zine = ctx.db.get_series(&user(1)).unwrap();
+        let zine = zine.iter().find(|s| s.get_name() == "Zine").unwrap();
+        assert!(feed.contains(&Route::Series(zine.id, slug(&zine.get_title())).url()));
+        assert!(!feed.contains("Saga"));
+
+        // The slug is only for people, a stale one still finds the series
+        let response = get(&ctx, &format!("/series/{}/not-the-slug", zine.id));
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(body(response).contains("Zine #1"));
+        assert_eq!(get(&ctx, "/series/9999/zine").status(), StatusCode::NOT_FOUND);
+        assert_eq!(get(&ctx, "/publisher/9999/image").status(), StatusCode::NOT_FOUND);
+    }
+
+    /// The entry titles of a feed in order
+    fn entry_titles(feed: &str) -> Vec<&str> {
+        feed.split("<entry><title>")
+            .skip(1)
+            .map(|entry| &entry[..entry.find("</title>").unwrap()])
+            .collect()
+    }
+
+    #[test]
+    fn feeds_follow_their_facets() {
+        let ctx = context();
+        let feed = body(get(&ctx, "/all?sort=title&publisher=Image"));
+        assert_eq!(entry_titles(&feed), vec!["Saga v1 1", "Saga v1 2", "Saga v1 3"]);
+        let feed = body(get(&ctx, "/all?format=cbr"));
+        assert_eq!(entry_titles(&feed), vec!["Saga v1 3"]);
+        let feed = body(get(&ctx, "/all?year=1999"));
+        assert_eq!(entry_titles(&feed), vec!["Zine v1 1"]);
+        assert!(feed.contains(r#"opds:facetGroup="Read""#));
+
+        // Everything in the unread feeds is unread, so there's no read facet
+        let feed = body(get(&ctx, "/unread_all?sort=released"));
+        assert!(!feed.contains(r#"opds:facetGroup="Read""#));
+        assert_eq!(
+            entry_titles(&feed),
+            vec!["Ms. Marvel v1 1", "Saga v1 3", "Saga v1 2", "Saga v1 1", "Zine v1 1"]
+        );
+        assert_eq!(get(&ctx, "/all?sort=sideways").status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get(&ctx, "/all?read=maybe").status(), StatusCode::BAD_REQUEST);
+    }
+
+    /// The titles in a page of the issues API
+    fn api_titles(page: &::serde_json::Value) -> Vec<&str> {
+        let items = page["items"].as_array().unwrap();
+        items.iter().map(|i| i["title"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn issues_api_filters_and_pages() {
+        let ctx = context();
+        let page = json(get(&ctx, "/api/v1/issues?limit=2&offset=1"));
+        assert_eq!((page["total"].as_i64(), page["limit"].as_i64()), (Some(5), Some(2)));
+        assert_eq!(api_titles(&page), vec!["Saga #1", "Saga #2"]);
+
+        // Missing publishers and series are None, and don't match everything else
+        let page = json(get(&ctx, "/api/v1/issues?publisher=None"));
+        assert_eq!(api_titles(&page), vec!["Zine #1"]);
+        let page = json(get(&ctx, "/api/v1/issues?publisher=Image&series=Saga&read=false"));
+        assert_eq!(api_titles(&page), vec!["Saga #1", "Saga #2", "Saga #3"]);
+        let page = json(get(&ctx, "/api/v1/issues?publisher=Marvel&series=Saga"));
+        assert_eq!(page["total"], 0);
+        let page = json(get(&ctx, "/api/v1/issues?year=2012&limit=1"));
+        assert_eq!((page["total"].as_i64(), api_titles(&page)), (Some(3), vec!["Saga #1"]));
+
+        let mut kid = user(2);
+        kid.restrictions.publishers = vec!["Marvel".to_owned()];
+        let page = json(send(&ctx, &kid, request("/api/v1/issues", &[]), b""));
+        assert_eq!((page["total"].as_i64(), api_titles(&page)), (Some(1), vec!["Ms. Marvel #1"]));
+        assert_eq!(get(&ctx, "/api/v1/issues?read=maybe").status(), StatusCode::BAD_REQUEST);
+        assert_eq!(get(&ctx, "/api/v1/search").status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn added_is_not_released() {
+        let ctx = context();
+        // An old back issue that just came in
+        store(&ctx.db, &["Marvel", "Secret Wars 1.cbz"], "Secret Wars", 1, None, "1984-05-01");
+
+        let recent = body(get(&ctx, "/recent"));
+        assert_eq!(entry_titles(&recent)[0], "Ms. Marvel v1 1");
+        let added = body(get(&ctx, "/added"));
+        assert_eq!(entry_titles(&added)[0], "Secret Wars v1 1");
+        assert_eq!(entry_titles(&recent).last(), Some(&"Secret Wars v1 1"));
+        let response = get(&ctx, "/added.atom");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], ATOM_CONTENT_TYPE);
+        assert_eq!(entry_titles(&body(response))[0], "Secret Wars v1 1");
+    }
+
+    #[test]
+    fn continue_reading_and_on_deck_feeds() {
+        let ctx = context();
+        let ids: HashMap<String, i64> = ctx.db.get_all(&user(1), &Facets::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.title.unwrap(), e.id.unwrap()))
+            .collect();
+        let progress = |id: i64, page: i32| {
+            let uri = Route::Progress(id, page).url();
+            call(&ctx, Method::POST, &uri, b"").status()
+        };
+        assert_eq!(progress(ids["Saga #1"], 5), StatusCode::NO_CONTENT);
+        let uri = Route::ApiProgress(ids["Zine #1"]).url();
+        let response = call(&ctx, Method::PUT, &uri, br#"{"page": 2}"#);
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let feed = body(get(&ctx, "/continue"));
+        assert_eq!(entry_titles(&feed), vec!["Zine v1 1", "Saga v1 1"]);
+        assert!(entry_titles(&body(get(&ctx, "/on_deck"))).is_empty());
+
+        // Finishing Saga #1 puts #2 on deck
+        assert_eq!(progress(ids["Saga #1"], 19), StatusCode::NO_CONTENT);
+        assert_eq!(entry_titles(&body(get(&ctx, "/continue"))), vec!["Zine v1 1"]);
+        assert_eq!(entry_titles(&body(get(&ctx, "/on_deck"))), vec!["Saga v1 2"]);
+
+        assert_eq!(progress(ids["Saga #2"], 20), StatusCode::BAD_REQUEST);
+        assert_eq!(progress(9999, 1), StatusCode::NOT_FOUND);
+        let mut guest = user(2);
+        guest.read_only = true;
+        let mut req = request(&Route::Progress(ids["Saga #2"], 1).url(), &[]);
+        *req.method_mut() = Method::POST;
+        assert_eq!(send(&ctx, &guest, req, b"").status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn only_list_imports_get_big_bodies() {
+        assert_eq!(body_limit(&Method::POST, "/api/v1/lists/import"), MAX_LIST_BODY);
+        assert_eq!(body_limit(&Method::POST, "/api/v1/lists"), MAX_BODY);
+        assert_eq!(body_limit(&Method::GET, "/api/v1/lists/import"), MAX_BODY);
+        assert_eq!(body_limit(&Method::POST, "/nowhere"), MAX_BODY);
+
+        let body = read_body(Body::from(vec![b'x'; MAX_BODY]), MAX_BODY).wait().unwrap();
+        assert_eq!(body.len(), MAX_BODY);
+        match read_body(Body::from(vec![b'x'; MAX_BODY + 1]), MAX_BODY).wait() {
+            Err(HttpError::BadRequest(_)) => (),
+            other => panic!("{:?}", other.map(|body| body.len())),
+        }
+    }
+
+    fn issue_ids(list: &::serde_json::Value) -> Vec<i64> {
+        let ids = list["issue_ids"].as_array().unwrap();
+        ids.iter().map(|id| id.as_i64().unwrap()).collect()
+    }
+
+    #[test]
+    fn reading_lists_api_and_feeds() {
+        let ctx = context();
+        // Lists need owners that exist, user(1) and user(2) are the first two users
+        ctx.db.create_user("casret", "secret").unwrap();
+        ctx.db.create_user("other", "secret").unwrap();
+        let ids: HashMap<String, i64> = ctx.db.get_all(&user(1), &Facets::default())
+            .unwrap()
+            .into_iter()
+            .map(|e| (e.title.unwrap(), e.id.unwrap()))
+            .collect();
+
+        let change = format!(
+            r#"{{"name": "Space Opera", "issues": [{}, {}]}}"#,
+            ids["Saga #2"], ids["Saga #1"]
+        );
+        let list = json(call(&ctx, Method::POST, "/api/v1/lists", change.as_bytes()));
+        let list_id = list["id"].as_i64().unwrap();
+        assert_eq!(issue_ids(&list), vec![ids["Saga #2"], ids["Saga #1"]]);
+        let feed = body(get(&ctx, &format!("/lists/{}", list_id)));
+        assert_eq!(entry_titles(&feed), vec!["Saga v1 2", "Saga v1 1"]);
+        ctx.db.mark_read(ids["Saga #2"], 1).unwrap();
+        let feed = body(get(&ctx, &format!("/lists/{}?read=false", list_id)));
+        assert_eq!(entry_titles(&feed), vec!["Saga v1 1"]);
+
+        let uri = format!("/api/v1/lists/{}", list_id);
+        let change = format!(r#"{{"name": "Zines", "issues": [{}]}}"#, ids["Zine #1"]);
+        let list = json(call(&ctx, Method::PUT, &uri, change.as_bytes()));
+        assert_eq!((&list["name"], issue_ids(&list)), (&"Zines".into(), vec![ids["Zine #1"]]));
+        let response = call(&ctx, Method::POST, "/api/v1/lists", br#"{"name": "Zines"}"#);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let mut req = request(&uri, &[]);
+        *req.method_mut() = Method::PUT;
+        let response = send(&ctx, &user(2), req, br#"{"name": "Mine now"}"#);
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+        let cbl = br#"<?xml version="1.0"?>
+            <ReadingList>
+              <Name>Marvel</Name>
+              <Books>
+                <Book Series="Ms. Marvel" Number="1" Volume="2014" Year="2014" />
+                <Book Series="Ms. Marvel" Number="2" Volume="2014" Year="2014" />
+              </Books>
+            </ReadingList>"#;
+        let import = json(call(&ctx, Method::POST, "/api/v1/lists/import", cbl));
+        assert_eq!(issue_ids(&import["list"]), vec![ids["Ms. Marvel #1"]]);
+        assert_eq!(import["unmatched"][0]["number"], "2");
+        let response = call(&ctx, Method::POST, "/api/v1/lists/import", b"not xml");
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let feed = body(get(&ctx, "/lists"));
+        assert!(feed.contains("<title>Marvel</title>") && feed.contains("<title>Zines</title>"));
+    }
+
+    #[test]
+    fn shelves_api_and_feeds() {
+        let ctx = context();
+        // Everyone gets their built in shelves when they're created
+        ctx.db.create_user("casret", "secret").unwrap();
+        ctx.db.create_user("other", "secret").unwrap();
+        let reader = user(1);
+        let shelves = json(get(&ctx, "/api/v1/shelves"));
+        let kinds: Vec<&str> = shelves["items"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["kind"].as_str().unwrap())
+            .collect();
+        assert_eq!(kinds, vec!["favorites", "want_to_read"]);
+        let favorites = shelves["items"][0]["id"].as_i64().unwrap();
+
+        let series = ctx.db.get_series(&reader).unwrap();
+        let saga = series.iter().find(|s| s.get_name() == "Saga").unwrap();
+        let zine = ctx.db.get_all(&reader, &Facets::default()).unwrap();
+        let zine = zine.iter().find(|e| e.series.as_ref().unwrap() == "Zine").unwrap();
+        let shelve_series = Route::ApiShelveSeries(favorites, saga.id).url();
+        let shelve_zine = Route::ApiShelveIssue(favorites, zine.id.unwrap()).url();
+        for uri in &[&shelve_series, &shelve_zine] {
+            assert_eq!(call(&ctx, Method::PUT, uri, b"").status(), StatusCode::NO_CONTENT);
+        }
+
+        ctx.db.mark_read(zine.id.unwrap(), reader.id).unwrap();
+        let feed = body(get(&ctx, "/shelves"));
+        assert!(feed.contains("<title>Favorites</title>"));
+        assert!(feed.contains("4 issues, 3 unread"));
+        let feed = body(get(&ctx, &Route::Shelf(favorites).url()));
+        assert_eq!(entry_titles(&feed), vec!["Saga (2012)", "Zine v1 1"]);
+
+        assert_eq!(call(&ctx, Method::DELETE, &shelve_zine, b"").status(), StatusCode::NO_CONTENT);
+        let feed = body(get(&ctx, &Route::Shelf(favorites).url()));
+        assert_eq!(entry_titles(&feed), vec!["Saga (2012)"]);
+
+        // Built in shelves stay, custom ones can go, and nobody else sees either
+        let uri = Route::ApiDeleteShelf(favorites).url();
+        assert_eq!(call(&ctx, Method::DELETE, &uri, b"").status(), StatusCode::BAD_REQUEST);
+        let later = json(call(&ctx, Method::POST, "/api/v1/shelves", br#"{"name": "Later"}"#));
+        let response = call(&ctx, Method::POST, "/api/v1/shelves", br#"{"name": "Later"}"#);
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let later = Route::ApiDeleteShelf(later["id"].as_i64().unwrap()).url();
+        assert_eq!(call(&ctx, Method::DELETE, &later, b"").status(), StatusCode::NO_CONTENT);
+        let response = send(&ctx, &user(2), request(&Route::Shelf(favorites).url(), &[]), b"");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn genres_tags_and_collections() {
+        let ctx = context();
+        let file = ["Image", "Paper Girls 1.cbz"];
+        let mut info = comic(&file, "Paper Girls", 1, Some("Image"), "2015-10-07");
+        info.genres = vec!["Science Fiction".to_owned(), "Mystery".to_owned()];
+        info.tags = vec!["Time travel".to_owned()];
+        ctx.db.store_comic(&info, &[]).unwrap();
+        let file = ["Marvel", "Ms. Marvel 2.cbz"];
+        let mut info = comic(&file, "Ms. Marvel", 2, Some("Marvel"), "2014-03-01");
+        info.genres = vec!["Science Fiction".to_owned()];
+        ctx.db.store_comic(&info, &[]).unwrap();
+        ctx.db.refresh_series().unwrap();
+
+        let genres = ctx.db.get_categories(&user(1), CategoryKind::Genre).unwrap();
+        let feed = body(get(&ctx, "/genres"));
+        assert_eq!(entry_titles(&feed), vec!["Mystery", "Science Fiction"]);
+        let scifi = genres.iter().find(|g| g.name == "Science Fiction").unwrap();
+        let url = category_route(CategoryKind::Genre, scifi.id, &scifi.name).url();
+        assert!(feed.contains(&url));
+        assert_eq!(
+            entry_titles(&body(get(&ctx, &url))),
+            vec!["Ms. Marvel v1 2", "Paper Girls v1 1"]
+        );
+        assert_eq!(entry_titles(&body(get(&ctx, "/tags"))), vec!["Time travel"]);
+
+        // Genres only count issues the user can see
+        let mut kid = user(2);
+        kid.restrictions.publishers = vec!["Marvel".to_owned()];
+        let feed = body(send(&ctx, &kid, request("/genres", &[]), b""));
+        assert_eq!(entry_titles(&feed), vec!["Science Fiction"]);
+        let response = send(&ctx, &kid, request(&url, &[]), b"");
+        assert_eq!(entry_titles(&body(response)), vec!["Ms. Marvel v1 2"]);
+
+        // Collections are made by admins and shared with everyone
+        let series = ctx.db.get_series(&user(1)).unwrap();
+        let ids: Vec<String> = series
+            .iter()
+            .filter(|s| s.get_name() != "Zine")
+            .map(|s| s.id.to_string())
+            .collect();
+        let change = format!(r#"{{"name": "Favorites", "series": [{}]}}"#, ids.join(", "));
+        let response = call(&ctx, Method::POST, "/api/v1/collections", change.as_bytes());
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let mut admin = user(3);
+        admin.role = Role::Admin;
+        let mut req = request("/api/v1/collections", &[]);
+        *req.method_mut() = Method::POST;
+        let collection = json(send(&ctx, &admin, req, change.as_bytes()));
+        let route = Route::Collection(collection["id"].as_i64().unwrap(), "favorites".to_owned());
+        let feed = body(get(&ctx, &route.url()));
+        assert_eq!(
+            entry_titles(&feed),
+            vec!["Ms. Marvel (2014)", "Paper Girls (2015)", "Saga (2012)"]
+        );
+        let feed = body(send(&ctx, &kid, request(&route.url(), &[]), b""));
+        assert_eq!(entry_titles(&feed), vec!["Ms. Marvel (2014)"]);
+        assert!(body(get(&ctx, "/collections")).contains("<title>Favorites</title>"));
+    }
+
+    #[test]
+    fn year_feeds() {
+        let ctx = context();
+        assert_eq!(entry_titles(&body(get(&ctx, "/years"))), vec!["1990s", "2010s"]);
+        let feed = body(get(&ctx, "/years"));
+        assert!(feed.contains(&format!(r#"href="{}""#, Route::Decade(2010).url())));
+
+        let feed = body(get(&ctx, "/years/decades/2010"));
+        assert_eq!(entry_titles(&feed), vec!["2012", "2014"]);
+        assert!(feed.contains(&format!(r#"href="{}""#, Route::Year(2012).url())));
+        let response = get(&ctx, "/years/decades/1980");
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        // A year reads in release order whatever order the issues were added in,
+        // and offers no year facet
+        let feed = body(get(&ctx, "/years/2012"));
+        assert_eq!(entry_titles(&feed), vec!["Saga v1 1", "Saga v1 2", "Saga v1 3"]);
+        assert!(feed.contains(r#"opds:facetGroup="Read""#));
+        assert!(!feed.contains(r#"opds:facetGroup="Year""#));
+    }
+
+    #[test]
+    fn character_team_and_location_feeds() {
+        let ctx = context();
+        let file = ["Marvel", "Ms. Marvel 2.cbz"];
+        let mut info = comic(&file, "Ms. Marvel", 2, Some("Marvel"), "2014-03-01");
+        info.characters = vec!["Kamala Khan".to_owned(), "Bruno Carrelli".to_owned()];
+        info.teams = vec!["Inhumans".to_owned()];
+        info.locations = vec!["Jersey City".to_owned()];
+        ctx.db.store_comic(&info, &[]).unwrap();
+        ctx.db.refresh_series().unwrap();
+
+        let start = body(get(&ctx, "/"));
+        for route in &[Route::Characters, Route::Teams, Route::Locations] {
+            assert!(start.contains(&format!(r#"href="{}""#, route.url())));
+        }
+        let feed = body(get(&ctx, "/characters"));
+        assert_eq!(entry_titles(&feed), vec!["Bruno Carrelli", "Kamala Khan"]);
+        assert_eq!(entry_titles(&body(get(&ctx, "/teams"))), vec!["Inhumans"]);
+        assert_eq!(entry_titles(&body(get(&ctx, "/locations"))), vec!["Jersey City"]);
+        assert!(entry_titles(&body(get(&ctx, "/genres"))).is_empty());
+
+        let characters = ctx.db.get_categories(&user(1), CategoryKind::Character).unwrap();
+        let kamala = characters.iter().find(|c| c.name == "Kamala Khan").unwrap();
+        let route = category_route(CategoryKind::Character, kamala.id, &kamala.name);
+        assert_eq!(route.url(), format!("/characters/{}/kamala-khan", kamala.id));
+        assert_eq!(entry_titles(&body(get(&ctx, &route.url()))), vec!["Ms. Marvel v1 2"]);
+        // A character isn't a team
+        let response = get(&ctx, &format!("/teams/{}/kamala-khan", kamala.id));
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn reader_direction() {
+        let ctx = context();
+        let file = path(&["comics", "Berserk 1.cbz"]);
+        let xml = "<ComicInfo><Series>Berserk</Series><Number>1</Number>\
+                   <Manga>YesAndRightToLeft</Manga></ComicInfo>";
+        let mut info = ComicInfo::new(Path::new(&file), Some(xml.to_owned())).unwrap();
+        info.library_id = Some(1);
+        let berserk = ctx.db.store_comic(&info, &[]).unwrap();
+        ctx.db.refresh_series().unwrap();
+        let issues = ctx.db.get_all(&user(1), &Facets::default()).unwrap();
+        let saga = issues.iter().find(|e| e.series.as_ref().unwrap() == "Saga").unwrap();
+
+        let page = body(get(&ctx, &Route::WebRead(berserk).url()));
+        assert!(page.contains(r#"data-direction="rtl""#));
+        let page = body(get(&ctx, &Route::WebRead(saga.id.unwrap()).url()));
+        assert!(page.contains(r#"data-direction="ltr""#));
+    }
+}
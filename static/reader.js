@@ -0,0 +1,131 @@
+// Page by page reader for /web/read/{id}, everything it needs is on #reader.
+// Arrow keys, space, tapping either side or swiping turn the page.  The page
+// we're on is saved as we go so the issue picks up where it left off.
+(function () {
+  'use strict';
+
+  var reader = document.getElementById('reader');
+  var image = document.getElementById('page');
+  var counter = document.getElementById('counter');
+  var pageCount = parseInt(reader.dataset.pages, 10);
+  var rtl = reader.dataset.direction === 'rtl';
+  var page = Math.min(parseInt(reader.dataset.start, 10) || 0, Math.max(pageCount - 1, 0));
+  var preload = new Image();
+  var touchStart = null;
+
+  function pageUrl(template, n) {
+    return template.replace('{pageNumber}', n);
+  }
+
+  function saveProgress(n) {
+    if (!window.fetch) {
+      return;
+    }
+    // Guests get a 403 here, which is fine
+    fetch(pageUrl(reader.dataset.progress, n), {
+      method: 'POST',
+      credentials: 'same-origin'
+    }).catch(function () {});
+  }
+
+  function show(n) {
+    if (n < 0) {
+      return;
+    }
+    if (n >= pageCount) {
+      window.location = reader.dataset.back;
+      return;
+    }
+    page = n;
+    image.src = pageUrl(reader.dataset.stream, page);
+    counter.textContent = (page + 1) + ' / ' + pageCount;
+    if (page + 1 < pageCount) {
+      preload.src = pageUrl(reader.dataset.stream, page + 1);
+    }
+    saveProgress(page);
+  }
+
+  function next() {
+    show(page + 1);
+  }
+
+  function previous() {
+    show(page - 1);
+  }
+
+  // Which way is forward depends on the reading direction
+  function left() {
+    if (rtl) {
+      next();
+    } else {
+      previous();
+    }
+  }
+
+  function right() {
+    if (rtl) {
+      previous();
+    } else {
+      next();
+    }
+  }
+
+  document.addEventListener('keydown', function (e) {
+    switch (e.key) {
+      case 'ArrowLeft':
+        left();
+        break;
+      case 'ArrowRight':
+        right();
+        break;
+      case ' ':
+      case 'PageDown':
+        next();
+        break;
+      case 'PageUp':
+        previous();
+        break;
+      case 'Escape':
+        window.location = reader.dataset.back;
+        break;
+      default:
+        return;
+    }
+    e.preventDefault();
+  });
+
+  reader.addEventListener('click', function (e) {
+    if (e.target.tagName === 'A') {
+      return;
+    }
+    if (e.clientX < window.innerWidth / 2) {
+      left();
+    } else {
+      right();
+    }
+  });
+
+  reader.addEventListener('touchstart', function (e) {
+    touchStart = e.changedTouches[0];
+  }, { passive: true });
+
+  reader.addEventListener('touchend', function (e) {
+    if (!touchStart) {
+      return;
+    }
+    var dx = e.changedTouches[0].clientX - touchStart.clientX;
+    var dy = e.changedTouches[0].clientY - touchStart.clientY;
+    touchStart = null;
+    if (Math.abs(dx) > 50 && Math.abs(dx) > Math.abs(dy)) {
+      // Swiping left drags the next page in from the right
+      if (dx < 0) {
+        right();
+      } else {
+        left();
+      }
+      e.preventDefault();
+    }
+  });
+
+  show(page);
+})();